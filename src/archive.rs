@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Cursor, ErrorKind, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use zip::{result::ZipError, write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    config::{Config, MaskSource},
+    error::MergeError,
+    process::{
+        decode_input_png, decode_png, decoded_pna_to_alpha_mask, encode_merged_png_to_vec,
+        merge_decoded, output_bit_depth, write_merged_png, write_output, PngInfo,
+    },
+};
+
+const BLOCK_SIZE: usize = 512;
+/// Signatures of a zip archive: a local file header, or the end of an empty archive.
+const ZIP_SIGNATURES: [&[u8]; 2] = [b"PK\x03\x04", b"PK\x05\x06"];
+
+/// Results of merging every png/pna pair in an archive.
+#[derive(Debug, Default)]
+pub(crate) struct ArchiveReport {
+    /// Output pngs that were written, as entry names when writing an output archive.
+    pub merged: Vec<PathBuf>,
+    /// Entry names of pngs that failed, with the reason.
+    pub failed: Vec<(String, MergeError)>,
+    /// Entry names of pngs without a matching pna.
+    pub skipped: Vec<String>,
+}
+
+/// Merges each `NAME.png` of the tar or zip archive at `config.png_path` with
+/// `NAME.pna` of the same archive, writing `NAME_new.png` with the directories of
+/// the entry under `config.output_path`, or into a new zip archive when that ends
+/// with `.zip`. `should_write` is asked before an output is written.
+pub(crate) fn process_archive(
+    config: &Config,
+    mut should_write: impl FnMut(&Path) -> bool,
+) -> Result<ArchiveReport, MergeError> {
+    let archive_file = File::open(&config.png_path)?;
+    let entries = read_archive(BufReader::with_capacity(config.buffer_size, archive_file))?;
+    let mut output_zip = None;
+    if is_zip_path(&config.output_path) && !config.validate_only {
+        if !should_write(&config.output_path) {
+            return Ok(ArchiveReport::default());
+        }
+        output_zip = Some(ZipWriter::new(Cursor::new(Vec::new())));
+    }
+
+    let pnas: HashMap<String, &[u8]> = entries
+        .iter()
+        .filter_map(|(name, data)| {
+            strip_extension(name, "pna").map(|stem| (stem.to_string(), data.as_slice()))
+        })
+        .collect();
+    let needs_pna = matches!(config.mask_source, MaskSource::Pna(_));
+
+    let mut report = ArchiveReport::default();
+    for (name, data) in &entries {
+        let Some(stem) = strip_extension(name, "png") else {
+            continue;
+        };
+        let pna_data = pnas.get(stem).copied();
+        if needs_pna && pna_data.is_none() {
+            report.skipped.push(name.clone());
+            continue;
+        }
+
+        let output_dir = match output_zip {
+            Some(_) => Path::new(""),
+            None => config.output_path.as_path(),
+        };
+        let output_path = match output_path_of(output_dir, stem) {
+            Some(v) => v,
+            None => {
+                let e = MergeError::InvalidArchive(format!("unsafe entry name: {}", name));
+                report.failed.push((name.clone(), e));
+                continue;
+            }
+        };
+        if !config.validate_only && output_zip.is_none() && !should_write(&output_path) {
+            continue;
+        }
+
+        let result = match &mut output_zip {
+            Some(zip) => merge_entry_to_zip(config, data, pna_data, &output_path, zip),
+            None => merge_entry(config, data, pna_data, &output_path),
+        };
+        match result {
+            Ok(()) if config.validate_only => {}
+            Ok(()) => report.merged.push(output_path),
+            Err(e) => report.failed.push((name.clone(), e)),
+        }
+    }
+
+    if let Some(zip) = output_zip {
+        let encoded = zip.finish().map_err(zip_error)?.into_inner();
+        write_output(&config.output_path, &encoded, config)?;
+    }
+
+    Ok(report)
+}
+
+fn merge_entry_to_zip(
+    config: &Config,
+    png_data: &[u8],
+    pna_data: Option<&[u8]>,
+    entry_path: &Path,
+    zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+) -> Result<(), MergeError> {
+    let (png_info, bit_depth, merged_buf) = merge_entry_data(config, png_data, pna_data)?;
+    let encoded = encode_merged_png_to_vec(&png_info, bit_depth, &merged_buf, None, config)?;
+
+    // The png is compressed already, so it is stored as it is.
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let name = entry_path
+        .iter()
+        .map(|v| v.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    zip.start_file(name, options).map_err(zip_error)?;
+    zip.write_all(&encoded)?;
+
+    Ok(())
+}
+
+fn merge_entry(
+    config: &Config,
+    png_data: &[u8],
+    pna_data: Option<&[u8]>,
+    output_path: &Path,
+) -> Result<(), MergeError> {
+    let (png_info, bit_depth, merged_buf) = merge_entry_data(config, png_data, pna_data)?;
+    if config.validate_only {
+        return Ok(());
+    }
+
+    if let Some(dir) = output_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_merged_png(output_path, &png_info, bit_depth, &merged_buf, None, config)
+}
+
+/// Decodes and merges a png entry with its pna entry, returning the png info, the
+/// bit depth of the output and the merged rgba.
+fn merge_entry_data(
+    config: &Config,
+    png_data: &[u8],
+    pna_data: Option<&[u8]>,
+) -> Result<(PngInfo, png::BitDepth, Vec<u8>), MergeError> {
+    let (png_buf, png_info) = decode_input_png(png_data)?;
+    let (merged_buf, png_info, _) = merge_decoded(config, &png_buf, png_info, |_| {
+        let pna_data = pna_data.unwrap_or_default();
+        let (pna_buf, pna_info) = decode_png(pna_data)?;
+        decoded_pna_to_alpha_mask(&pna_buf, &pna_info, config)
+    })?;
+    let bit_depth = output_bit_depth(config, &png_info);
+
+    Ok((png_info, bit_depth, merged_buf))
+}
+
+/// Returns `OUTPUT_DIR/DIRS/STEM_new.png` for the entry `DIRS/STEM`, or `None` when the
+/// entry name is absolute or climbs out with `..`.
+fn output_path_of(output_dir: &Path, stem: &str) -> Option<PathBuf> {
+    let mut path = output_dir.to_path_buf();
+    let stem = Path::new(stem);
+    for component in stem.parent()?.components() {
+        match component {
+            Component::Normal(v) => path.push(v),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    let file_name = stem.file_name()?.to_str()?;
+    path.push(format!("{}_new.png", file_name));
+
+    Some(path)
+}
+
+fn strip_extension<'a>(name: &'a str, extension: &str) -> Option<&'a str> {
+    let (stem, v) = name.rsplit_once('.')?;
+    (v.eq_ignore_ascii_case(extension) && !stem.is_empty() && !stem.ends_with('/')).then_some(stem)
+}
+
+fn is_zip_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("zip"))
+}
+
+/// Reads the regular files of a zip archive, found by its signature, or else of a
+/// tar archive as `(name, data)` pairs.
+fn read_archive<R: BufRead + Seek>(mut reader: R) -> Result<Vec<(String, Vec<u8>)>, MergeError> {
+    let head = reader.fill_buf()?;
+    if ZIP_SIGNATURES.iter().any(|v| head.starts_with(v)) {
+        read_zip(reader)
+    } else {
+        read_tar(reader)
+    }
+}
+
+/// Reads the files of a zip archive as `(name, data)` pairs, skipping directories.
+fn read_zip<R: Read + Seek>(reader: R) -> Result<Vec<(String, Vec<u8>)>, MergeError> {
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(zip_error)?;
+        if !file.is_file() {
+            continue;
+        }
+        let name = file.name().map_err(zip_error)?.into_owned();
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data).map_err(|e| match e.kind() {
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                MergeError::InvalidArchive(format!("broken data of {}: {}", name, e))
+            }
+            _ => MergeError::Io(e),
+        })?;
+        entries.push((name, data));
+    }
+
+    Ok(entries)
+}
+
+fn zip_error(e: ZipError) -> MergeError {
+    match e {
+        ZipError::Io(e) => MergeError::Io(e),
+        e => MergeError::InvalidArchive(e.to_string()),
+    }
+}
+
+/// Reads the regular files of a ustar (or old v7) tar archive as `(name, data)` pairs.
+/// Other entry types, such as directories and links, are skipped.
+pub(crate) fn read_tar<R: Read>(mut reader: R) -> Result<Vec<(String, Vec<u8>)>, MergeError> {
+    let mut entries = Vec::new();
+    let mut header = [0; BLOCK_SIZE];
+
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            // Some writers omit the two zero blocks at the end.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && !entries.is_empty() => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(MergeError::InvalidArchive("no tar header".to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if header.iter().all(|v| *v == 0) {
+            break;
+        }
+
+        let mut name = header_string(&header[0..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = header_string(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        let size = parse_octal(&header[124..136])
+            .ok_or_else(|| MergeError::InvalidArchive(format!("invalid size of {}", name)))?;
+
+        let mut data = vec![0; size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE];
+        reader.read_exact(&mut data).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => {
+                MergeError::InvalidArchive(format!("truncated data of {}", name))
+            }
+            _ => MergeError::Io(e),
+        })?;
+        data.truncate(size);
+
+        if matches!(header[156], b'0' | 0) {
+            entries.push((name, data));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn header_string(field: &[u8]) -> String {
+    let end = field.iter().position(|v| *v == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let s = header_string(field);
+    let s = s.trim_matches(|c: char| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(s, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_header(name: &str, size: usize, type_flag: u8) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    fn build_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, data) in files {
+            tar.extend_from_slice(&tar_header(name, data.len(), b'0'));
+            tar.extend_from_slice(data);
+            tar.resize(tar.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        }
+        tar.extend_from_slice(&[0; BLOCK_SIZE * 2]);
+        tar
+    }
+
+    fn build_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add_directory("dir/", SimpleFileOptions::default())
+            .unwrap();
+        for (name, data) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    mod read_archive {
+        use super::*;
+
+        #[test]
+        fn success_when_zip() {
+            let zip = build_zip(&[("dir/a.png", b"abc"), ("b.pna", &[7; 600])]);
+
+            let entries = read_archive(Cursor::new(zip)).unwrap();
+
+            assert_eq!(
+                entries,
+                vec![
+                    ("dir/a.png".to_string(), b"abc".to_vec()),
+                    ("b.pna".to_string(), vec![7; 600]),
+                ]
+            );
+        }
+
+        #[test]
+        fn success_when_tar() {
+            let tar = build_tar(&[("a.png", b"abc")]);
+
+            let entries = read_archive(Cursor::new(tar)).unwrap();
+
+            assert_eq!(entries, vec![("a.png".to_string(), b"abc".to_vec())]);
+        }
+
+        #[test]
+        fn failed_when_zip_is_truncated() {
+            let zip = build_zip(&[("a.png", &[1; 600])]);
+
+            assert!(matches!(
+                read_archive(Cursor::new(&zip[..zip.len() - 30])),
+                Err(MergeError::InvalidArchive(_))
+            ));
+        }
+    }
+
+    mod read_tar {
+        use super::*;
+
+        #[test]
+        fn success_when_regular_files() {
+            let mut tar = tar_header("dir/", 0, b'5').to_vec();
+            tar.extend(build_tar(&[("dir/a.png", b"abc"), ("b.pna", &[7; 600])]));
+
+            let entries = read_tar(tar.as_slice()).unwrap();
+
+            assert_eq!(
+                entries,
+                vec![
+                    ("dir/a.png".to_string(), b"abc".to_vec()),
+                    ("b.pna".to_string(), vec![7; 600]),
+                ]
+            );
+        }
+
+        #[test]
+        fn failed_when_truncated() {
+            let tar = build_tar(&[("a.png", &[1; 600])]);
+
+            assert!(matches!(
+                read_tar(&tar[..BLOCK_SIZE * 2]),
+                Err(MergeError::InvalidArchive(_))
+            ));
+            assert!(matches!(
+                read_tar(&[][..]),
+                Err(MergeError::InvalidArchive(_))
+            ));
+        }
+    }
+
+    mod output_path_of {
+        use super::*;
+
+        #[test]
+        fn success_when_nested_entry() {
+            assert_eq!(
+                output_path_of(Path::new("out"), "./dir/a"),
+                Some(PathBuf::from("out/dir/a_new.png"))
+            );
+        }
+
+        #[test]
+        fn failed_when_entry_climbs_out() {
+            assert_eq!(output_path_of(Path::new("out"), "../a"), None);
+            assert_eq!(output_path_of(Path::new("out"), "/tmp/a"), None);
+        }
+    }
+
+    mod process_archive {
+        use super::*;
+
+        #[test]
+        fn success_when_pairs_in_archive() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png = std::fs::read(dir.join("surface0000.png")).unwrap();
+            let pna = std::fs::read(dir.join("surface0000.pna")).unwrap();
+            let tar = build_tar(&[
+                ("sub/surface0000.png", &png),
+                ("sub/surface0000.pna", &pna),
+                ("lonely.png", &png),
+            ]);
+
+            let work_dir = std::env::temp_dir().join("merge-pna_process_archive");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let archive_path = work_dir.join("pairs.tar");
+            std::fs::write(&archive_path, tar).unwrap();
+            let output_dir = work_dir.join("out");
+            let config = Config {
+                png_path: archive_path,
+                mask_source: MaskSource::Pna(PathBuf::new()),
+                output_path: output_dir.clone(),
+                ..Default::default()
+            };
+
+            let report = process_archive(&config, |_| true).unwrap();
+
+            let output_path = output_dir.join("sub/surface0000_new.png");
+            assert_eq!(report.merged, vec![output_path.clone()]);
+            assert!(report.failed.is_empty());
+            assert_eq!(report.skipped, vec!["lonely.png".to_string()]);
+            let (expected_buf, _, _) = crate::process::merge(&Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                ..Default::default()
+            })
+            .unwrap();
+            let (actual_buf, _) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(actual_buf, expected_buf);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_output_is_zip() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png = std::fs::read(dir.join("surface0000.png")).unwrap();
+            let pna = std::fs::read(dir.join("surface0000.pna")).unwrap();
+            let zip = build_zip(&[("sub/surface0000.png", &png), ("sub/surface0000.pna", &pna)]);
+
+            let work_dir = std::env::temp_dir().join("merge-pna_process_archive_zip");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let archive_path = work_dir.join("pairs.zip");
+            std::fs::write(&archive_path, zip).unwrap();
+            let output_path = work_dir.join("merged.zip");
+            let config = Config {
+                png_path: archive_path,
+                mask_source: MaskSource::Pna(PathBuf::new()),
+                output_path: output_path.clone(),
+                ..Default::default()
+            };
+            let mut asked = Vec::new();
+
+            let report = process_archive(&config, |v| {
+                asked.push(v.to_path_buf());
+                true
+            })
+            .unwrap();
+
+            assert_eq!(asked, vec![output_path.clone()]);
+            assert_eq!(
+                report.merged,
+                vec![PathBuf::from("sub/surface0000_new.png")]
+            );
+            assert!(report.failed.is_empty());
+            let entries = read_zip(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].0, "sub/surface0000_new.png");
+            let (expected_buf, _, _) = crate::process::merge(&Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                ..Default::default()
+            })
+            .unwrap();
+            let (actual_buf, _) = decode_png(entries[0].1.as_slice()).unwrap();
+            assert_eq!(actual_buf, expected_buf);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::pna::AlphaMask;
+
+const CACHE_MAGIC: &[u8; 8] = b"MPNAMSK2";
+
+/// Environment variable to enable the decoded pna cache without the flag.
+pub(crate) const CACHE_ENABLE_ENV: &str = "MERGE_PNA_CACHE_DECODED_PNA";
+/// Environment variable to override the directory of the decoded pna cache.
+pub(crate) const CACHE_DIR_ENV: &str = "MERGE_PNA_CACHE_DIR";
+
+/// Identity of a pna file and how it was derived.
+/// The cache entry is valid only while all of them are unchanged.
+#[derive(Debug, PartialEq)]
+struct CacheKey {
+    path: String,
+    variant: String,
+    modified_secs: u64,
+    modified_nanos: u32,
+    len: u64,
+}
+
+pub(crate) fn load_alpha_mask(pna_path: &Path, variant: &str) -> Option<AlphaMask> {
+    let key = cache_key(pna_path, variant)?;
+    let file = File::open(cache_file_path(&key)).ok()?;
+    let mut reader = BufReader::new(file);
+
+    read_entry(&mut reader, &key)
+}
+
+pub(crate) fn save_alpha_mask(pna_path: &Path, variant: &str, alpha_mask: &AlphaMask) {
+    let Some(key) = cache_key(pna_path, variant) else {
+        return;
+    };
+    let cache_path = cache_file_path(&key);
+    if let Some(dir) = cache_path.parent() {
+        if std::fs::create_dir_all(dir).is_err() {
+            return;
+        }
+    }
+
+    // The cache is only an optimization, so a failed write is not an error.
+    if let Ok(file) = File::create(&cache_path) {
+        let mut writer = BufWriter::new(file);
+        if write_entry(&mut writer, &key, alpha_mask).is_err() {
+            drop(writer);
+            let _ = std::fs::remove_file(&cache_path);
+        }
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    match std::env::var_os(CACHE_DIR_ENV) {
+        Some(v) if !v.is_empty() => PathBuf::from(v),
+        _ => std::env::temp_dir().join("merge-pna"),
+    }
+}
+
+fn cache_key(pna_path: &Path, variant: &str) -> Option<CacheKey> {
+    let path = pna_path.canonicalize().ok()?;
+    let metadata = path.metadata().ok()?;
+    let path = path.to_string_lossy().into_owned();
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some(CacheKey {
+        path,
+        variant: variant.to_string(),
+        modified_secs: modified.as_secs(),
+        modified_nanos: modified.subsec_nanos(),
+        len: metadata.len(),
+    })
+}
+
+fn cache_file_path(key: &CacheKey) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.path.hash(&mut hasher);
+    key.variant.hash(&mut hasher);
+
+    cache_dir().join(format!("{:016x}.mask", hasher.finish()))
+}
+
+fn write_entry<W: Write>(
+    writer: &mut W,
+    key: &CacheKey,
+    alpha_mask: &AlphaMask,
+) -> std::io::Result<()> {
+    writer.write_all(CACHE_MAGIC)?;
+    write_str(writer, &key.path)?;
+    write_str(writer, &key.variant)?;
+    writer.write_all(&key.modified_secs.to_le_bytes())?;
+    writer.write_all(&key.modified_nanos.to_le_bytes())?;
+    writer.write_all(&key.len.to_le_bytes())?;
+    writer.write_all(&alpha_mask.width.to_le_bytes())?;
+    writer.write_all(&alpha_mask.height.to_le_bytes())?;
+    writer.write_all(&alpha_mask.data)?;
+    writer.flush()
+}
+
+fn read_entry<R: Read>(reader: &mut R, key: &CacheKey) -> Option<AlphaMask> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic).ok()?;
+    if &magic != CACHE_MAGIC {
+        return None;
+    }
+
+    let cached_key = CacheKey {
+        path: read_str(reader)?,
+        variant: read_str(reader)?,
+        modified_secs: read_u64(reader)?,
+        modified_nanos: read_u32(reader)?,
+        len: read_u64(reader)?,
+    };
+    if cached_key != *key {
+        return None;
+    }
+
+    let width = read_u32(reader)?;
+    let height = read_u32(reader)?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).ok()?;
+    if data.len() != (width as usize) * (height as usize) {
+        return None;
+    }
+
+    Some(AlphaMask {
+        width,
+        height,
+        data,
+    })
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &str) -> std::io::Result<()> {
+    writer.write_all(&(s.len() as u64).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_str<R: Read>(reader: &mut R) -> Option<String> {
+    let len = read_u64(reader)? as usize;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).ok()?;
+    String::from_utf8(buf).ok()
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Option<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Option<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf).ok()?;
+    Some(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod read_entry {
+        use super::*;
+
+        fn key() -> CacheKey {
+            CacheKey {
+                path: "/path/to/surface0000.pna".to_string(),
+                variant: "Alpha".to_string(),
+                modified_secs: 1_700_000_000,
+                modified_nanos: 123,
+                len: 456,
+            }
+        }
+
+        fn alpha_mask() -> AlphaMask {
+            AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![0, u8::MAX],
+            }
+        }
+
+        #[test]
+        fn success_when_same_key() {
+            let mut buf = Vec::new();
+            write_entry(&mut buf, &key(), &alpha_mask()).unwrap();
+
+            let result = read_entry(&mut buf.as_slice(), &key());
+
+            assert_eq!(result, Some(alpha_mask()));
+        }
+
+        #[test]
+        fn failed_when_modified_time_is_changed() {
+            let mut buf = Vec::new();
+            write_entry(&mut buf, &key(), &alpha_mask()).unwrap();
+
+            let mut changed = key();
+            changed.modified_nanos += 1;
+
+            assert!(read_entry(&mut buf.as_slice(), &changed).is_none());
+        }
+
+        #[test]
+        fn failed_when_variant_is_changed() {
+            let mut buf = Vec::new();
+            write_entry(&mut buf, &key(), &alpha_mask()).unwrap();
+
+            let mut changed = key();
+            changed.variant = "Gray".to_string();
+
+            assert!(read_entry(&mut buf.as_slice(), &changed).is_none());
+        }
+
+        #[test]
+        fn failed_when_data_is_truncated() {
+            let mut buf = Vec::new();
+            write_entry(&mut buf, &key(), &alpha_mask()).unwrap();
+            buf.pop();
+
+            assert!(read_entry(&mut buf.as_slice(), &key()).is_none());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::Write;
+
+use clap::ValueEnum;
+
+/// Hash of `--checksum`, written next to each merged png.
+/// Each algorithm is only available with its feature.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ChecksumAlgorithm {
+    #[cfg(feature = "checksum-sha256")]
+    Sha256,
+    #[cfg(feature = "checksum-blake3")]
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Extension of the sidecar file, which is appended to the output file name.
+    pub(crate) fn extension(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "checksum-sha256")]
+            Self::Sha256 => "sha256",
+            #[cfg(feature = "checksum-blake3")]
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    pub(crate) fn hasher(&self) -> Hasher {
+        match *self {
+            #[cfg(feature = "checksum-sha256")]
+            Self::Sha256 => Hasher::Sha256(sha2::Sha256::default()),
+            #[cfg(feature = "checksum-blake3")]
+            Self::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+}
+
+pub(crate) enum Hasher {
+    #[cfg(feature = "checksum-sha256")]
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "checksum-blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    #[cfg_attr(
+        not(any(feature = "checksum-sha256", feature = "checksum-blake3")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        // Dereferenced, so that the match is exhaustive without any algorithm.
+        match *self {
+            #[cfg(feature = "checksum-sha256")]
+            Self::Sha256(ref mut v) => sha2::Digest::update(v, data),
+            #[cfg(feature = "checksum-blake3")]
+            Self::Blake3(ref mut v) => {
+                v.update(data);
+            }
+        }
+    }
+
+    /// The 32-byte digest as lowercase hex.
+    pub(crate) fn finalize_hex(self) -> String {
+        match self {
+            #[cfg(feature = "checksum-sha256")]
+            Self::Sha256(v) => format!("{:x}", sha2::Digest::finalize(v)),
+            #[cfg(feature = "checksum-blake3")]
+            Self::Blake3(v) => v.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Passes the writes through to `inner`, hashing the bytes that were written.
+pub(crate) struct ChecksumWriter<'a, W: Write> {
+    pub inner: W,
+    pub hasher: Option<&'a mut Hasher>,
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes `HASH  FILE_NAME` to `path` with the algorithm's extension appended, in the
+/// format `sha256sum -c` and `b3sum -c` read.
+pub(crate) fn write_checksum_file(
+    path: &std::path::Path,
+    algorithm: ChecksumAlgorithm,
+    hex: &str,
+) -> std::io::Result<()> {
+    let mut sidecar_name = path.file_name().unwrap_or_default().to_os_string();
+    let file_name = sidecar_name.to_string_lossy().into_owned();
+    sidecar_name.push(".");
+    sidecar_name.push(algorithm.extension());
+
+    let mut file = std::fs::File::create(path.with_file_name(sidecar_name))?;
+    writeln!(file, "{}  {}", hex, file_name)
+}
+
+#[cfg(all(test, any(feature = "checksum-sha256", feature = "checksum-blake3")))]
+mod tests {
+    use super::*;
+
+    fn hex(algorithm: ChecksumAlgorithm, data: &[u8]) -> String {
+        let mut hasher = algorithm.hasher();
+        hasher.update(data);
+        hasher.finalize_hex()
+    }
+
+    fn test_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    mod checksum_writer {
+        use super::*;
+
+        #[test]
+        fn success_when_written_in_pieces() {
+            let data = test_input(31744);
+            for &algorithm in ChecksumAlgorithm::value_variants() {
+                let mut hasher = algorithm.hasher();
+                let mut writer = ChecksumWriter {
+                    inner: Vec::new(),
+                    hasher: Some(&mut hasher),
+                };
+                for piece in data.chunks(333) {
+                    writer.write_all(piece).unwrap();
+                }
+
+                assert_eq!(writer.inner, data);
+                assert_eq!(hasher.finalize_hex(), hex(algorithm, &data));
+            }
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    config::{self, Config, Sequence},
+    error::{self, ConfigError, ErrorDetail, MergeError, Warning},
+    log::LogLevel,
+    process, retry,
+};
+
+/// Exit code when `--no-clobber` skipped an existing output.
+const EXIT_CODE_SKIPPED: i32 = 2;
+
+enum JobOutcome {
+    Done,
+    Skipped,
+    Failed,
+    /// Failed in a way every later job would too, so the remaining jobs are not run.
+    Fatal,
+}
+
+/// Runs the command line tool with `std::env::args_os()`.
+pub fn run() {
+    let config_raw = match config::ConfigRaw::parse_with_config_file(std::env::args_os()) {
+        Ok(v) => v,
+        Err(e) => {
+            // The config file failed to parse, so only the command line can ask for json.
+            let json_errors = std::env::args_os().any(|v| v == "--json-errors");
+            exit_with_error("Error on parsing argumets", &e, None, json_errors);
+        }
+    };
+    let json_errors = config_raw.json_errors;
+    let warnings_as_errors = config_raw.warnings_as_errors;
+    let log_level = config_raw.log_level();
+    let threads = match config_raw.low_memory {
+        true => 1,
+        false => config_raw.jobs,
+    };
+    let input_path = config_raw.input_or_pna_path().to_path_buf();
+
+    let (batch_config, force_flag) = match config_raw.to_batch_config_with_force_flag() {
+        Ok(v) => v,
+        Err(e) => exit_with_error(
+            "Error on parsing argumets",
+            &e,
+            Some(&input_path),
+            json_errors,
+        ),
+    };
+
+    // Every job runs even when one fails; the exit code reports the worst outcome.
+    let mut exit_code = 0;
+    for path in batch_config.skipped {
+        let warning = Warning::PnaNotFound(path);
+        if !report_warning(&warning, warnings_as_errors, json_errors, log_level) {
+            exit_code = 1;
+        }
+    }
+    let mut record = |outcome: &JobOutcome| match outcome {
+        JobOutcome::Done => {}
+        JobOutcome::Skipped => {
+            if exit_code == 0 {
+                exit_code = EXIT_CODE_SKIPPED;
+            }
+        }
+        JobOutcome::Failed | JobOutcome::Fatal => exit_code = 1,
+    };
+
+    if let Some(sequence) = &batch_config.sequence {
+        record(&run_sequence(
+            &batch_config.jobs,
+            sequence,
+            &input_path,
+            force_flag,
+            json_errors,
+        ));
+        if exit_code != 0 {
+            std::process::exit(exit_code);
+        }
+        return;
+    }
+
+    // Prompts and skips are settled one by one before any job starts, so that
+    // parallel jobs never ask at the same time.
+    let mut ready = Vec::new();
+    let is_batch = batch_config.is_batch;
+    for config in batch_config.jobs {
+        #[cfg(feature = "archive")]
+        if config.archive {
+            if !run_archive_job(&config, force_flag, warnings_as_errors, json_errors) {
+                record(&JobOutcome::Failed);
+            }
+            continue;
+        }
+
+        match check_outputs(&config, force_flag, is_batch, json_errors) {
+            Some(outcome) => record(&outcome),
+            None => ready.push(config),
+        }
+    }
+
+    let outcomes = run_jobs(&ready, threads, warnings_as_errors, json_errors);
+    outcomes.iter().for_each(&mut record);
+    if ready.len() > 1 && !json_errors {
+        let failed: Vec<&Path> = ready
+            .iter()
+            .zip(&outcomes)
+            .filter(|(_, outcome)| matches!(outcome, JobOutcome::Failed | JobOutcome::Fatal))
+            .map(|(config, _)| config.png_path.as_path())
+            .collect();
+        if !failed.is_empty() {
+            let mut stderr = std::io::stderr().lock();
+            let _ = writeln!(stderr, "{} of {} pngs failed:", failed.len(), ready.len());
+            for path in failed {
+                let _ = writeln!(stderr, "  {}", path.display());
+            }
+        }
+        // A fatal failure stops the queue.
+        let not_run = ready.len() - outcomes.len();
+        if not_run > 0 {
+            let _ = writeln!(
+                std::io::stderr(),
+                "Stopped, {} pngs were not merged.",
+                not_run
+            );
+        }
+    }
+
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Runs the jobs on up to `threads` threads, each taking the next job nobody has
+/// started. Outcomes are in the order of `jobs`, and after a `JobOutcome::Fatal` no job
+/// is started, so only the jobs that ran have one.
+fn run_jobs(
+    jobs: &[Config],
+    threads: usize,
+    warnings_as_errors: bool,
+    json_errors: bool,
+) -> Vec<JobOutcome> {
+    if threads <= 1 {
+        let mut outcomes = Vec::new();
+        for config in jobs {
+            let outcome = run_job(config, warnings_as_errors, json_errors);
+            let is_fatal = matches!(outcome, JobOutcome::Fatal);
+            outcomes.push(outcome);
+            if is_fatal {
+                break;
+            }
+        }
+        return outcomes;
+    }
+
+    // A queue on scoped threads instead of rayon's par_iter: it is all this needs, and
+    // it keeps the dependencies to png, clap and flate2, all of which the offline
+    // builds of this tool already vendor.
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let mut outcomes: Vec<(usize, JobOutcome)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..threads.min(jobs.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut outcomes = Vec::new();
+                    while !stopped.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(config) = jobs.get(i) else {
+                            break;
+                        };
+                        let outcome = run_job(config, warnings_as_errors, json_errors);
+                        if matches!(outcome, JobOutcome::Fatal) {
+                            stopped.store(true, Ordering::Relaxed);
+                        }
+                        outcomes.push((i, outcome));
+                    }
+                    outcomes
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|v| v.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    });
+    outcomes.sort_by_key(|v| v.0);
+
+    outcomes.into_iter().map(|v| v.1).collect()
+}
+
+/// Skips the job for `--no-clobber` or asks before overwriting its outputs.
+/// Returns the outcome when the job must not run.
+fn check_outputs(
+    config: &Config,
+    force_flag: bool,
+    is_batch: bool,
+    json_errors: bool,
+) -> Option<JobOutcome> {
+    // These only read.
+    if config.dump_info || config.diff_existing || config.validate_only {
+        return None;
+    }
+
+    check_paths(
+        config,
+        written_paths(config),
+        force_flag,
+        is_batch,
+        json_errors,
+    )
+}
+
+/// Paths of the files the job writes.
+fn written_paths(config: &Config) -> Vec<PathBuf> {
+    // --split also writes the pna.
+    let mut output_paths = config.output_paths();
+    if let Some(split) = &config.split {
+        output_paths.push(split.pna_path.clone());
+    }
+    output_paths
+}
+
+/// Same as `check_outputs` for the given paths the job writes. A declined overwrite
+/// skips the job of a batch, and ends the run of a single file.
+fn check_paths(
+    config: &Config,
+    mut output_paths: Vec<PathBuf>,
+    force_flag: bool,
+    is_batch: bool,
+    json_errors: bool,
+) -> Option<JobOutcome> {
+    // Stdout is never clobbered.
+    output_paths.retain(|v| !config::is_stdio(v));
+
+    if config.no_clobber {
+        if let Some(path) = output_paths.iter().find(|v| v.exists()) {
+            config
+                .log_level
+                .verbose(format_args!("Skipped {}: output exists", path.display()));
+            return Some(JobOutcome::Skipped);
+        }
+    }
+
+    if !force_flag {
+        for path in &output_paths {
+            // The answer would be read from the png.
+            let result = if config::is_stdio(&config.png_path) && path.exists() {
+                Err(ConfigError::CannotConfirmWithStdin(path.clone()))
+            } else {
+                config::confirm_overwriting(path, config.log_level)
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) if is_batch => {
+                    config.log_level.verbose(format_args!(
+                        "Skipped {}: overwrite declined",
+                        path.display()
+                    ));
+                    return Some(JobOutcome::Skipped);
+                }
+                Ok(false) => {
+                    if config.log_level > LogLevel::Quiet {
+                        println!("Closing this program...");
+                    }
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    report_error("Error on confirm overwriting", &e, Some(path), json_errors);
+                    return Some(JobOutcome::Failed);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn run_job(config: &Config, warnings_as_errors: bool, json_errors: bool) -> JobOutcome {
+    let png_path = &config.png_path;
+
+    if config.dump_info {
+        return match process::dump_info(config) {
+            Ok(v) => {
+                println!("{}", v);
+                JobOutcome::Done
+            }
+            Err(e) => {
+                report_error("Error on reading png", &e, Some(png_path), json_errors);
+                JobOutcome::Failed
+            }
+        };
+    }
+
+    if config.diff_existing {
+        return match process::diff_existing(config) {
+            Ok(v) => {
+                let state = match v {
+                    process::ExistingOutput::Missing => "missing",
+                    process::ExistingOutput::Identical => "identical",
+                    process::ExistingOutput::WouldChange => "would change",
+                };
+                println!("{}: {}", config.output_path.display(), state);
+                JobOutcome::Done
+            }
+            Err(e) => {
+                report_merge_error(&e, png_path, json_errors);
+                JobOutcome::Failed
+            }
+        };
+    }
+
+    if config.dry_run {
+        let targets: Vec<String> = written_paths(config)
+            .iter()
+            .map(|v| plan_target(config, v))
+            .collect();
+        print_plan(config, &targets.join(", "));
+    }
+    if config.validate_only {
+        config
+            .log_level
+            .verbose(format_args!("Validating {}", png_path.display()));
+    } else if config.log_level >= LogLevel::Verbose {
+        let output_paths: Vec<String> = written_paths(config)
+            .iter()
+            .map(|v| v.display().to_string())
+            .collect();
+        config.log_level.verbose(format_args!(
+            "Merging {} into {}",
+            png_path.display(),
+            output_paths.join(", ")
+        ));
+    }
+
+    // A png read from stdin is gone after the first try.
+    let retries = match config::is_stdio(png_path) {
+        true => 0,
+        false => config.retries,
+    };
+    match retry::retry(retries, config.retry_backoff, || process::process(config)) {
+        Ok(warnings) => {
+            let mut outcome = JobOutcome::Done;
+            for warning in &warnings {
+                if !report_warning(warning, warnings_as_errors, json_errors, config.log_level) {
+                    outcome = JobOutcome::Failed;
+                }
+            }
+            outcome
+        }
+        Err(e) => {
+            report_merge_error(&e, png_path, json_errors);
+            match e {
+                // The other outputs are in the same place, so they would fail too.
+                MergeError::OutputPermissionDenied(_) => JobOutcome::Fatal,
+                _ => JobOutcome::Failed,
+            }
+        }
+    }
+}
+
+/// Merges the jobs as the frames of the animated png of `--sequence`, after the checks
+/// of `check_outputs` for it.
+fn run_sequence(
+    jobs: &[Config],
+    sequence: &Sequence,
+    input_path: &Path,
+    force_flag: bool,
+    json_errors: bool,
+) -> JobOutcome {
+    let config = jobs.first().expect("A sequence has a frame");
+    if config.dry_run {
+        let target = plan_target(config, &sequence.path);
+        for (i, job) in jobs.iter().enumerate() {
+            print_plan(job, &format!("{} frame {}", target, i));
+        }
+    }
+    if !config.validate_only {
+        let output_paths = vec![sequence.path.clone()];
+        if let Some(outcome) = check_paths(config, output_paths, force_flag, true, json_errors) {
+            return outcome;
+        }
+    }
+
+    let retries = match config::is_stdio(&config.png_path) {
+        true => 0,
+        false => config.retries,
+    };
+    match retry::retry(retries, config.retry_backoff, || {
+        process::merge_sequence(jobs, sequence)
+    }) {
+        Ok(()) => JobOutcome::Done,
+        Err(e) => {
+            report_merge_error(&e, input_path, json_errors);
+            JobOutcome::Failed
+        }
+    }
+}
+
+/// Prints a line of the plan of `--dry-run`: the png and mask merged into `target`.
+fn print_plan(config: &Config, target: &str) {
+    println!(
+        "{} + {} -> {}",
+        config.png_path.display(),
+        config.mask_source,
+        target
+    );
+}
+
+/// `path` as a target of the plan, marked when it already exists.
+fn plan_target(config: &Config, path: &Path) -> String {
+    if config::is_stdio(path) || !path.exists() {
+        return path.display().to_string();
+    }
+    if config.no_clobber {
+        return format!("{} (exists, skipped)", path.display());
+    }
+    if config.backup {
+        return format!(
+            "{} (exists, backed up to {})",
+            path.display(),
+            process::backup_path(path).display()
+        );
+    }
+    format!("{} (exists, overwritten)", path.display())
+}
+
+/// Merges every pair of the archive, reporting each failure and skipped png.
+/// Returns false when any of them was reported as an error.
+#[cfg(feature = "archive")]
+fn run_archive_job(
+    config: &Config,
+    force_flag: bool,
+    warnings_as_errors: bool,
+    json_errors: bool,
+) -> bool {
+    let should_write = |path: &Path| {
+        if !path.exists() {
+            return true;
+        }
+        if config.no_clobber {
+            config
+                .log_level
+                .verbose(format_args!("Skipped {}: output exists", path.display()));
+            return false;
+        }
+        if force_flag {
+            return true;
+        }
+        match config::confirm_overwriting(path, config.log_level) {
+            Ok(true) => true,
+            Ok(false) => {
+                config.log_level.verbose(format_args!(
+                    "Skipped {}: overwrite declined",
+                    path.display()
+                ));
+                false
+            }
+            Err(_) => false,
+        }
+    };
+    let report = match crate::archive::process_archive(config, should_write) {
+        Ok(v) => v,
+        Err(e) => {
+            report_merge_error(&e, &config.png_path, json_errors);
+            return false;
+        }
+    };
+
+    let mut is_succeeded = true;
+    for name in report.skipped {
+        let warning = Warning::PnaNotFound(name.into());
+        is_succeeded &= report_warning(&warning, warnings_as_errors, json_errors, config.log_level);
+    }
+    for (name, e) in report.failed {
+        report_merge_error(&e, Path::new(&name), json_errors);
+        is_succeeded = false;
+    }
+
+    is_succeeded
+}
+
+fn report_merge_error(e: &MergeError, png_path: &Path, json_errors: bool) {
+    // Keeps the hint next to its error when jobs run in parallel.
+    let _stderr = std::io::stderr().lock();
+    report_error(
+        "Error on merging png and pna",
+        e,
+        Some(png_path),
+        json_errors,
+    );
+    if json_errors {
+        return;
+    }
+
+    match e {
+        MergeError::OutputPermissionDenied(_) => eprintln!(
+            "The output location is not writable. Is the directory or the mount read-only?"
+        ),
+        MergeError::NotAPng { .. } => eprintln!(
+            "The file does not start with the png signature. Is it another format renamed to .png or .pna?"
+        ),
+        MergeError::OutputTooLarge { .. } => eprintln!(
+            "Nothing was written. --compression best or --filter adaptive may make it smaller."
+        ),
+        _ => {}
+    }
+}
+
+/// Prints the warning to stderr unless `--quiet`, or reports it as an error with
+/// `--warnings-as-errors`. Returns false when it was reported as an error.
+fn report_warning(
+    warning: &Warning,
+    warnings_as_errors: bool,
+    json_errors: bool,
+    log_level: LogLevel,
+) -> bool {
+    if warnings_as_errors {
+        report_error("Error on warning", warning, None, json_errors);
+        false
+    } else {
+        log_level.notice(warning);
+        true
+    }
+}
+
+/// Prints the error to stderr, as JSON when `json_errors` is set.
+fn report_error(
+    context: &str,
+    e: &impl ErrorDetail,
+    fallback_path: Option<&Path>,
+    json_errors: bool,
+) {
+    if json_errors {
+        eprintln!("{}", error::error_to_json(e, fallback_path));
+    } else {
+        eprintln!("{}: {}", context, e);
+    }
+}
+
+/// Same as `report_error`, then exits with 1.
+fn exit_with_error(
+    context: &str,
+    e: &impl ErrorDetail,
+    fallback_path: Option<&Path>,
+    json_errors: bool,
+) -> ! {
+    report_error(context, e, fallback_path, json_errors);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::MaskSource;
+
+    mod run_jobs {
+        use super::*;
+
+        // Files can't be created in /sys, even by root.
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn success_when_stopped_on_output_permission_denied() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let jobs: Vec<Config> = (0..8)
+                .map(|i| Config {
+                    png_path: dir.join("surface0000.png"),
+                    mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                    output_path: PathBuf::from(format!("/sys/merge-pna_{}.png", i)),
+                    log_level: LogLevel::Quiet,
+                    ..Default::default()
+                })
+                .collect();
+
+            let outcomes = run_jobs(&jobs, 1, false, true);
+            assert!(matches!(outcomes[..], [JobOutcome::Fatal]));
+
+            let outcomes = run_jobs(&jobs, 2, false, true);
+            assert!(outcomes.len() < jobs.len());
+            assert!(outcomes.iter().all(|v| matches!(v, JobOutcome::Fatal)));
+        }
+    }
+}
@@ -1,119 +1,2095 @@
 use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
     io::{BufRead, Write},
-    path::PathBuf,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use clap::Parser;
+use png::BitDepth;
 
-use crate::error::ConfigError;
+use crate::{
+    cache,
+    checksum::ChecksumAlgorithm,
+    config_file,
+    error::ConfigError,
+    expr::AlphaExpr,
+    log::LogLevel,
+    mask::{Gradient, GradientDirection},
+    pna::{AlphaSource, Channel, LumaWeights, MergeMode, MergeOptions, Packing},
+    process::{CompressionLevel, FilterMode, OutputColor, StrippedChunk},
+    resize::PartialCells,
+};
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub(crate) struct ConfigRaw {
-    /// Path to input png file.
-    #[arg(short, long)]
-    pub input_path: PathBuf,
-    /// Path to pna file [default: PATH_TO_PNG_DIR/PNG_NAME.pna]
-    #[arg(short, long)]
+    /// Path to input png file, or a directory or file name pattern to merge every png in it.
+    /// `-` reads the png from stdin. Without it, --pna-path is merged with --placeholder-color.
+    #[arg(short, long, required_unless_present = "pna_path")]
+    pub input_path: Option<PathBuf>,
+    /// Path to pna file [default: the first of PNG_NAME.pna, PNG_NAME.PNA and PNG_NAME_a.png in PATH_TO_PNG_DIR]
+    #[arg(short, long, conflicts_with_all = ["gen_gradient", "solid_alpha"])]
     pub pna_path: Option<PathBuf>,
-    /// Path to output png file [default: PATH_TO_PNG_DIR/PNG_NAME_new.png]
+    /// Path to output png file, or `-` for stdout [default: PATH_TO_PNG_DIR/PNG_NAME_new.png, stdout for stdin]
     #[arg(short, long)]
     pub output_path: Option<PathBuf>,
     /// Flag of force overwriting output png.
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, default_value_t = false, visible_aliases = ["yes", "assume-yes"])]
     pub force: bool,
+    /// Flag of caching decoded pna between runs (or set MERGE_PNA_CACHE_DECODED_PNA=1).
+    #[arg(long, default_value_t = false)]
+    pub cache_decoded_pna: bool,
+    /// Minimum percentage of pixels that must be non-transparent in the alpha mask.
+    #[arg(long, value_name = "PERCENT")]
+    pub min_coverage: Option<f64>,
+    /// Generate a gradient alpha mask instead of loading pna.
+    #[arg(long, value_name = "DIRECTION")]
+    pub gen_gradient: Option<GradientDirection>,
+    /// Alpha at the start of the generated gradient.
+    #[arg(long, value_name = "ALPHA", default_value_t = u8::MAX, requires = "gen_gradient")]
+    pub gradient_start: u8,
+    /// Alpha at the end of the generated gradient.
+    #[arg(
+        long,
+        value_name = "ALPHA",
+        default_value_t = 0,
+        requires = "gen_gradient"
+    )]
+    pub gradient_end: u8,
+    /// Fail unless the input png is exactly this size.
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions)]
+    pub assert_dimensions: Option<(u32, u32)>,
+    /// Path to write a downscaled thumbnail of the merged png.
+    #[arg(long, requires = "thumb_size")]
+    pub thumbnail: Option<PathBuf>,
+    /// Size of the thumbnail.
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions, requires = "thumbnail")]
+    pub thumb_size: Option<(u32, u32)>,
+    /// Slice the merged png into this many columns and rows, each written as PNG_NAME_INDEX.png next to the output.
+    #[arg(long, value_name = "COLSxROWS", value_parser = parse_dimensions, conflicts_with = "cell_size")]
+    pub grid: Option<(u32, u32)>,
+    /// Slice the merged png into cells of this size, each written as PNG_NAME_INDEX.png next to the output.
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions)]
+    pub cell_size: Option<(u32, u32)>,
+    /// Handling of grid cells cut short by the right or bottom edge.
+    #[arg(long, value_enum, default_value_t = PartialCells::Pad)]
+    pub partial_cells: PartialCells,
+    /// Channel of a grayscale-alpha pna used as the alpha mask.
+    #[arg(long, value_enum, default_value_t = AlphaSource::Alpha)]
+    pub alpha_source: AlphaSource,
+    /// Weights used to reduce the color of a pna without an alpha channel to the mask.
+    #[arg(long, value_enum, default_value_t = LumaWeights::Bt601)]
+    pub luma_weights: LumaWeights,
+    /// Path to write the alpha as a packed 1-bit bitmask.
+    #[arg(long)]
+    pub bitmask: Option<PathBuf>,
+    /// Alpha at or above this is set in the bitmask.
+    #[arg(
+        long,
+        value_name = "ALPHA",
+        default_value_t = 128,
+        requires = "bitmask"
+    )]
+    pub bitmask_threshold: u8,
+    /// Decode and merge without writing any file.
+    #[arg(long, default_value_t = false)]
+    pub validate_only: bool,
+    /// Decode and merge as --validate-only does, printing which png and mask make each output and which outputs already exist.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["dump_info", "diff_existing"])]
+    pub dry_run: bool,
+    /// Make the silhouette of the mask opaque and fade it outward over this many pixels.
+    #[arg(long, value_name = "PX")]
+    pub edge_feather: Option<u32>,
+    /// Use pna as the color image and the input png as the alpha mask.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["gen_gradient", "solid_alpha"])]
+    pub swap_roles: bool,
+    /// Size in bytes of the buffers used for reading and writing files.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_BUFFER_SIZE)]
+    pub buffer_size: usize,
+    /// Threshold the mask to binary at this alpha before anti-aliasing its edge.
+    #[arg(long, value_name = "ALPHA")]
+    pub cutout: Option<u8>,
+    /// Width in pixels of the anti-aliasing applied on each side of the cutout edge.
+    #[arg(long, value_name = "PX", default_value_t = 1, requires = "cutout")]
+    pub cutout_aa: u32,
+    /// Flag of ignoring .merge-pna.toml.
+    #[arg(long, default_value_t = false)]
+    pub no_config_file: bool,
+    /// Reduce the alpha mask to N evenly spaced levels (2-256).
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(2..=256))]
+    pub posterize_alpha: Option<u16>,
+    /// Make the alpha binary as the last step of the mask: opaque at or above this alpha, transparent below.
+    #[arg(long, value_name = "ALPHA", conflicts_with = "cutout")]
+    pub threshold: Option<u8>,
+    /// Keep the png's own alpha where the mask is exactly this value, instead of combining them.
+    #[arg(long, value_name = "ALPHA")]
+    pub mask_passthrough_value: Option<u8>,
+    /// Make pixels transparent where r, g and b are all within this tolerance of black.
+    #[arg(long, value_name = "TOLERANCE")]
+    pub black_is_transparent: Option<u8>,
+    /// Directory to write each channel of the merged png as PNG_NAME_{r,g,b,a}.png.
+    #[arg(long, value_name = "DIR")]
+    pub split_channels: Option<PathBuf>,
+    /// Bit depth of the grayscale pngs written by --split-channels (1, 2, 4 or 8).
+    #[arg(
+        long,
+        value_name = "BITS",
+        default_value = "8",
+        value_parser = parse_gray_bit_depth,
+        requires = "split_channels"
+    )]
+    pub split_depth: BitDepth,
+    /// Fail unless pna is grayscale (or every pixel of it is gray).
+    #[arg(long, default_value_t = false)]
+    pub expect_pna_grayscale: bool,
+    /// Flag of inverting the mask reduced from pna, for pnas where black is opaque and white is transparent.
+    #[arg(long, default_value_t = false)]
+    pub invert_alpha: bool,
+    /// Path to a reference pna whose alpha histogram the mask is remapped to.
+    #[arg(long, value_name = "REFERENCE_PNA")]
+    pub match_histogram: Option<PathBuf>,
+    /// Flag of skipping without prompt when output png already exists (takes precedence over --force).
+    #[arg(long, default_value_t = false)]
+    pub no_clobber: bool,
+    /// Flag of renaming an existing output png to OUTPUT.bak (or OUTPUT.bak.N) before writing it.
+    #[arg(long, default_value_t = false, conflicts_with = "no_clobber")]
+    pub backup: bool,
+    /// Apply this alpha uniformly to the whole png instead of loading pna.
+    #[arg(long, value_name = "ALPHA", conflicts_with = "gen_gradient")]
+    pub solid_alpha: Option<u8>,
+    /// Print decoded properties of the input png as JSON and exit.
+    #[arg(long, default_value_t = false)]
+    pub dump_info: bool,
+    /// Fade the mask from the inscribed circle toward the corners by this strength (0.0-1.0).
+    #[arg(long, value_name = "STRENGTH")]
+    pub vignette: Option<f64>,
+    /// Scale the mask by the luminance of the png with this strength (0.0-1.0), keeping it where the png is bright.
+    #[arg(long, value_name = "STRENGTH")]
+    pub modulate_by_luma: Option<f64>,
+    /// Replace the alpha with this expression of the alpha a, the luminance l of the png, x, y, w and h, after the other adjustments.
+    #[arg(long, value_name = "EXPR")]
+    pub alpha_expr: Option<AlphaExpr>,
+    /// Flag of treating the colors of input png as premultiplied by its alpha.
+    #[arg(long, default_value_t = false)]
+    pub input_is_premultiplied: bool,
+    /// Fail when decoded image data is longer than the image size, instead of ignoring the rest.
+    #[arg(long, default_value_t = false)]
+    pub strict_data_size: bool,
+    /// How the mask is combined with the alpha the input png already has.
+    #[arg(long, value_enum, default_value_t = MergeMode::Replace)]
+    pub merge_mode: MergeMode,
+    /// Channel of the output the mask is written to, for packing it into a color channel of a texture.
+    #[arg(long, value_enum, default_value_t = Channel::A)]
+    pub pack_into: Channel,
+    /// Fill the channels other than --pack-into with this value instead of taking them from the png.
+    #[arg(long, value_name = "VALUE")]
+    pub pack_fill: Option<u8>,
+    /// Path to a csv file to append the metrics of this merge to.
+    #[arg(long, value_name = "CSV")]
+    pub stats_csv: Option<PathBuf>,
+    /// Flag of setting the modification time of output png to the newest of png and pna.
+    #[arg(long, default_value_t = false)]
+    pub preserve_mtime: bool,
+    /// Apply the mask only to pixels of indexed png whose palette index is in these ranges (e.g. 0-15,32).
+    #[arg(long, value_name = "RANGES", value_parser = parse_mask_indices)]
+    pub mask_indices: Option<MaskIndices>,
+    /// Flag of printing errors to stderr as JSON.
+    #[arg(long, default_value_t = false)]
+    pub json_errors: bool,
+    /// Flag of failing the run on any warning, such as a png without pna in a directory input.
+    #[arg(long, default_value_t = false)]
+    pub warnings_as_errors: bool,
+    /// Print errors only: no warnings and no notices around the overwrite prompt.
+    #[arg(short, long, default_value_t = false, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Also print each png as it is merged, with its size, color type and bit depth, and its output path.
+    #[arg(short, long, default_value_t = false)]
+    pub verbose: bool,
+    /// Merge only the first N pngs of a directory or pattern input, in sorted order.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub limit: Option<u64>,
+    /// Number of pngs of a directory or pattern input merged at the same time.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: usize,
+    /// Try a merge again this many times after an I/O error that may be temporary.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+    /// Wait before each retry, starting around this many milliseconds and doubling, with jitter.
+    #[arg(long, value_name = "MS", requires = "retries")]
+    pub retry_backoff: Option<u64>,
+    /// Use a band of this width along the silhouette of the png's own alpha (or luminance) as the mask.
+    #[arg(
+        long,
+        value_name = "PX",
+        conflicts_with_all = ["pna_path", "gen_gradient", "solid_alpha", "swap_roles"]
+    )]
+    pub edge_mask: Option<u32>,
+    /// Derive the mask from the color distance between the png and this background plate, transparent where they match.
+    #[arg(
+        long,
+        value_name = "PLATE",
+        conflicts_with_all = ["pna_path", "gen_gradient", "solid_alpha", "edge_mask", "swap_roles"]
+    )]
+    pub background_plate: Option<PathBuf>,
+    /// Color distance from the background plate at which the mask becomes opaque.
+    #[arg(
+        long,
+        value_name = "DISTANCE",
+        default_value_t = 64.0,
+        requires = "background_plate"
+    )]
+    pub diff_threshold: f64,
+    /// Force the outermost PX pixel rings of the mask fully opaque [default when given without PX: 1].
+    #[arg(long, value_name = "PX", num_args = 0..=1, default_missing_value = "1")]
+    pub opaque_edges: Option<u32>,
+    /// Merge in memory and report whether the existing output would change, without writing.
+    #[arg(long, default_value_t = false)]
+    pub diff_existing: bool,
+    /// Treat mask values as depth, opaque at NEAR and transparent at FAR (e.g. 0,255).
+    #[arg(long, value_name = "NEAR,FAR", value_parser = parse_depth_range)]
+    pub alpha_from_depth: Option<(u8, u8)>,
+    /// Color of the image the mask is applied to when --input-path is omitted.
+    #[arg(long, value_name = "R,G,B", default_value = "128,128,128", value_parser = parse_rgb)]
+    pub placeholder_color: [u8; 3],
+    /// Split the input png into an rgb png at --output-path and its alpha as a grayscale pna at --pna-path.
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "input_path",
+        conflicts_with_all = ["gen_gradient", "solid_alpha", "edge_mask", "background_plate", "swap_roles", "grid", "cell_size"]
+    )]
+    pub split: bool,
+    /// Composite the colors onto this background when splitting, instead of discarding the alpha.
+    #[arg(long, value_name = "R,G,B", value_parser = parse_rgb, requires = "split")]
+    pub split_background: Option<[u8; 3]>,
+    /// Flag of writing 16-bit rgba for a 16-bit png instead of reducing it to 8 bits.
+    #[arg(long, default_value_t = false)]
+    pub keep_16_bit: bool,
+    /// Write the merged png in each of these bit depths (8 and/or 16) as OUTPUT_STEM_BITS.png from one merge.
+    #[arg(
+        long,
+        value_name = "BITS",
+        value_delimiter = ',',
+        value_parser = parse_emit_bit_depth,
+        conflicts_with_all = ["split", "diff_existing", "keep_16_bit", "grid", "cell_size"]
+    )]
+    pub emit: Vec<BitDepth>,
+    /// zlib compression level of written pngs [default: the png encoder's]
+    #[arg(long, value_enum)]
+    pub compression: Option<CompressionLevel>,
+    /// Row filter of written pngs [default: the png encoder's]
+    #[arg(long, value_enum)]
+    pub filter: Option<FilterMode>,
+    /// Leave these chunks of the input png out of the merged png. text is tEXt, zTXt and iTXt.
+    #[arg(long, value_name = "LIST", value_enum, value_delimiter = ',')]
+    pub strip_chunks: Vec<StrippedChunk>,
+    /// Color type of the merged png.
+    #[arg(
+        long = "output",
+        value_name = "COLOR",
+        value_enum,
+        default_value_t = OutputColor::Rgba,
+        conflicts_with_all = ["split", "diff_existing", "keep_16_bit", "emit", "stream", "low_memory", "sequence"]
+    )]
+    pub output_color: OutputColor,
+    /// Path to an ICC profile to embed in the output as an iCCP chunk.
+    #[arg(long, value_name = "ICC")]
+    pub icc: Option<PathBuf>,
+    /// Fail without writing when the merged png is larger than this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub max_output_bytes: Option<u64>,
+    /// Write the hash of each merged png to OUTPUT_PATH.ALGORITHM, in the format of sha256sum and b3sum.
+    #[arg(long, value_name = "ALGORITHM", value_enum, conflicts_with = "split")]
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// Merge row by row instead of holding whole images in memory. Only for a pna mask without whole-image adjustments.
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "input_path",
+        conflicts_with_all = [
+            "cache_decoded_pna", "min_coverage", "gen_gradient", "thumbnail", "grid", "cell_size",
+            "bitmask", "edge_feather", "cutout", "split_channels", "match_histogram", "solid_alpha",
+            "vignette", "modulate_by_luma", "alpha_expr", "stats_csv", "mask_indices", "edge_mask",
+            "background_plate", "opaque_edges", "diff_existing", "alpha_from_depth", "split", "emit",
+            "max_output_bytes"
+        ]
+    )]
+    pub stream: bool,
+    /// Keep memory use bounded whatever the image size: implies --stream and --jobs 1, and caps what each png decoder may allocate.
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "input_path",
+        conflicts_with_all = [
+            "cache_decoded_pna", "min_coverage", "gen_gradient", "thumbnail", "grid", "cell_size",
+            "bitmask", "edge_feather", "cutout", "split_channels", "match_histogram", "solid_alpha",
+            "vignette", "modulate_by_luma", "alpha_expr", "stats_csv", "mask_indices", "edge_mask",
+            "background_plate", "opaque_edges", "diff_existing", "alpha_from_depth", "split", "emit",
+            "max_output_bytes", "jobs"
+        ]
+    )]
+    pub low_memory: bool,
+    /// Merge the pngs of a directory or pattern input, in sorted order, into the frames of an animated png written here.
+    #[arg(
+        long,
+        value_name = "APNG",
+        conflicts_with_all = [
+            "output_path", "thumbnail", "bitmask", "split_channels", "stats_csv", "preserve_mtime",
+            "dump_info", "diff_existing", "split", "emit", "keep_16_bit", "grid", "cell_size",
+            "max_output_bytes", "stream", "low_memory"
+        ]
+    )]
+    pub sequence: Option<PathBuf>,
+    /// File of `FRAME_INDEX MS` lines giving the delays of the frames of --sequence.
+    #[arg(long, value_name = "FILE", requires = "sequence")]
+    pub delays: Option<PathBuf>,
+    /// Delay in milliseconds of the frames of --sequence that --delays doesn't list.
+    #[arg(long, value_name = "MS", default_value_t = 100, requires = "sequence")]
+    pub default_delay: u16,
+    /// Treat --input-path as a tar or zip archive of png/pna pairs and write the merged pngs to the --output-path directory, or to a new zip archive when it ends with .zip.
+    #[cfg(feature = "archive")]
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["pna_path", "swap_roles", "thumbnail", "bitmask", "split_channels", "dump_info", "diff_existing", "grid", "cell_size", "emit", "stream", "low_memory", "sequence", "output_color"]
+    )]
+    pub archive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MaskSource {
+    Pna(PathBuf),
+    Gradient(Gradient),
+    Solid(u8),
+    Edge(u32),
+    Plate(BackgroundPlate),
+}
+
+impl Default for MaskSource {
+    fn default() -> Self {
+        Self::Pna(PathBuf::new())
+    }
+}
+
+impl fmt::Display for MaskSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pna(path) => write!(f, "{}", path.display()),
+            Self::Gradient(v) => write!(
+                f,
+                "gradient {} {}-{}",
+                format!("{:?}", v.direction).to_lowercase(),
+                v.start_alpha,
+                v.end_alpha
+            ),
+            Self::Solid(v) => write!(f, "solid alpha {}", v),
+            Self::Edge(v) => write!(f, "edge mask {}px", v),
+            Self::Plate(v) => write!(f, "background plate {}", v.path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BackgroundPlate {
+    pub path: PathBuf,
+    pub threshold: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Thumbnail {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Grid {
+    pub layout: GridLayout,
+    pub partial: PartialCells,
+}
+
+/// How the merged png is divided by `--grid` or `--cell-size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GridLayout {
+    Count { cols: u32, rows: u32 },
+    CellSize { width: u32, height: u32 },
+}
+
+impl GridLayout {
+    /// Size of a cell of an image of `width` x `height`. Counted cells are rounded up,
+    /// so only the last column and row can be partial.
+    pub(crate) fn cell_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match *self {
+            Self::Count { cols, rows } => {
+                (width.div_ceil(cols).max(1), height.div_ceil(rows).max(1))
+            }
+            Self::CellSize { width, height } => (width, height),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Bitmask {
+    pub path: PathBuf,
+    pub threshold: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cutout {
+    pub threshold: u8,
+    pub aa: u32,
+}
+
+/// Outputs of `--split`: the rgb png goes to `Config::output_path`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Split {
+    pub pna_path: PathBuf,
+    pub background: Option<[u8; 3]>,
+}
+
+/// Jobs made from the command line.
+#[derive(Debug)]
+pub(crate) struct BatchConfig {
+    pub jobs: Vec<Config>,
+    /// Pngs of a directory or pattern input that have no sibling pna.
+    pub skipped: Vec<PathBuf>,
+    /// With `--sequence`, the jobs are merged as its frames instead of to their outputs.
+    pub sequence: Option<Sequence>,
+    /// Whether the input was a directory or pattern rather than a single file.
+    pub is_batch: bool,
+}
+
+/// Animated png of `--sequence`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Sequence {
+    pub path: PathBuf,
+    /// Delays in milliseconds by frame index, from `--delays`.
+    pub delays: HashMap<usize, u16>,
+    pub default_delay: u16,
+}
+
+impl Sequence {
+    /// Delay in milliseconds of the frame at `index`.
+    pub(crate) fn delay(&self, index: usize) -> u16 {
+        self.delays
+            .get(&index)
+            .copied()
+            .unwrap_or(self.default_delay)
+    }
+}
+
+/// Palette indices selected by `--mask-indices`, as inclusive ranges.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MaskIndices(pub Vec<RangeInclusive<u8>>);
+
+impl MaskIndices {
+    pub(crate) fn contains(&self, index: usize) -> bool {
+        u8::try_from(index).is_ok_and(|i| self.0.iter().any(|r| r.contains(&i)))
+    }
 }
 
+/// Same as the default capacity of `BufWriter`.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Bytes each png decoder may allocate with `--low-memory`: a quarter of the `png`
+/// crate's default, still far more than a row of any image it can stream.
+pub(crate) const LOW_MEMORY_DECODER_LIMIT: usize = 16 * 1024 * 1024;
+
 #[derive(Debug)]
 pub(crate) struct Config {
     pub png_path: PathBuf,
-    pub pna_path: PathBuf,
+    pub mask_source: MaskSource,
     pub output_path: PathBuf,
+    pub cache_decoded_pna: bool,
+    pub min_coverage: Option<f64>,
+    pub assert_dimensions: Option<(u32, u32)>,
+    pub thumbnail: Option<Thumbnail>,
+    pub grid: Option<Grid>,
+    pub alpha_source: AlphaSource,
+    pub luma_weights: LumaWeights,
+    pub bitmask: Option<Bitmask>,
+    pub validate_only: bool,
+    /// Prints the plan of each job, on top of `validate_only`.
+    pub dry_run: bool,
+    pub edge_feather: Option<u32>,
+    pub buffer_size: usize,
+    pub cutout: Option<Cutout>,
+    pub posterize_alpha: Option<u16>,
+    pub threshold: Option<u8>,
+    /// Mask value at which the alpha of the png is kept unchanged.
+    pub mask_passthrough_value: Option<u8>,
+    pub black_is_transparent: Option<u8>,
+    pub split_channels: Option<PathBuf>,
+    pub split_depth: BitDepth,
+    pub expect_pna_grayscale: bool,
+    pub invert_alpha: bool,
+    pub match_histogram: Option<PathBuf>,
+    pub no_clobber: bool,
+    pub backup: bool,
+    pub dump_info: bool,
+    pub vignette: Option<f64>,
+    pub modulate_by_luma: Option<f64>,
+    pub alpha_expr: Option<AlphaExpr>,
+    pub input_is_premultiplied: bool,
+    pub strict_data_size: bool,
+    pub merge_mode: MergeMode,
+    pub packing: Packing,
+    pub stats_csv: Option<PathBuf>,
+    pub preserve_mtime: bool,
+    pub mask_indices: Option<MaskIndices>,
+    pub opaque_edges: Option<u32>,
+    pub diff_existing: bool,
+    pub alpha_from_depth: Option<(u8, u8)>,
+    /// Color of the image generated in place of a missing input png.
+    pub placeholder_color: Option<[u8; 3]>,
+    pub split: Option<Split>,
+    pub keep_16_bit: bool,
+    /// Bit depths of `--emit`. Empty writes only `output_path`.
+    pub emit: Vec<BitDepth>,
+    pub compression: Option<CompressionLevel>,
+    pub filter: Option<FilterMode>,
+    pub strip_chunks: Vec<StrippedChunk>,
+    pub output_color: OutputColor,
+    /// Contents of the `--icc` file.
+    pub icc_profile: Option<Vec<u8>>,
+    pub max_output_bytes: Option<u64>,
+    pub checksum: Option<ChecksumAlgorithm>,
+    pub stream: bool,
+    /// Bytes each png decoder may allocate, set by `--low-memory`.
+    pub decoder_limit: Option<usize>,
+    pub retries: u32,
+    pub retry_backoff: Option<Duration>,
+    pub log_level: LogLevel,
+    #[cfg(feature = "archive")]
+    pub archive: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            png_path: PathBuf::new(),
+            mask_source: MaskSource::default(),
+            output_path: PathBuf::new(),
+            cache_decoded_pna: false,
+            min_coverage: None,
+            assert_dimensions: None,
+            thumbnail: None,
+            grid: None,
+            alpha_source: AlphaSource::default(),
+            luma_weights: LumaWeights::default(),
+            bitmask: None,
+            validate_only: false,
+            dry_run: false,
+            edge_feather: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            cutout: None,
+            posterize_alpha: None,
+            threshold: None,
+            mask_passthrough_value: None,
+            black_is_transparent: None,
+            split_channels: None,
+            split_depth: BitDepth::Eight,
+            expect_pna_grayscale: false,
+            invert_alpha: false,
+            match_histogram: None,
+            no_clobber: false,
+            backup: false,
+            dump_info: false,
+            vignette: None,
+            modulate_by_luma: None,
+            alpha_expr: None,
+            input_is_premultiplied: false,
+            strict_data_size: false,
+            merge_mode: MergeMode::default(),
+            packing: Packing::default(),
+            stats_csv: None,
+            preserve_mtime: false,
+            mask_indices: None,
+            opaque_edges: None,
+            diff_existing: false,
+            alpha_from_depth: None,
+            placeholder_color: None,
+            split: None,
+            keep_16_bit: false,
+            emit: Vec::new(),
+            compression: None,
+            filter: None,
+            strip_chunks: Vec::new(),
+            output_color: OutputColor::Rgba,
+            icc_profile: None,
+            max_output_bytes: None,
+            checksum: None,
+            stream: false,
+            decoder_limit: None,
+            retries: 0,
+            retry_backoff: None,
+            log_level: LogLevel::default(),
+            #[cfg(feature = "archive")]
+            archive: false,
+        }
+    }
+}
+
+impl Config {
+    /// Options of the merge of the mask into the png, as a library caller would pass them.
+    pub(crate) fn merge_options(&self) -> MergeOptions {
+        MergeOptions {
+            alpha_source: self.alpha_source,
+            luma_weights: self.luma_weights,
+            invert: self.invert_alpha,
+            threshold: self.threshold,
+            merge_mode: self.merge_mode,
+            black_tolerance: self.black_is_transparent,
+            premultiplied: self.input_is_premultiplied,
+            mask_passthrough_value: self.mask_passthrough_value,
+            strict_data_size: self.strict_data_size,
+        }
+    }
+
+    /// Paths of the merged pngs: `output_path`, or one depth-suffixed path per `--emit` depth.
+    pub(crate) fn output_paths(&self) -> Vec<PathBuf> {
+        if self.emit.is_empty() {
+            return vec![self.output_path.clone()];
+        }
+        self.emit
+            .iter()
+            .map(|v| depth_suffixed_path(&self.output_path, *v))
+            .collect()
+    }
 }
 
 impl ConfigRaw {
+    /// Parses the arguments, using `.merge-pna.toml` found from the input path as defaults.
+    /// An `@FILE` argument is replaced with the arguments in FILE.
+    /// Precedence: command line > environment variables > config file > built-in defaults.
+    pub(crate) fn parse_with_config_file<I, T>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = expand_response_files(args.into_iter().map(Into::into).collect())?;
+        let config_raw = Self::parse_from(&args);
+        if config_raw.no_config_file {
+            return Ok(config_raw);
+        }
+
+        let Some(path) = config_file::find_config_file(config_raw.input_or_pna_path()) else {
+            return Ok(config_raw);
+        };
+        let file_args = config_file::load_config_file_as_args(&path)?;
+
+        // Later arguments override earlier ones, so the command line wins.
+        let merged_args = args
+            .iter()
+            .take(1)
+            .chain(file_args.iter())
+            .chain(args.iter().skip(1));
+
+        Self::try_parse_from(merged_args)
+            .map_err(|e| ConfigError::InvalidConfigFile(path, e.to_string()))
+    }
+
+    /// Same as `to_config_with_force_flag` for a file input. A directory input makes
+    /// one job per png in it, and a file name pattern one job per matching png;
+    /// pngs without a sibling pna are skipped when the mask comes from pna.
+    #[allow(clippy::wrong_self_convention)]
+    pub(crate) fn to_batch_config_with_force_flag(
+        self,
+    ) -> Result<(BatchConfig, bool), ConfigError> {
+        let sequence = self.to_sequence()?;
+        let input_path = self.input_or_pna_path().to_path_buf();
+        // A path that escaping changes has wildcards in it, unless it names a file as it is.
+        let pattern = input_path
+            .to_str()
+            .filter(|v| {
+                self.input_path.is_some() && !input_path.is_file() && glob::Pattern::escape(v) != *v
+            })
+            .map(|v| v.to_string());
+
+        if !(self.input_path.is_some() && input_path.is_dir()) && pattern.is_none() {
+            let (config, force_flag) = self.to_config_with_force_flag()?;
+            return Ok((
+                BatchConfig {
+                    jobs: vec![config],
+                    skipped: Vec::new(),
+                    sequence,
+                    is_batch: false,
+                },
+                force_flag,
+            ));
+        }
+
+        // These name a single file, so they can't be shared by the jobs.
+        for (name, is_set) in [
+            ("--pna-path", self.pna_path.is_some()),
+            ("--output-path", self.output_path.is_some()),
+            ("--thumbnail", self.thumbnail.is_some()),
+            ("--bitmask", self.bitmask.is_some()),
+        ] {
+            if is_set {
+                return Err(ConfigError::NotAllowedForDirectory(name.to_string()));
+            }
+        }
+
+        let needs_pna = !self.split
+            && self.gen_gradient.is_none()
+            && self.solid_alpha.is_none()
+            && self.edge_mask.is_none()
+            && self.background_plate.is_none();
+
+        let candidates: Vec<PathBuf> = match &pattern {
+            Some(pattern) => glob::glob(pattern)
+                .map_err(|e| ConfigError::InvalidPattern(pattern.clone(), e.to_string()))?
+                .collect::<Result<_, _>>()
+                .map_err(|e| {
+                    ConfigError::UnreadablePatternMatch(e.path().to_path_buf(), e.into())
+                })?,
+            None => std::fs::read_dir(&input_path)?
+                .map(|v| v.map(|v| v.path()))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut png_paths = Vec::new();
+        for path in candidates {
+            if path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|v| v.eq_ignore_ascii_case("png"))
+            {
+                png_paths.push(path);
+            }
+        }
+        if pattern.is_some() && png_paths.is_empty() {
+            return Err(ConfigError::PngIsNotExist);
+        }
+        png_paths.sort();
+
+        // Outputs of an earlier run are not inputs, and neither are masks stored as pngs.
+        let mut not_inputs: Vec<PathBuf> =
+            png_paths.iter().map(|p| default_output_path(p)).collect();
+        if needs_pna {
+            not_inputs.extend(png_paths.iter().filter_map(|p| find_pna_path(p)));
+        }
+        png_paths.retain(|p| !not_inputs.contains(p));
+        // Nor is the animation of an earlier run, however its path was written.
+        if let Some(sequence_path) = sequence.as_ref().and_then(|v| v.path.canonicalize().ok()) {
+            png_paths.retain(|p| p.canonicalize().ok().as_ref() != Some(&sequence_path));
+        }
+
+        let mut batch_config = BatchConfig {
+            jobs: Vec::new(),
+            skipped: Vec::new(),
+            sequence,
+            is_batch: true,
+        };
+        for png_path in png_paths {
+            if self
+                .limit
+                .is_some_and(|v| batch_config.jobs.len() as u64 >= v)
+            {
+                break;
+            }
+            if needs_pna && find_pna_path(&png_path).is_none() {
+                batch_config.skipped.push(png_path);
+                continue;
+            }
+
+            let config_raw = ConfigRaw {
+                input_path: Some(png_path),
+                ..self.clone()
+            };
+            let (config, _) = config_raw.to_config_with_force_flag()?;
+            batch_config.jobs.push(config);
+        }
+        // An animation needs a frame.
+        if batch_config.sequence.is_some() && batch_config.jobs.is_empty() {
+            return Err(ConfigError::PngIsNotExist);
+        }
+
+        Ok((batch_config, self.force))
+    }
+
+    fn to_sequence(&self) -> Result<Option<Sequence>, ConfigError> {
+        let Some(path) = &self.sequence else {
+            return Ok(None);
+        };
+        let delays = match &self.delays {
+            Some(delays_path) => std::fs::read_to_string(delays_path)
+                .map_err(|e| e.to_string())
+                .and_then(|v| parse_delays(&v))
+                .map_err(|e| ConfigError::InvalidDelays(delays_path.clone(), e))?,
+            None => HashMap::new(),
+        };
+
+        Ok(Some(Sequence {
+            path: path.clone(),
+            delays,
+            default_delay: self.default_delay,
+        }))
+    }
+
+    /// Level of `--quiet` or `--verbose`.
+    pub(crate) fn log_level(&self) -> LogLevel {
+        match (self.quiet, self.verbose) {
+            (true, _) => LogLevel::Quiet,
+            (_, true) => LogLevel::Verbose,
+            _ => LogLevel::Normal,
+        }
+    }
+
+    /// Returns the input path, or the pna path when the input is omitted.
+    pub(crate) fn input_or_pna_path(&self) -> &Path {
+        self.input_path
+            .as_deref()
+            .or(self.pna_path.as_deref())
+            .unwrap_or(Path::new(""))
+    }
+
+    fn is_archive(&self) -> bool {
+        #[cfg(feature = "archive")]
+        return self.archive;
+        #[cfg(not(feature = "archive"))]
+        false
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     pub(crate) fn to_config_with_force_flag(self) -> Result<(Config, bool), ConfigError> {
-        let png_path = self.input_path;
-        if !png_path.exists() || !png_path.is_file() {
+        let is_archive = self.is_archive();
+        let log_level = self.log_level();
+        // Without an input png, the pna is merged with a generated placeholder.
+        let placeholder_color = self.input_path.is_none().then_some(self.placeholder_color);
+        let png_path = self.input_or_pna_path().to_path_buf();
+        let reads_stdin = placeholder_color.is_none() && is_stdio(&png_path);
+        if placeholder_color.is_none()
+            && !reads_stdin
+            && (!png_path.exists() || !png_path.is_file())
+        {
             return Err(ConfigError::PngIsNotExist);
         }
 
-        let pna_path = if let Some(p) = self.pna_path {
-            p
+        let mask_source = if let Some(direction) = self.gen_gradient {
+            MaskSource::Gradient(Gradient {
+                direction,
+                start_alpha: self.gradient_start,
+                end_alpha: self.gradient_end,
+            })
+        } else if let Some(width) = self.edge_mask {
+            MaskSource::Edge(width)
+        } else if let Some(alpha) = self.solid_alpha {
+            MaskSource::Solid(alpha)
+        } else if let Some(path) = self.background_plate {
+            if self.diff_threshold.is_nan() || self.diff_threshold <= 0.0 {
+                return Err(ConfigError::InvalidDiffThreshold);
+            }
+            MaskSource::Plate(BackgroundPlate {
+                path,
+                threshold: self.diff_threshold,
+            })
         } else {
-            let mut p = png_path.clone();
-            p.set_extension("pna");
-            p
+            let pna_path = match self.pna_path {
+                Some(p) => p,
+                // --split writes the pna, so it goes to the first candidate.
+                None if self.split => pna_path_candidates(&png_path)[0].clone(),
+                None => find_pna_path(&png_path)
+                    .unwrap_or_else(|| pna_path_candidates(&png_path)[0].clone()),
+            };
+            // --dump-info only reads the input png, an archive carries its own pnas
+            // and --split writes the pna.
+            if !self.dump_info
+                && !is_archive
+                && !self.split
+                && (!pna_path.exists() || !pna_path.is_file())
+            {
+                return Err(ConfigError::InvalidPnaPath);
+            }
+            MaskSource::Pna(pna_path)
         };
-        if !pna_path.exists() || !pna_path.is_file() {
-            return Err(ConfigError::InvalidPnaPath);
-        }
 
         let output_path = if let Some(p) = self.output_path {
             p
+        } else if reads_stdin {
+            PathBuf::from(STDIO_PATH)
+        } else if is_archive {
+            png_path
+                .parent()
+                .filter(|v| !v.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf()
         } else {
-            let mut p = png_path.clone();
-            let mut p_file_name = p
-                .file_stem()
-                .expect("It's already checked that png file path is valid")
-                .to_os_string();
-            p_file_name.push("_new.png");
-
-            p.set_file_name(p_file_name);
-            p
+            default_output_path(&png_path)
+        };
+
+        // These need a file to read the time or size of, or a directory to write next to.
+        let writes_stdout = is_stdio(&output_path);
+        for (name, is_set) in [
+            ("--archive", is_archive),
+            ("--preserve-mtime", self.preserve_mtime),
+            ("--stats-csv", writes_stdout && self.stats_csv.is_some()),
+            ("--diff-existing", writes_stdout && self.diff_existing),
+            ("--emit", writes_stdout && !self.emit.is_empty()),
+            ("--grid", writes_stdout && self.grid.is_some()),
+            ("--cell-size", writes_stdout && self.cell_size.is_some()),
+            ("--checksum", writes_stdout && self.checksum.is_some()),
+        ] {
+            if is_set && (reads_stdin || writes_stdout) {
+                return Err(ConfigError::NotAllowedForStdio(name.to_string()));
+            }
+        }
+
+        if let Some(v) = self.min_coverage {
+            if !(0.0..=100.0).contains(&v) {
+                return Err(ConfigError::InvalidMinCoverage);
+            }
+        }
+
+        if self.buffer_size == 0 {
+            return Err(ConfigError::InvalidBufferSize);
+        }
+
+        if let Some(v) = self.vignette {
+            if !(0.0..=1.0).contains(&v) {
+                return Err(ConfigError::InvalidVignetteStrength);
+            }
+        }
+
+        if let Some(v) = self.modulate_by_luma {
+            if !(0.0..=1.0).contains(&v) {
+                return Err(ConfigError::InvalidModulationStrength);
+            }
+        }
+
+        let icc_profile = match self.icc {
+            Some(path) => {
+                let profile = std::fs::read(&path)?;
+                if profile.is_empty() {
+                    return Err(ConfigError::EmptyIccProfile(path));
+                }
+                Some(profile)
+            }
+            None => None,
+        };
+
+        // `--emit 8,8` writes the file once.
+        let mut emit = self.emit;
+        emit.sort_by_key(|v| *v as u8);
+        emit.dedup();
+
+        let thumbnail = match (self.thumbnail, self.thumb_size) {
+            (Some(path), Some((width, height))) => Some(Thumbnail {
+                path,
+                width,
+                height,
+            }),
+            _ => None,
+        };
+
+        let split = match &mask_source {
+            MaskSource::Pna(pna_path) if self.split => Some(Split {
+                pna_path: pna_path.clone(),
+                background: self.split_background,
+            }),
+            _ => None,
+        };
+
+        let grid_layout = match (self.grid, self.cell_size) {
+            (Some((cols, rows)), _) => Some(GridLayout::Count { cols, rows }),
+            (None, Some((width, height))) => Some(GridLayout::CellSize { width, height }),
+            (None, None) => None,
         };
+        let grid = grid_layout.map(|layout| Grid {
+            layout,
+            partial: self.partial_cells,
+        });
+
+        let (png_path, mask_source) = match mask_source {
+            MaskSource::Pna(pna_path) if self.swap_roles => (pna_path, MaskSource::Pna(png_path)),
+            v => (png_path, v),
+        };
+
+        // A dry run leaves the cache as it is too.
+        let cache_decoded_pna = (self.cache_decoded_pna
+            || std::env::var(cache::CACHE_ENABLE_ENV).is_ok_and(|v| v == "1"))
+            && !self.dry_run;
 
         Ok((
             Config {
                 png_path,
-                pna_path,
+                mask_source,
                 output_path,
+                cache_decoded_pna,
+                min_coverage: self.min_coverage,
+                assert_dimensions: self.assert_dimensions,
+                thumbnail,
+                grid,
+                alpha_source: self.alpha_source,
+                luma_weights: self.luma_weights,
+                bitmask: self.bitmask.map(|path| Bitmask {
+                    path,
+                    threshold: self.bitmask_threshold,
+                }),
+                validate_only: self.validate_only || self.dry_run,
+                dry_run: self.dry_run,
+                edge_feather: self.edge_feather,
+                buffer_size: self.buffer_size,
+                cutout: self.cutout.map(|threshold| Cutout {
+                    threshold,
+                    aa: self.cutout_aa,
+                }),
+                posterize_alpha: self.posterize_alpha,
+                threshold: self.threshold,
+                mask_passthrough_value: self.mask_passthrough_value,
+                black_is_transparent: self.black_is_transparent,
+                split_channels: self.split_channels,
+                split_depth: self.split_depth,
+                expect_pna_grayscale: self.expect_pna_grayscale,
+                invert_alpha: self.invert_alpha,
+                match_histogram: self.match_histogram,
+                no_clobber: self.no_clobber,
+                backup: self.backup,
+                dump_info: self.dump_info,
+                vignette: self.vignette,
+                modulate_by_luma: self.modulate_by_luma,
+                alpha_expr: self.alpha_expr,
+                input_is_premultiplied: self.input_is_premultiplied,
+                strict_data_size: self.strict_data_size,
+                merge_mode: self.merge_mode,
+                packing: Packing {
+                    channel: self.pack_into,
+                    fill: self.pack_fill,
+                },
+                stats_csv: self.stats_csv,
+                preserve_mtime: self.preserve_mtime,
+                mask_indices: self.mask_indices,
+                opaque_edges: self.opaque_edges,
+                diff_existing: self.diff_existing,
+                alpha_from_depth: self.alpha_from_depth,
+                placeholder_color,
+                split,
+                keep_16_bit: self.keep_16_bit,
+                emit,
+                compression: self.compression,
+                filter: self.filter,
+                strip_chunks: self.strip_chunks,
+                output_color: self.output_color,
+                icc_profile,
+                max_output_bytes: self.max_output_bytes,
+                checksum: self.checksum,
+                stream: self.stream || self.low_memory,
+                decoder_limit: self.low_memory.then_some(LOW_MEMORY_DECODER_LIMIT),
+                retries: self.retries,
+                retry_backoff: self.retry_backoff.map(Duration::from_millis),
+                log_level,
+                #[cfg(feature = "archive")]
+                archive: self.archive,
             },
             self.force,
         ))
     }
 }
 
-impl Config {
-    pub(crate) fn confirm_overwriting(&self) -> Result<(), ConfigError> {
-        if self.output_path.exists() {
-            let stdin = std::io::stdin();
-            let mut buf_reader = std::io::BufReader::new(stdin);
+/// Replaces each `@FILE` argument after the program name with the lines of FILE, one
+/// argument per line. Empty lines are skipped, and `@` in FILE is not expanded again.
+fn expand_response_files(args: Vec<OsString>) -> Result<Vec<OsString>, ConfigError> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for (i, arg) in args.into_iter().enumerate() {
+        let path = match arg.to_str().and_then(|v| v.strip_prefix('@')) {
+            Some(v) if i > 0 && !v.is_empty() => PathBuf::from(v),
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        let s = std::fs::read_to_string(&path)
+            .map_err(|e| ConfigError::InvalidResponseFile(path.clone(), e.to_string()))?;
+        expanded.extend(
+            s.lines()
+                .map(|v| v.strip_suffix('\r').unwrap_or(v))
+                .filter(|v| !v.is_empty())
+                .map(OsString::from),
+        );
+    }
+
+    Ok(expanded)
+}
+
+/// Reads `FRAME_INDEX MS` lines, separated by spaces or a comma. Empty lines and lines
+/// starting with `#` are skipped, and a later line for the same frame wins.
+fn parse_delays(s: &str) -> Result<HashMap<usize, u16>, String> {
+    let mut delays = HashMap::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (index, delay) = line
+            .split_once(|c: char| c == ',' || c.is_whitespace())
+            .ok_or_else(|| {
+                format!(
+                    "line {}: '{}' is not in the form FRAME_INDEX MS",
+                    i + 1,
+                    line
+                )
+            })?;
+        let index = index
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| format!("line {}: invalid frame index '{}': {}", i + 1, index, e))?;
+        let delay = delay
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| format!("line {}: invalid delay '{}': {}", i + 1, delay.trim(), e))?;
+        delays.insert(index, delay);
+    }
+
+    Ok(delays)
+}
+
+fn parse_dimensions(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("'{}' is not in the form WxH", s))?;
+    let width = width
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| format!("invalid width '{}': {}", width, e))?;
+    let height = height
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| format!("invalid height '{}': {}", height, e))?;
+
+    if width == 0 || height == 0 {
+        return Err(format!("'{}' has a zero dimension", s));
+    }
+
+    Ok((width, height))
+}
+
+/// `--input-path` and `--output-path` of this name mean stdin and stdout.
+pub(crate) const STDIO_PATH: &str = "-";
+
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
+}
+
+/// PATH_TO_PNG_DIR/PNG_NAME_new.png
+fn default_output_path(png_path: &Path) -> PathBuf {
+    let mut p_file_name = png_path
+        .file_stem()
+        .expect("It's already checked that png file path is valid")
+        .to_os_string();
+    p_file_name.push("_new.png");
+
+    png_path.with_file_name(p_file_name)
+}
+
+/// Paths where the pna of a png is looked for, in this order:
+/// PATH_TO_PNG_DIR/PNG_NAME.pna, PNG_NAME.PNA and PNG_NAME_a.png.
+fn pna_path_candidates(png_path: &Path) -> [PathBuf; 3] {
+    let with_suffix = |suffix: &str| {
+        let mut p_file_name = png_path.file_stem().unwrap_or_default().to_os_string();
+        p_file_name.push(suffix);
+        png_path.with_file_name(p_file_name)
+    };
+
+    [
+        with_suffix(".pna"),
+        with_suffix(".PNA"),
+        with_suffix("_a.png"),
+    ]
+}
+
+/// The first of `pna_path_candidates` that is a file.
+fn find_pna_path(png_path: &Path) -> Option<PathBuf> {
+    pna_path_candidates(png_path)
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// PATH_TO_OUTPUT_DIR/OUTPUT_STEM_BITS.png
+fn depth_suffixed_path(output_path: &Path, bit_depth: BitDepth) -> PathBuf {
+    let mut p_file_name = output_path.file_stem().unwrap_or_default().to_os_string();
+    p_file_name.push(format!("_{}", bit_depth as u8));
+    let mut path = output_path.with_file_name(p_file_name);
+    if let Some(extension) = output_path.extension() {
+        path.set_extension(extension);
+    }
+
+    path
+}
+
+fn parse_emit_bit_depth(s: &str) -> Result<BitDepth, String> {
+    match s.trim() {
+        "8" => Ok(BitDepth::Eight),
+        "16" => Ok(BitDepth::Sixteen),
+        _ => Err(format!("'{}' is not 8 or 16", s)),
+    }
+}
+
+fn parse_depth_range(s: &str) -> Result<(u8, u8), String> {
+    let (near, far) = s
+        .split_once(',')
+        .ok_or_else(|| format!("'{}' is not in the form NEAR,FAR", s))?;
+    let near = near
+        .trim()
+        .parse::<u8>()
+        .map_err(|e| format!("invalid near '{}': {}", near, e))?;
+    let far = far
+        .trim()
+        .parse::<u8>()
+        .map_err(|e| format!("invalid far '{}': {}", far, e))?;
+
+    if near == far {
+        return Err(format!("'{}' has the same near and far", s));
+    }
+
+    Ok((near, far))
+}
 
-            let stdout = std::io::stdout();
-            let stdout_lock = stdout.lock();
-            let mut buf_writer = std::io::BufWriter::new(stdout_lock);
+fn parse_rgb(s: &str) -> Result<[u8; 3], String> {
+    let values = s
+        .split(',')
+        .map(|v| {
+            v.trim()
+                .parse::<u8>()
+                .map_err(|e| format!("invalid color '{}': {}", v, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-            let mut s = String::new();
+    values
+        .try_into()
+        .map_err(|_| format!("'{}' is not in the form R,G,B", s))
+}
+
+fn parse_gray_bit_depth(s: &str) -> Result<BitDepth, String> {
+    match s.trim() {
+        "1" => Ok(BitDepth::One),
+        "2" => Ok(BitDepth::Two),
+        "4" => Ok(BitDepth::Four),
+        "8" => Ok(BitDepth::Eight),
+        _ => Err(format!("'{}' is not one of 1, 2, 4 or 8", s)),
+    }
+}
+
+fn parse_mask_indices(s: &str) -> Result<MaskIndices, String> {
+    let parse_index = |v: &str| {
+        v.trim()
+            .parse::<u8>()
+            .map_err(|e| format!("invalid index '{}': {}", v, e))
+    };
+
+    s.split(',')
+        .map(|part| match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse_index(start)?, parse_index(end)?);
+                if start > end {
+                    return Err(format!("'{}' is an empty range", part));
+                }
+                Ok(start..=end)
+            }
+            None => parse_index(part).map(|v| v..=v),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(MaskIndices)
+}
+
+/// Asks on stdin whether to overwrite `path` when it exists. Returns false when it was
+/// declined. With `LogLevel::Quiet`, only the question is printed.
+pub(crate) fn confirm_overwriting(path: &Path, log_level: LogLevel) -> Result<bool, ConfigError> {
+    if path.exists() {
+        let stdin = std::io::stdin();
+        let mut buf_reader = std::io::BufReader::new(stdin);
+
+        let stdout = std::io::stdout();
+        let stdout_lock = stdout.lock();
+        let mut buf_writer = std::io::BufWriter::new(stdout_lock);
+
+        let mut s = String::new();
+        if log_level > LogLevel::Quiet {
             buf_writer.write_all(b"The output file already exists.\n")?;
+        }
 
-            loop {
-                buf_writer.write_all(b"Do you want to overwrite the file? [Y/n]: ")?;
-                buf_writer.flush()?;
+        loop {
+            buf_writer.write_all(b"Do you want to overwrite the file? [y/n]: ")?;
+            buf_writer.flush()?;
 
-                s.clear();
-                buf_reader.read_line(&mut s)?;
+            s.clear();
+            // EOF, as when run non-interactively, declines.
+            let answer = match buf_reader.read_line(&mut s)? {
+                0 => Some(false),
+                _ => parse_answer(&s),
+            };
 
-                match s.trim() {
-                    "Y" => {
+            match answer {
+                Some(true) => {
+                    if log_level > LogLevel::Quiet {
                         buf_writer.write_all(b"The file will be overwritten.\n")?;
                         buf_writer.flush()?;
-                        break;
-                    }
-                    "n" => {
-                        buf_writer.write_all(b"Closing this program...\n")?;
-                        buf_writer.flush()?;
-                        std::process::exit(0);
-                    }
-                    _ => {
-                        buf_writer.write_all(
-                            b"Please input 'Y' or 'n'. (for closing this program, input 'n')\n",
-                        )?;
                     }
+                    break;
+                }
+                Some(false) => return Ok(false),
+                None => {
+                    buf_writer.write_all(b"Please input 'y' or 'n'.\n")?;
+                }
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// `y`/`yes` or `n`/`no` in any case, or `None` for anything else.
+fn parse_answer(s: &str) -> Option<bool> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod to_batch_config_with_force_flag {
+        use super::*;
+
+        #[test]
+        fn success_when_directory() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in [
+                "b.png",
+                "b.pna",
+                "a.PNG",
+                "a.pna",
+                "c.png",
+                "d.pna",
+                "b_new.png",
+            ] {
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+            let config_raw =
+                ConfigRaw::parse_from(["merge-pna", "-i", dir.to_str().unwrap(), "-f"]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+            let (batch_config, force_flag) = result.unwrap();
+
+            assert!(force_flag);
+            assert_eq!(
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| (v.png_path.clone(), v.output_path.clone()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (dir.join("a.PNG"), dir.join("a_new.png")),
+                    (dir.join("b.png"), dir.join("b_new.png")),
+                ]
+            );
+            assert_eq!(batch_config.skipped, vec![dir.join("c.png")]);
+        }
+
+        #[test]
+        fn success_when_pna_candidates() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_candidates");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in ["a.png", "a.PNA", "b.png", "b_a.png", "c.png"] {
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+            let config_raw = ConfigRaw::parse_from(["merge-pna", "-i", dir.to_str().unwrap()]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+            let (batch_config, _) = result.unwrap();
+
+            assert_eq!(
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| (v.png_path.clone(), v.mask_source.clone()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (dir.join("a.png"), MaskSource::Pna(dir.join("a.PNA"))),
+                    (dir.join("b.png"), MaskSource::Pna(dir.join("b_a.png"))),
+                ]
+            );
+            assert_eq!(batch_config.skipped, vec![dir.join("c.png")]);
+        }
+
+        #[test]
+        fn success_when_limited() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_limit");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in [
+                "a.png", "a.pna", "b.png", "c.png", "c.pna", "d.png", "d.pna",
+            ] {
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+            let config_raw =
+                ConfigRaw::parse_from(["merge-pna", "-i", dir.to_str().unwrap(), "--limit", "2"]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+            let (batch_config, _) = result.unwrap();
+
+            assert_eq!(
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| v.png_path.clone())
+                    .collect::<Vec<_>>(),
+                vec![dir.join("a.png"), dir.join("c.png")]
+            );
+            assert_eq!(batch_config.skipped, vec![dir.join("b.png")]);
+            assert!(
+                ConfigRaw::try_parse_from(["merge-pna", "-i", "a.png", "--limit", "0"]).is_err()
+            );
+        }
+
+        #[test]
+        fn success_when_pattern() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_pattern");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in [
+                "surface0.png",
+                "surface0.pna",
+                "surface1.png",
+                "surface1.pna",
+                "surface2.png",
+                "other.png",
+                "other.pna",
+            ] {
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+            let pattern = dir.join("surface*.png");
+            let config_raw = ConfigRaw::parse_from(["merge-pna", "-i", pattern.to_str().unwrap()]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+            let (batch_config, _) = result.unwrap();
+
+            assert_eq!(
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| v.png_path.clone())
+                    .collect::<Vec<_>>(),
+                vec![dir.join("surface0.png"), dir.join("surface1.png")]
+            );
+            assert_eq!(batch_config.skipped, vec![dir.join("surface2.png")]);
+        }
+
+        #[test]
+        fn success_when_pattern_in_directory() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_pattern_in_directory");
+            let _ = std::fs::remove_dir_all(&dir);
+            for name in [
+                "a/surface0.png",
+                "a/surface0.pna",
+                "b/c/surface1.png",
+                "b/c/surface1.pna",
+            ] {
+                std::fs::create_dir_all(dir.join(name).parent().unwrap()).unwrap();
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+
+            let mut results = Vec::new();
+            for pattern in ["*/surface*.png", "**/surface*.png"] {
+                let pattern = dir.join(pattern);
+                let config_raw =
+                    ConfigRaw::parse_from(["merge-pna", "-i", pattern.to_str().unwrap()]);
+                results.push(config_raw.to_batch_config_with_force_flag());
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+            let png_paths = |result: Result<(BatchConfig, bool), ConfigError>| {
+                let (batch_config, _) = result.unwrap();
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| v.png_path.clone())
+                    .collect::<Vec<_>>()
+            };
+
+            let mut results = results.into_iter();
+            assert_eq!(
+                png_paths(results.next().unwrap()),
+                vec![dir.join("a/surface0.png")]
+            );
+            assert_eq!(
+                png_paths(results.next().unwrap()),
+                vec![dir.join("a/surface0.png"), dir.join("b/c/surface1.png")]
+            );
+        }
+
+        #[test]
+        fn failed_when_invalid_pattern() {
+            let config_raw = ConfigRaw::parse_from(["merge-pna", "-i", "frames/[a.png"]);
+
+            assert!(matches!(
+                config_raw.to_batch_config_with_force_flag(),
+                Err(ConfigError::InvalidPattern(_, _))
+            ));
+        }
+
+        #[test]
+        fn failed_when_directory_with_output_path() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let config_raw =
+                ConfigRaw::parse_from(["merge-pna", "-i", dir.to_str().unwrap(), "-o", "out.png"]);
+
+            assert!(matches!(
+                config_raw.to_batch_config_with_force_flag(),
+                Err(ConfigError::NotAllowedForDirectory(_))
+            ));
+        }
+
+        #[test]
+        fn success_when_sequence() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_sequence");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in ["f0.png", "f0.pna", "f1.png", "f1.pna", "anim.png"] {
+                std::fs::write(dir.join(name), []).unwrap();
+            }
+            std::fs::write(dir.join("delays.txt"), "# frame ms\n1 250\n").unwrap();
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                dir.to_str().unwrap(),
+                "--sequence",
+                dir.join("anim.png").to_str().unwrap(),
+                "--delays",
+                dir.join("delays.txt").to_str().unwrap(),
+            ]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+            let (batch_config, _) = result.unwrap();
+
+            assert_eq!(
+                batch_config
+                    .jobs
+                    .iter()
+                    .map(|v| v.png_path.clone())
+                    .collect::<Vec<_>>(),
+                vec![dir.join("f0.png"), dir.join("f1.png")]
+            );
+            assert!(batch_config.skipped.is_empty());
+            assert_eq!(
+                batch_config.sequence,
+                Some(Sequence {
+                    path: dir.join("anim.png"),
+                    delays: HashMap::from([(1, 250)]),
+                    default_delay: 100,
+                })
+            );
+        }
+
+        #[test]
+        fn failed_when_sequence_without_frames() {
+            let dir = std::env::temp_dir().join("merge-pna_batch_config_sequence_empty");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("a.png"), []).unwrap();
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                dir.to_str().unwrap(),
+                "--sequence",
+                "anim.png",
+            ]);
+
+            let result = config_raw.to_batch_config_with_force_flag();
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            assert!(matches!(result, Err(ConfigError::PngIsNotExist)));
+        }
+    }
+
+    mod to_config_with_force_flag {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_args() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let config_raw =
+                ConfigRaw::parse_from(["merge-pna", "-i", png_path.to_str().unwrap(), "-f"]);
+
+            let (config, force_flag) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert!(force_flag);
+            assert_eq!(config.png_path, png_path);
+            assert_eq!(
+                config.mask_source,
+                MaskSource::Pna(dir.join("surface0000.pna"))
+            );
+            assert_eq!(config.output_path, dir.join("surface0000_new.png"));
+        }
+
+        #[test]
+        fn success_when_pna_candidates() {
+            let dir = std::env::temp_dir().join("merge-pna_config_candidates");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let png_path = dir.join("a.png");
+            let pna_path = |explicit: Option<&str>| {
+                let mut args = vec!["merge-pna", "-i", png_path.to_str().unwrap()];
+                if let Some(name) = explicit {
+                    args.extend(["-p", name]);
                 }
+                ConfigRaw::parse_from(args)
+                    .to_config_with_force_flag()
+                    .map(|(config, _)| config.mask_source)
+            };
+            std::fs::write(&png_path, []).unwrap();
+
+            let none = pna_path(None);
+            std::fs::write(dir.join("a_a.png"), []).unwrap();
+            let mask_png = pna_path(None);
+            std::fs::write(dir.join("a.PNA"), []).unwrap();
+            let upper = pna_path(None);
+            std::fs::write(dir.join("a.pna"), []).unwrap();
+            let lower = pna_path(None);
+            let explicit_path = dir.join("a_a.png");
+            let explicit = pna_path(explicit_path.to_str());
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            assert!(matches!(none, Err(ConfigError::InvalidPnaPath)));
+            assert_eq!(mask_png.unwrap(), MaskSource::Pna(dir.join("a_a.png")));
+            assert_eq!(upper.unwrap(), MaskSource::Pna(dir.join("a.PNA")));
+            assert_eq!(lower.unwrap(), MaskSource::Pna(dir.join("a.pna")));
+            assert_eq!(explicit.unwrap(), MaskSource::Pna(dir.join("a_a.png")));
+        }
+
+        #[test]
+        fn success_when_yes_alias() {
+            for flag in ["--yes", "--assume-yes"] {
+                let config_raw = ConfigRaw::parse_from(["merge-pna", "-i", "a.png", flag]);
+
+                assert!(config_raw.force);
+            }
+        }
+
+        #[test]
+        fn success_when_stdin() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let pna_path = dir.join("surface0000.pna");
+            let config_raw =
+                ConfigRaw::parse_from(["merge-pna", "-i", "-", "-p", pna_path.to_str().unwrap()]);
+
+            let (config, _) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert_eq!(config.png_path, PathBuf::from("-"));
+            assert_eq!(config.mask_source, MaskSource::Pna(pna_path));
+            assert_eq!(config.output_path, PathBuf::from("-"));
+        }
+
+        #[test]
+        fn failed_when_stdout_with_preserve_mtime() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                dir.join("surface0000.png").to_str().unwrap(),
+                "-o",
+                "-",
+                "--preserve-mtime",
+            ]);
+
+            assert!(matches!(
+                config_raw.to_config_with_force_flag(),
+                Err(ConfigError::NotAllowedForStdio(_))
+            ));
+        }
+
+        #[test]
+        fn failed_when_icc_profile_is_empty() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let icc_path = std::env::temp_dir().join("merge-pna_empty.icc");
+            std::fs::write(&icc_path, []).unwrap();
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                dir.join("surface0000.png").to_str().unwrap(),
+                "--icc",
+                icc_path.to_str().unwrap(),
+            ]);
+
+            let result = config_raw.to_config_with_force_flag();
+            std::fs::remove_file(&icc_path).unwrap();
+
+            assert!(matches!(result, Err(ConfigError::EmptyIccProfile(_))));
+        }
+
+        #[test]
+        fn success_when_swap_roles() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--swap-roles",
+            ]);
+
+            let (config, _) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert_eq!(config.png_path, dir.join("surface0000.pna"));
+            assert_eq!(config.mask_source, MaskSource::Pna(png_path));
+            assert_eq!(config.output_path, dir.join("surface0000_new.png"));
+        }
+
+        #[test]
+        fn success_when_low_memory() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--low-memory",
+            ]);
+
+            let (config, _) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert!(config.stream);
+            assert_eq!(config.decoder_limit, Some(LOW_MEMORY_DECODER_LIMIT));
+            assert!(ConfigRaw::try_parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--low-memory",
+                "--jobs",
+                "2",
+            ])
+            .is_err());
+        }
+
+        #[test]
+        fn success_when_dry_run() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--dry-run",
+                "--cache-decoded-pna",
+            ]);
+
+            let (config, _) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert!(config.dry_run);
+            assert!(config.validate_only);
+            assert!(!config.cache_decoded_pna);
+            assert_eq!(
+                config.mask_source.to_string(),
+                dir.join("surface0000.pna").display().to_string()
+            );
+            assert_eq!(MaskSource::Solid(128).to_string(), "solid alpha 128");
+        }
+
+        #[test]
+        fn success_when_log_level() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let log_level = |flag: Option<&str>| {
+                let mut args = vec!["merge-pna", "-i", png_path.to_str().unwrap()];
+                args.extend(flag);
+                let (config, _) = ConfigRaw::parse_from(args)
+                    .to_config_with_force_flag()
+                    .unwrap();
+                config.log_level
+            };
+
+            assert_eq!(log_level(None), LogLevel::Normal);
+            assert_eq!(log_level(Some("-q")), LogLevel::Quiet);
+            assert_eq!(log_level(Some("--verbose")), LogLevel::Verbose);
+            assert!(ConfigRaw::try_parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--quiet",
+                "--verbose",
+            ])
+            .is_err());
+        }
+
+        #[test]
+        fn success_when_input_is_omitted() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let pna_path = dir.join("surface0000.pna");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-p",
+                pna_path.to_str().unwrap(),
+                "--placeholder-color",
+                "255,0,0",
+            ]);
+
+            let (config, _) = config_raw.to_config_with_force_flag().unwrap();
+
+            assert_eq!(config.placeholder_color, Some([255, 0, 0]));
+            assert_eq!(config.mask_source, MaskSource::Pna(pna_path));
+            assert_eq!(config.output_path, dir.join("surface0000_new.png"));
+        }
+
+        #[test]
+        fn failed_when_buffer_size_is_zero() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.join("surface0000.png");
+            let config_raw = ConfigRaw::parse_from([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--buffer-size",
+                "0",
+            ]);
+
+            assert!(matches!(
+                config_raw.to_config_with_force_flag(),
+                Err(ConfigError::InvalidBufferSize)
+            ));
+        }
+
+        #[test]
+        fn failed_when_png_is_not_exist() {
+            let config_raw = ConfigRaw::parse_from(["merge-pna", "-i", "not_exist.png"]);
+
+            assert!(matches!(
+                config_raw.to_config_with_force_flag(),
+                Err(ConfigError::PngIsNotExist)
+            ));
+        }
+    }
+
+    mod parse_with_config_file {
+        use super::*;
+
+        #[test]
+        fn success_when_config_file_is_found() {
+            let dir = std::env::temp_dir().join("merge-pna_config_file_found");
+            std::fs::create_dir_all(&dir).unwrap();
+            let png_path = dir.join("surface0000.png");
+            std::fs::write(&png_path, b"").unwrap();
+            std::fs::write(
+                dir.join(config_file::CONFIG_FILE_NAME),
+                "alpha_source = \"gray\"\nbuffer_size = 1024\nforce = true\n",
+            )
+            .unwrap();
+
+            let result = ConfigRaw::parse_with_config_file([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--buffer-size",
+                "2048",
+            ])
+            .unwrap();
+
+            assert_eq!(result.alpha_source, AlphaSource::Gray);
+            assert_eq!(result.buffer_size, 2048);
+            assert!(result.force);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_response_file() {
+            let dir = std::env::temp_dir().join("merge-pna_response_file");
+            std::fs::create_dir_all(&dir).unwrap();
+            let args_path = dir.join("args.txt");
+            std::fs::write(
+                &args_path,
+                "--no-config-file\r\n-i\r\na b.png\r\n\r\n--buffer-size\r\n1024\r\n",
+            )
+            .unwrap();
+
+            let result = ConfigRaw::parse_with_config_file([
+                "merge-pna",
+                &format!("@{}", args_path.display()),
+                "-f",
+            ])
+            .unwrap();
+
+            assert_eq!(result.input_path, Some(PathBuf::from("a b.png")));
+            assert_eq!(result.buffer_size, 1024);
+            assert!(result.force);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_response_file_is_missing() {
+            let result = ConfigRaw::parse_with_config_file(["merge-pna", "@merge-pna_missing.txt"]);
+
+            assert!(matches!(
+                result,
+                Err(ConfigError::InvalidResponseFile(_, _))
+            ));
+        }
+
+        #[test]
+        fn success_when_no_config_file() {
+            let dir = std::env::temp_dir().join("merge-pna_config_file_ignored");
+            std::fs::create_dir_all(&dir).unwrap();
+            let png_path = dir.join("surface0000.png");
+            std::fs::write(&png_path, b"").unwrap();
+            std::fs::write(dir.join(config_file::CONFIG_FILE_NAME), "force = true\n").unwrap();
+
+            let result = ConfigRaw::parse_with_config_file([
+                "merge-pna",
+                "-i",
+                png_path.to_str().unwrap(),
+                "--no-config-file",
+            ])
+            .unwrap();
+
+            assert!(!result.force);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_config_file_has_unknown_key() {
+            let dir = std::env::temp_dir().join("merge-pna_config_file_unknown");
+            std::fs::create_dir_all(&dir).unwrap();
+            let png_path = dir.join("surface0000.png");
+            std::fs::write(&png_path, b"").unwrap();
+            std::fs::write(dir.join(config_file::CONFIG_FILE_NAME), "unknown = 1\n").unwrap();
+
+            let result =
+                ConfigRaw::parse_with_config_file(["merge-pna", "-i", png_path.to_str().unwrap()]);
+
+            assert!(matches!(result, Err(ConfigError::InvalidConfigFile(_, _))));
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    mod parse_depth_range {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_str() {
+            assert_eq!(parse_depth_range("0,255"), Ok((0, 255)));
+            assert_eq!(parse_depth_range("200, 10"), Ok((200, 10)));
+        }
+
+        #[test]
+        fn failed_when_invalid_str() {
+            assert!(parse_depth_range("10").is_err());
+            assert!(parse_depth_range("10,10").is_err());
+            assert!(parse_depth_range("0,256").is_err());
+        }
+    }
+
+    mod parse_rgb {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_str() {
+            assert_eq!(parse_rgb("128,128,128"), Ok([128, 128, 128]));
+            assert_eq!(parse_rgb("0, 10 ,255"), Ok([0, 10, 255]));
+        }
+
+        #[test]
+        fn failed_when_invalid_str() {
+            assert!(parse_rgb("1,2").is_err());
+            assert!(parse_rgb("1,2,3,4").is_err());
+            assert!(parse_rgb("1,2,256").is_err());
+        }
+    }
+
+    mod parse_mask_indices {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_str() {
+            let result = parse_mask_indices("0-15, 32,255").unwrap();
+
+            assert_eq!(result, MaskIndices(vec![0..=15, 32..=32, 255..=255]));
+            assert!(result.contains(15));
+            assert!(!result.contains(16));
+            assert!(!result.contains(256));
+        }
+
+        #[test]
+        fn failed_when_invalid_str() {
+            assert!(parse_mask_indices("").is_err());
+            assert!(parse_mask_indices("15-0").is_err());
+            assert!(parse_mask_indices("0-256").is_err());
+            assert!(parse_mask_indices("a").is_err());
+        }
+    }
+
+    mod parse_dimensions {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_str() {
+            assert_eq!(parse_dimensions("640x480"), Ok((640, 480)));
+            assert_eq!(parse_dimensions("1X2"), Ok((1, 2)));
+        }
+
+        #[test]
+        fn failed_when_invalid_str() {
+            assert!(parse_dimensions("640").is_err());
+            assert!(parse_dimensions("640x").is_err());
+            assert!(parse_dimensions("0x480").is_err());
+            assert!(parse_dimensions("-1x480").is_err());
+        }
+    }
+
+    mod parse_delays {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_str() {
+            assert_eq!(
+                parse_delays("# frame ms\n\n0 50\n2,300\r\n 3\t 0 \n0 70\n"),
+                Ok(HashMap::from([(0, 70), (2, 300), (3, 0)]))
+            );
+        }
+
+        #[test]
+        fn failed_when_invalid_str() {
+            assert!(parse_delays("0").is_err());
+            assert!(parse_delays("a 50").is_err());
+            assert!(parse_delays("0 -1").is_err());
+            assert!(parse_delays("0 65536").is_err());
+            assert!(parse_delays("0 50 60").is_err());
+        }
+    }
+
+    mod parse_answer {
+        use super::*;
+
+        #[test]
+        fn success_when_yes_or_no_in_any_case() {
+            for s in ["y", "Y", "yes", "Yes", "YES\n"] {
+                assert_eq!(parse_answer(s), Some(true));
+            }
+            for s in ["n", "N", "no", "No\r\n"] {
+                assert_eq!(parse_answer(s), Some(false));
             }
         }
 
-        Ok(())
+        #[test]
+        fn failed_when_other_str() {
+            assert_eq!(parse_answer(""), None);
+            assert_eq!(parse_answer("yeah"), None);
+        }
     }
 }
@@ -0,0 +1,263 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use crate::error::ConfigError;
+
+pub(crate) const CONFIG_FILE_NAME: &str = ".merge-pna.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// Looks for `CONFIG_FILE_NAME` in the directory of `input_path` and its ancestors.
+pub(crate) fn find_config_file(input_path: &Path) -> Option<PathBuf> {
+    let input_path = match input_path.canonicalize() {
+        Ok(v) => v,
+        // A file name pattern doesn't exist itself, so start from its directory.
+        Err(_) => input_path
+            .parent()
+            .filter(|v| !v.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .canonicalize()
+            .ok()?
+            .join(input_path.file_name()?),
+    };
+    let start = if input_path.is_dir() {
+        input_path.as_path()
+    } else {
+        input_path.parent()?
+    };
+
+    start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|p| p.is_file())
+}
+
+/// Reads the config file and converts its entries into command line arguments.
+pub(crate) fn load_config_file_as_args(path: &Path) -> Result<Vec<OsString>, ConfigError> {
+    let content = std::fs::read_to_string(path)?;
+    let entries = parse_config_file(&content)
+        .map_err(|e| ConfigError::InvalidConfigFile(path.to_path_buf(), e))?;
+
+    Ok(entries_to_args(&entries))
+}
+
+/// Parses the subset of TOML used by the config file: top-level `key = value` lines
+/// with strings, integers, floats and booleans. Keys are the long option names,
+/// in either snake_case or kebab-case.
+pub(crate) fn parse_config_file(content: &str) -> Result<Vec<(String, ConfigValue)>, String> {
+    let mut entries = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!("line {}: tables are not supported", line_number));
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected 'key = value'", line_number))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("line {}: invalid key '{}'", line_number, key));
+        }
+
+        let value =
+            parse_value(value.trim()).map_err(|e| format!("line {}: {}", line_number, e))?;
+
+        entries.push((key.to_string(), value));
+    }
+
+    Ok(entries)
+}
+
+pub(crate) fn entries_to_args(entries: &[(String, ConfigValue)]) -> Vec<OsString> {
+    let mut args = Vec::new();
+
+    for (key, value) in entries {
+        let flag = OsString::from(format!("--{}", key.replace('_', "-")));
+        match value {
+            ConfigValue::Boolean(true) => args.push(flag),
+            ConfigValue::Boolean(false) => {}
+            ConfigValue::String(v) => {
+                args.push(flag);
+                args.push(OsString::from(v));
+            }
+            ConfigValue::Integer(v) => {
+                args.push(flag);
+                args.push(OsString::from(v.to_string()));
+            }
+            ConfigValue::Float(v) => {
+                args.push(flag);
+                args.push(OsString::from(v.to_string()));
+            }
+        }
+    }
+
+    args
+}
+
+fn parse_value(s: &str) -> Result<ConfigValue, String> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let (v, rest) = parse_basic_string(rest)?;
+        check_trailing(rest)?;
+        return Ok(ConfigValue::String(v));
+    }
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| "unterminated string".to_string())?;
+        check_trailing(&rest[end + 1..])?;
+        return Ok(ConfigValue::String(rest[..end].to_string()));
+    }
+
+    let s = match s.find('#') {
+        Some(i) => s[..i].trim(),
+        None => s,
+    };
+    match s {
+        "true" => return Ok(ConfigValue::Boolean(true)),
+        "false" => return Ok(ConfigValue::Boolean(false)),
+        _ => {}
+    }
+
+    let number = s.replace('_', "");
+    if let Ok(v) = number.parse::<i64>() {
+        return Ok(ConfigValue::Integer(v));
+    }
+    if let Ok(v) = number.parse::<f64>() {
+        return Ok(ConfigValue::Float(v));
+    }
+
+    Err(format!("invalid value '{}'", s))
+}
+
+fn parse_basic_string(s: &str) -> Result<(String, &str), String> {
+    let mut result = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((result, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => result.push('"'),
+                Some((_, '\\')) => result.push('\\'),
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                Some((_, c)) => return Err(format!("unsupported escape '\\{}'", c)),
+                None => break,
+            },
+            c => result.push(c),
+        }
+    }
+
+    Err("unterminated string".to_string())
+}
+
+fn check_trailing(s: &str) -> Result<(), String> {
+    let s = s.trim();
+    if s.is_empty() || s.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected '{}' after value", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_config_file {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_content() {
+            let content = r#"
+# project defaults
+force = true
+alpha-source = "gray"   # inline comment
+min_coverage = 12.5
+buffer_size = 65_536
+output_path = 'C:\out\a.png'
+swap_roles = false
+"#;
+
+            let result = parse_config_file(content).unwrap();
+
+            assert_eq!(
+                result,
+                vec![
+                    ("force".to_string(), ConfigValue::Boolean(true)),
+                    (
+                        "alpha-source".to_string(),
+                        ConfigValue::String("gray".to_string())
+                    ),
+                    ("min_coverage".to_string(), ConfigValue::Float(12.5)),
+                    ("buffer_size".to_string(), ConfigValue::Integer(65536)),
+                    (
+                        "output_path".to_string(),
+                        ConfigValue::String("C:\\out\\a.png".to_string())
+                    ),
+                    ("swap_roles".to_string(), ConfigValue::Boolean(false)),
+                ]
+            );
+        }
+
+        #[test]
+        fn failed_when_table() {
+            assert!(parse_config_file("[png]\ncompression = 9").is_err());
+        }
+
+        #[test]
+        fn failed_when_invalid_value() {
+            assert!(parse_config_file("force = yes").is_err());
+            assert!(parse_config_file("alpha_source = \"gray").is_err());
+            assert!(parse_config_file("alpha_source = \"gray\" x").is_err());
+        }
+    }
+
+    mod entries_to_args {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_entries() {
+            let entries = vec![
+                ("force".to_string(), ConfigValue::Boolean(true)),
+                ("swap_roles".to_string(), ConfigValue::Boolean(false)),
+                (
+                    "alpha_source".to_string(),
+                    ConfigValue::String("gray".to_string()),
+                ),
+                ("buffer-size".to_string(), ConfigValue::Integer(4096)),
+            ];
+
+            let result = entries_to_args(&entries);
+
+            assert_eq!(
+                result,
+                vec![
+                    OsString::from("--force"),
+                    OsString::from("--alpha-source"),
+                    OsString::from("gray"),
+                    OsString::from("--buffer-size"),
+                    OsString::from("4096"),
+                ]
+            );
+        }
+    }
+}
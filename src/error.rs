@@ -1,24 +1,405 @@
+use std::{fmt, path::Path};
+
 #[derive(Debug)]
 pub(crate) enum ConfigError {
-    #[allow(dead_code)]
     Io(std::io::Error),
     PngIsNotExist,
     InvalidPnaPath,
+    InvalidMinCoverage,
+    InvalidBufferSize,
+    InvalidVignetteStrength,
+    InvalidModulationStrength,
+    InvalidDiffThreshold,
+    InvalidConfigFile(std::path::PathBuf, String),
+    InvalidResponseFile(std::path::PathBuf, String),
+    InvalidDelays(std::path::PathBuf, String),
+    NotAllowedForDirectory(String),
+    NotAllowedForStdio(String),
+    CannotConfirmWithStdin(std::path::PathBuf),
+    EmptyIccProfile(std::path::PathBuf),
+    InvalidPattern(String, String),
+    UnreadablePatternMatch(std::path::PathBuf, std::io::Error),
 }
 
 #[derive(Debug)]
-pub(crate) enum MergeError {
-    #[allow(dead_code)]
+pub enum MergeError {
     Io(std::io::Error),
-    #[allow(dead_code)]
     DecodingError(png::DecodingError),
-    #[allow(dead_code)]
     EncodingError(png::EncodingError),
-    SizePngAndPnaAreDifferent,
+    SizePngAndPnaAreDifferent {
+        png: (u32, u32),
+        pna: (u32, u32),
+    },
     LessDataSize,
+    ImageTooLarge {
+        width: u32,
+        height: u32,
+    },
+    PartialPixel {
+        size: usize,
+        leftover: usize,
+    },
+    DataSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
     PaletteNotFoundWhenIndexedPng,
-    InvalidPalette,
-    InvalidIndexForPalette,
+    InvalidPalette {
+        len: usize,
+    },
+    InvalidIndexForPalette {
+        index: usize,
+        palette_len: usize,
+    },
+    OutputPermissionDenied(std::path::PathBuf),
+    CoverageTooLow {
+        coverage: f64,
+        min_coverage: f64,
+    },
+    PnaIsNotGrayscale(png::ColorType),
+    UnexpectedDimensions {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    NotAPng {
+        path: std::path::PathBuf,
+    },
+    InvalidArchive(String),
+    OutputTooLarge {
+        size: u64,
+        max: u64,
+    },
+    FrameSizeMismatch {
+        path: std::path::PathBuf,
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+}
+
+/// Non-fatal conditions, reported and then ignored unless `--warnings-as-errors` is set.
+#[derive(Debug)]
+pub(crate) enum Warning {
+    /// A png of a directory input has no sibling pna, so it was skipped.
+    PnaNotFound(std::path::PathBuf),
+    /// `--output indexed` wrote this output as rgba, as it has too many colors.
+    NotIndexed(std::path::PathBuf),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::PngIsNotExist => write!(f, "Input png does not exist"),
+            Self::InvalidPnaPath => write!(f, "Pna does not exist or is not a file"),
+            Self::InvalidMinCoverage => write!(f, "--min-coverage must be between 0 and 100"),
+            Self::InvalidBufferSize => write!(f, "--buffer-size must be greater than 0"),
+            Self::InvalidVignetteStrength => write!(f, "--vignette must be between 0.0 and 1.0"),
+            Self::InvalidModulationStrength => {
+                write!(f, "--modulate-by-luma must be between 0.0 and 1.0")
+            }
+            Self::InvalidDiffThreshold => write!(f, "--diff-threshold must be greater than 0"),
+            Self::InvalidConfigFile(path, message) => {
+                write!(f, "Invalid config file {}: {}", path.display(), message)
+            }
+            Self::InvalidResponseFile(path, message) => {
+                write!(f, "Invalid response file {}: {}", path.display(), message)
+            }
+            Self::InvalidDelays(path, message) => {
+                write!(f, "Invalid delays file {}: {}", path.display(), message)
+            }
+            Self::NotAllowedForDirectory(name) => {
+                write!(
+                    f,
+                    "{} can't be used with a directory or pattern input",
+                    name
+                )
+            }
+            Self::NotAllowedForStdio(name) => {
+                write!(f, "{} can't be used with - for stdin or stdout", name)
+            }
+            Self::CannotConfirmWithStdin(path) => write!(
+                f,
+                "{} already exists and the png is read from stdin, so use --force to overwrite it",
+                path.display()
+            ),
+            Self::EmptyIccProfile(path) => write!(f, "ICC profile {} is empty", path.display()),
+            Self::InvalidPattern(pattern, message) => {
+                write!(f, "Invalid pattern {}: {}", pattern, message)
+            }
+            Self::UnreadablePatternMatch(path, e) => write!(
+                f,
+                "{} matched by the pattern can't be read: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) | Self::UnreadablePatternMatch(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::DecodingError(e) => write!(f, "Failed to decode png: {}", e),
+            Self::EncodingError(e) => write!(f, "Failed to encode png: {}", e),
+            Self::SizePngAndPnaAreDifferent { png, pna } => write!(
+                f,
+                "PNG is {}x{} but PNA is {}x{}",
+                png.0, png.1, pna.0, pna.1
+            ),
+            Self::LessDataSize => write!(f, "Decoded data is shorter than the image size"),
+            Self::ImageTooLarge { width, height } => write!(
+                f,
+                "Image of {}x{} has more pixels than can be held in memory",
+                width, height
+            ),
+            Self::PartialPixel { size, leftover } => write!(
+                f,
+                "Decoded data ends with {} values, short of a whole pixel of {}",
+                leftover, size
+            ),
+            Self::DataSizeMismatch { expected, actual } => write!(
+                f,
+                "Decoded data has {} values where the image size has {}",
+                actual, expected
+            ),
+            Self::PaletteNotFoundWhenIndexedPng => write!(f, "Indexed png has no palette"),
+            Self::InvalidPalette { len } => {
+                write!(f, "Palette of {} bytes is not a list of rgb entries", len)
+            }
+            Self::InvalidIndexForPalette { index, palette_len } => write!(
+                f,
+                "Palette index {} is out of range for a palette of {} entries",
+                index, palette_len
+            ),
+            Self::OutputPermissionDenied(path) => {
+                write!(f, "Permission denied to write {}", path.display())
+            }
+            Self::CoverageTooLow {
+                coverage,
+                min_coverage,
+            } => write!(
+                f,
+                "Alpha coverage {:.2}% is below the minimum of {}%",
+                coverage, min_coverage
+            ),
+            Self::PnaIsNotGrayscale(color_type) => {
+                write!(f, "Pna is not grayscale (color type: {:?})", color_type)
+            }
+            Self::UnexpectedDimensions { expected, actual } => write!(
+                f,
+                "Expected png of {}x{}, but it is {}x{}",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            Self::NotAPng { path } => write!(f, "{} is not a png", path.display()),
+            Self::InvalidArchive(message) => write!(f, "Invalid archive: {}", message),
+            Self::OutputTooLarge { size, max } => write!(
+                f,
+                "Output of {} bytes is over the limit of {} bytes",
+                size, max
+            ),
+            Self::FrameSizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Frame {} is {}x{}, but the sequence is {}x{}",
+                path.display(),
+                actual.0,
+                actual.1,
+                expected.0,
+                expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::DecodingError(e) => Some(e),
+            Self::EncodingError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl MergeError {
+    /// Whether the error may go away when the same merge is tried again, as an I/O
+    /// error of a flaky mount does. Bad inputs and missing or forbidden files don't.
+    pub(crate) fn is_transient(&self) -> bool {
+        let e = match self {
+            Self::Io(e)
+            | Self::DecodingError(png::DecodingError::IoError(e))
+            | Self::EncodingError(png::EncodingError::IoError(e)) => e,
+            _ => return false,
+        };
+        !matches!(
+            e.kind(),
+            std::io::ErrorKind::NotFound
+                | std::io::ErrorKind::PermissionDenied
+                | std::io::ErrorKind::ReadOnlyFilesystem
+                | std::io::ErrorKind::AlreadyExists
+                | std::io::ErrorKind::InvalidInput
+                | std::io::ErrorKind::InvalidData
+                | std::io::ErrorKind::IsADirectory
+                | std::io::ErrorKind::NotADirectory
+                | std::io::ErrorKind::Unsupported
+        )
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PnaNotFound(path) => write!(f, "Skipped {}: no matching pna", path.display()),
+            Self::NotIndexed(path) => write!(
+                f,
+                "Wrote {} as rgba: it has more than 256 colors to index",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Kind name and related file of an error, printed by `--json-errors`.
+pub(crate) trait ErrorDetail: fmt::Display {
+    fn kind(&self) -> &'static str;
+
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+impl ErrorDetail for ConfigError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "Io",
+            Self::PngIsNotExist => "PngIsNotExist",
+            Self::InvalidPnaPath => "InvalidPnaPath",
+            Self::InvalidMinCoverage => "InvalidMinCoverage",
+            Self::InvalidBufferSize => "InvalidBufferSize",
+            Self::InvalidVignetteStrength => "InvalidVignetteStrength",
+            Self::InvalidModulationStrength => "InvalidModulationStrength",
+            Self::InvalidDiffThreshold => "InvalidDiffThreshold",
+            Self::InvalidConfigFile(_, _) => "InvalidConfigFile",
+            Self::InvalidResponseFile(_, _) => "InvalidResponseFile",
+            Self::InvalidDelays(_, _) => "InvalidDelays",
+            Self::NotAllowedForDirectory(_) => "NotAllowedForDirectory",
+            Self::NotAllowedForStdio(_) => "NotAllowedForStdio",
+            Self::CannotConfirmWithStdin(_) => "CannotConfirmWithStdin",
+            Self::EmptyIccProfile(_) => "EmptyIccProfile",
+            Self::InvalidPattern(_, _) => "InvalidPattern",
+            Self::UnreadablePatternMatch(_, _) => "UnreadablePatternMatch",
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::InvalidConfigFile(path, _)
+            | Self::InvalidResponseFile(path, _)
+            | Self::InvalidDelays(path, _)
+            | Self::CannotConfirmWithStdin(path)
+            | Self::EmptyIccProfile(path)
+            | Self::UnreadablePatternMatch(path, _) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorDetail for MergeError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "Io",
+            Self::DecodingError(_) => "DecodingError",
+            Self::EncodingError(_) => "EncodingError",
+            Self::SizePngAndPnaAreDifferent { .. } => "SizePngAndPnaAreDifferent",
+            Self::LessDataSize => "LessDataSize",
+            Self::ImageTooLarge { .. } => "ImageTooLarge",
+            Self::PartialPixel { .. } => "PartialPixel",
+            Self::DataSizeMismatch { .. } => "DataSizeMismatch",
+            Self::PaletteNotFoundWhenIndexedPng => "PaletteNotFoundWhenIndexedPng",
+            Self::InvalidPalette { .. } => "InvalidPalette",
+            Self::InvalidIndexForPalette { .. } => "InvalidIndexForPalette",
+            Self::OutputPermissionDenied(_) => "OutputPermissionDenied",
+            Self::CoverageTooLow { .. } => "CoverageTooLow",
+            Self::PnaIsNotGrayscale(_) => "PnaIsNotGrayscale",
+            Self::UnexpectedDimensions { .. } => "UnexpectedDimensions",
+            Self::NotAPng { .. } => "NotAPng",
+            Self::InvalidArchive(_) => "InvalidArchive",
+            Self::OutputTooLarge { .. } => "OutputTooLarge",
+            Self::FrameSizeMismatch { .. } => "FrameSizeMismatch",
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::OutputPermissionDenied(path)
+            | Self::NotAPng { path }
+            | Self::FrameSizeMismatch { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorDetail for Warning {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::PnaNotFound(_) => "PnaNotFound",
+            Self::NotIndexed(_) => "NotIndexed",
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::PnaNotFound(path) | Self::NotIndexed(path) => Some(path),
+        }
+    }
+}
+
+/// Formats the error as a single line of JSON: `{"kind":..,"message":..,"path":..}`.
+/// `fallback_path` is used when the error itself doesn't tell the file.
+pub(crate) fn error_to_json(e: &impl ErrorDetail, fallback_path: Option<&Path>) -> String {
+    let path = match e.path().or(fallback_path) {
+        Some(p) => json_string(&p.to_string_lossy()),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"kind\":{},\"message\":{},\"path\":{}}}",
+        json_string(e.kind()),
+        json_string(&e.to_string()),
+        path
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
 }
 
 impl From<std::io::Error> for ConfigError {
@@ -44,3 +425,68 @@ impl From<png::EncodingError> for MergeError {
         Self::EncodingError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod display {
+        use std::error::Error;
+
+        use super::*;
+
+        #[test]
+        fn success_when_error_carries_values() {
+            let e = MergeError::InvalidIndexForPalette {
+                index: 12,
+                palette_len: 4,
+            };
+
+            assert_eq!(
+                e.to_string(),
+                "Palette index 12 is out of range for a palette of 4 entries"
+            );
+        }
+
+        #[test]
+        fn success_when_source_is_wired() {
+            let e = MergeError::from(std::io::Error::other("disk full"));
+
+            assert_eq!(e.to_string(), "I/O error: disk full");
+            assert_eq!(e.source().unwrap().to_string(), "disk full");
+            assert!(MergeError::LessDataSize.source().is_none());
+            let boxed: Box<dyn Error> = Box::new(ConfigError::InvalidBufferSize);
+            assert_eq!(boxed.to_string(), "--buffer-size must be greater than 0");
+        }
+    }
+
+    mod error_to_json {
+        use super::*;
+
+        #[test]
+        fn success_when_error_has_path() {
+            let e = MergeError::NotAPng {
+                path: "dir/a \"b\".png".into(),
+            };
+
+            assert_eq!(
+                error_to_json(&e, Some(Path::new("fallback.png"))),
+                r#"{"kind":"NotAPng","message":"dir/a \"b\".png is not a png","path":"dir/a \"b\".png"}"#
+            );
+        }
+
+        #[test]
+        fn success_when_fallback_path() {
+            let e = MergeError::LessDataSize;
+
+            assert_eq!(
+                error_to_json(&e, Some(Path::new("a.png"))),
+                r#"{"kind":"LessDataSize","message":"Decoded data is shorter than the image size","path":"a.png"}"#
+            );
+            assert_eq!(
+                error_to_json(&ConfigError::InvalidBufferSize, None),
+                r#"{"kind":"InvalidBufferSize","message":"--buffer-size must be greater than 0","path":null}"#
+            );
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::str::FromStr;
+
+/// Values of the variables of an alpha expression at one pixel.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Vars {
+    /// Alpha of the mask.
+    pub a: f64,
+    /// Luminance of the png.
+    pub l: f64,
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+}
+
+/// Expression of `--alpha-expr`, parsed once and evaluated per pixel.
+///
+/// It has numbers, the variables `a`, `l`, `x`, `y`, `w` and `h`, `+ - * / %`,
+/// unary `-`, parentheses and the functions `min(p, q)`, `max(p, q)` and `abs(p)`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AlphaExpr(Node);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Var(Var),
+    Neg(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    A,
+    L,
+    X,
+    Y,
+    W,
+    H,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Func {
+    Min,
+    Max,
+    Abs,
+}
+
+impl Func {
+    fn arity(&self) -> usize {
+        match self {
+            Self::Min | Self::Max => 2,
+            Self::Abs => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(usize, usize),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+}
+
+impl AlphaExpr {
+    /// Alpha at the pixel of `vars`, rounded and clamped to 0-255. Division and `%`
+    /// by zero give 0.
+    pub(crate) fn eval(&self, vars: &Vars) -> u8 {
+        let v = self.0.eval(vars);
+        if v.is_nan() {
+            return 0;
+        }
+        v.round().clamp(0.0, u8::MAX as f64) as u8
+    }
+}
+
+impl Node {
+    fn eval(&self, vars: &Vars) -> f64 {
+        match self {
+            Self::Number(v) => *v,
+            Self::Var(var) => match var {
+                Var::A => vars.a,
+                Var::L => vars.l,
+                Var::X => vars.x,
+                Var::Y => vars.y,
+                Var::W => vars.w,
+                Var::H => vars.h,
+            },
+            Self::Neg(v) => -v.eval(vars),
+            Self::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(vars), rhs.eval(vars));
+                match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div | Op::Rem if rhs == 0.0 => 0.0,
+                    Op::Div => lhs / rhs,
+                    Op::Rem => lhs % rhs,
+                }
+            }
+            Self::Call(func, args) => match func {
+                Func::Min => args[0].eval(vars).min(args[1].eval(vars)),
+                Func::Max => args[0].eval(vars).max(args[1].eval(vars)),
+                Func::Abs => args[0].eval(vars).abs(),
+            },
+        }
+    }
+}
+
+impl FromStr for AlphaExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            source: s,
+            tokens: &tokens,
+            pos: 0,
+        };
+        let node = parser.expr()?;
+        match parser.peek() {
+            None => Ok(Self(node)),
+            Some(_) => Err(format!("unexpected {} in '{}'", parser.describe_next(), s)),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '0'..='9' | '.' => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = j + c.len_utf8();
+                }
+                let v = s[i..end]
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid number '{}'", &s[i..end]))?;
+                tokens.push(Token::Number(v));
+            }
+            'a'..='z' | 'A'..='Z' => {
+                let mut end = i + 1;
+                while let Some((j, _)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+                    end = j + 1;
+                }
+                tokens.push(Token::Ident(i, end));
+            }
+            '+' | '-' | '*' | '/' | '%' => tokens.push(Token::Op(c)),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            _ => return Err(format!("unexpected '{}' in '{}'", c, s)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent over the grammar:
+/// `expr = term (("+" | "-") term)*`, `term = unary (("*" | "/" | "%") unary)*`,
+/// `unary = "-" unary | primary`, `primary = number | var | func "(" args ")" | "(" expr ")"`.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            None => "end".to_string(),
+            Some(Token::Number(v)) => format!("'{}'", v),
+            Some(Token::Ident(start, end)) => format!("'{}'", &self.source[start..end]),
+            Some(Token::Op(c)) => format!("'{}'", c),
+            Some(Token::Open) => "'('".to_string(),
+            Some(Token::Close) => "')'".to_string(),
+            Some(Token::Comma) => "','".to_string(),
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        if self.peek() != Some(token) {
+            return Err(format!(
+                "unexpected {} in '{}'",
+                self.describe_next(),
+                self.source
+            ));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Node, String> {
+        let mut node = self.term()?;
+        while let Some(Token::Op(c @ ('+' | '-'))) = self.peek() {
+            self.pos += 1;
+            let op = if c == '+' { Op::Add } else { Op::Sub };
+            node = Node::Binary(op, Box::new(node), Box::new(self.term()?));
+        }
+        Ok(node)
+    }
+
+    fn term(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while let Some(Token::Op(c @ ('*' | '/' | '%'))) = self.peek() {
+            self.pos += 1;
+            let op = match c {
+                '*' => Op::Mul,
+                '/' => Op::Div,
+                _ => Op::Rem,
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some(Token::Op('-')) {
+            self.pos += 1;
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let description = self.describe_next();
+        match self.next() {
+            Some(Token::Number(v)) => Ok(Node::Number(v)),
+            Some(Token::Open) => {
+                let node = self.expr()?;
+                self.expect(Token::Close)?;
+                Ok(node)
+            }
+            Some(Token::Ident(start, end)) => {
+                let name = &self.source[start..end];
+                let var = match name {
+                    "a" => Some(Var::A),
+                    "l" => Some(Var::L),
+                    "x" => Some(Var::X),
+                    "y" => Some(Var::Y),
+                    "w" => Some(Var::W),
+                    "h" => Some(Var::H),
+                    _ => None,
+                };
+                if let Some(var) = var {
+                    return Ok(Node::Var(var));
+                }
+                let func = match name {
+                    "min" => Func::Min,
+                    "max" => Func::Max,
+                    "abs" => Func::Abs,
+                    _ => return Err(format!("unknown name '{}' in '{}'", name, self.source)),
+                };
+                self.call(func, name)
+            }
+            _ => Err(format!("unexpected {} in '{}'", description, self.source)),
+        }
+    }
+
+    fn call(&mut self, func: Func, name: &str) -> Result<Node, String> {
+        self.expect(Token::Open)?;
+        let mut args = vec![self.expr()?];
+        while self.peek() == Some(Token::Comma) {
+            self.pos += 1;
+            args.push(self.expr()?);
+        }
+        self.expect(Token::Close)?;
+
+        if args.len() != func.arity() {
+            return Err(format!(
+                "{} takes {} argument(s) in '{}'",
+                name,
+                func.arity(),
+                self.source
+            ));
+        }
+        Ok(Node::Call(func, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, vars: &Vars) -> u8 {
+        s.parse::<AlphaExpr>().unwrap().eval(vars)
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_expr() {
+            let vars = Vars {
+                a: 200.0,
+                l: 100.0,
+                x: 3.0,
+                y: 4.0,
+                w: 10.0,
+                h: 8.0,
+            };
+
+            assert_eq!(eval("a", &vars), 200);
+            assert_eq!(eval("a * l / 255", &vars), 78);
+            assert_eq!(eval("255 - a", &vars), 55);
+            assert_eq!(eval("(x + 1) * 255 / w", &vars), 102);
+            assert_eq!(eval("-y + 2 * 3 % 4", &vars), 0);
+            assert_eq!(eval("min(a, max(l, 150))", &vars), 150);
+            assert_eq!(eval("abs(l - a) + 0.5", &vars), 101);
+        }
+
+        #[test]
+        fn failed_when_invalid_expr() {
+            for s in [
+                "",
+                "a +",
+                "(a",
+                "a)",
+                "b",
+                "min(a)",
+                "abs(a, l)",
+                "a $ l",
+                "1.2.3",
+            ] {
+                assert!(s.parse::<AlphaExpr>().is_err(), "{}", s);
+            }
+        }
+    }
+
+    mod eval {
+        use super::*;
+
+        #[test]
+        fn success_when_out_of_range_or_divided_by_zero() {
+            let vars = Vars::default();
+
+            assert_eq!(eval("1000", &vars), 255);
+            assert_eq!(eval("-1000", &vars), 0);
+            assert_eq!(eval("255 / x", &vars), 0);
+            assert_eq!(eval("255 % w", &vars), 0);
+        }
+    }
+}
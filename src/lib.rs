@@ -0,0 +1,62 @@
+//! Merges PNG and PNA (grayscale image for alpha-channel).
+//!
+//! The command line tool is built on [`run`]; embedders can call [`merge_pna`] directly.
+
+#[cfg(feature = "archive")]
+pub(crate) mod archive;
+pub(crate) mod cache;
+pub(crate) mod checksum;
+pub(crate) mod cli;
+pub(crate) mod config;
+pub(crate) mod config_file;
+pub(crate) mod error;
+pub(crate) mod expr;
+pub(crate) mod log;
+pub(crate) mod mask;
+pub(crate) mod pna;
+pub(crate) mod process;
+pub(crate) mod resize;
+pub(crate) mod retry;
+
+pub use cli::run;
+pub use error::MergeError;
+pub use pna::{AlphaSource, LumaWeights, MergeMode, MergeOptions};
+pub use png;
+
+/// Merges a decoded png and a decoded pna into 8-bit RGBA.
+///
+/// The buffers are the raw frames described by their `Info`, as decoded with
+/// `png::Transformations::IDENTITY`. The pna is used as a grayscale alpha mask
+/// (the alpha channel for a grayscale-alpha pna), and both images must have the same size.
+/// How the mask is taken and merged is set by `options`; `MergeOptions::default()` is
+/// the plain merge of the command line tool.
+pub fn merge_pna(
+    png_buf: &[u8],
+    png_info: &png::Info,
+    pna_buf: &[u8],
+    pna_info: &png::Info,
+    options: &MergeOptions,
+) -> Result<Vec<u8>, MergeError> {
+    pna::merge_pna(png_buf, png_info, pna_buf, pna_info, options)
+}
+
+/// Same as [`merge_pna`] for one row of each image, to merge images too large to hold
+/// whole, row by row as they are decoded with `png::Reader::next_row`.
+///
+/// The `Info`s are those of the whole images, which must have the same width.
+/// Returns the 8-bit RGBA row.
+pub fn merge_pna_row(
+    png_row: &[u8],
+    png_info: &png::Info,
+    pna_row: &[u8],
+    pna_info: &png::Info,
+    options: &MergeOptions,
+) -> Result<Vec<u8>, MergeError> {
+    pna::merge_pna(
+        png_row,
+        &pna::row_info(png_info),
+        pna_row,
+        &pna::row_info(pna_info),
+        options,
+    )
+}
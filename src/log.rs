@@ -0,0 +1,29 @@
+use std::fmt::Display;
+
+/// How much is printed to stderr besides errors, set by `--quiet` and `--verbose`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LogLevel {
+    /// Errors only.
+    Quiet,
+    /// Errors, warnings and the notices of the overwrite prompt.
+    #[default]
+    Normal,
+    /// Also each png as it is merged.
+    Verbose,
+}
+
+impl LogLevel {
+    /// Prints a non-fatal notice, such as a warning, unless `--quiet`.
+    pub(crate) fn notice(self, message: impl Display) {
+        if self >= Self::Normal {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// Prints a detail of the progress with `--verbose`.
+    pub(crate) fn verbose(self, message: impl Display) {
+        if self >= Self::Verbose {
+            eprintln!("{}", message);
+        }
+    }
+}
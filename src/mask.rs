@@ -0,0 +1,1014 @@
+use clap::ValueEnum;
+
+use crate::{
+    expr::{AlphaExpr, Vars},
+    pna::AlphaMask,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum GradientDirection {
+    /// Left to right.
+    Ltr,
+    /// Right to left.
+    Rtl,
+    /// Top to bottom.
+    Ttb,
+    /// Bottom to top.
+    Btt,
+    /// Center to corners.
+    Radial,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Gradient {
+    pub direction: GradientDirection,
+    pub start_alpha: u8,
+    pub end_alpha: u8,
+}
+
+/// Mask of a uniform alpha over the whole image.
+pub(crate) fn solid_alpha_mask(width: u32, height: u32, alpha: u8) -> AlphaMask {
+    AlphaMask {
+        width,
+        height,
+        data: vec![alpha; width as usize * height as usize],
+    }
+}
+
+pub(crate) fn gradient_alpha_mask(width: u32, height: u32, gradient: &Gradient) -> AlphaMask {
+    let position = |v: u32, size: u32| {
+        if size <= 1 {
+            0.0
+        } else {
+            v as f64 / (size - 1) as f64
+        }
+    };
+    let center_x = (width.max(1) - 1) as f64 / 2.0;
+    let center_y = (height.max(1) - 1) as f64 / 2.0;
+    let max_distance = (center_x * center_x + center_y * center_y).sqrt();
+
+    let mut data = Vec::with_capacity(width as usize * height as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let t = match gradient.direction {
+                GradientDirection::Ltr => position(x, width),
+                GradientDirection::Rtl => 1.0 - position(x, width),
+                GradientDirection::Ttb => position(y, height),
+                GradientDirection::Btt => 1.0 - position(y, height),
+                GradientDirection::Radial => {
+                    if max_distance == 0.0 {
+                        0.0
+                    } else {
+                        let dx = x as f64 - center_x;
+                        let dy = y as f64 - center_y;
+                        (dx * dx + dy * dy).sqrt() / max_distance
+                    }
+                }
+            };
+            data.push(lerp_alpha(gradient.start_alpha, gradient.end_alpha, t));
+        }
+    }
+
+    AlphaMask {
+        width,
+        height,
+        data,
+    }
+}
+
+/// Forces the outermost `rings` pixel rings of the mask to fully opaque.
+pub(crate) fn opaque_edges(alpha_mask: &AlphaMask, rings: u32) -> AlphaMask {
+    let (width, height) = (alpha_mask.width as usize, alpha_mask.height as usize);
+    let rings = rings as usize;
+
+    let data = alpha_mask
+        .data
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            let (x, y) = (i % width, i / width);
+            let ring = x.min(y).min(width - 1 - x).min(height - 1 - y);
+            if ring < rings {
+                u8::MAX
+            } else {
+                *a
+            }
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Multiplies a radial falloff into the mask. Pixels inside the inscribed circle are kept,
+/// and alpha fades linearly from its edge to the corners, where it is scaled by `1 - strength`.
+pub(crate) fn vignette(alpha_mask: &AlphaMask, strength: f64) -> AlphaMask {
+    let (width, height) = (alpha_mask.width, alpha_mask.height);
+    let center_x = (width.max(1) - 1) as f64 / 2.0;
+    let center_y = (height.max(1) - 1) as f64 / 2.0;
+    let radius = center_x.min(center_y);
+    let max_distance = (center_x * center_x + center_y * center_y).sqrt();
+
+    let data = alpha_mask
+        .data
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            let dx = (i % width as usize) as f64 - center_x;
+            let dy = (i / width as usize) as f64 - center_y;
+            let distance = (dx * dx + dy * dy).sqrt();
+            let t = if max_distance > radius {
+                ((distance - radius) / (max_distance - radius)).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let factor = 1.0 - strength * t;
+            (*a as f64 * factor).round().clamp(0.0, u8::MAX as f64) as u8
+        })
+        .collect();
+
+    AlphaMask {
+        width,
+        height,
+        data,
+    }
+}
+
+/// Multiplies the luminance of the png into the mask, scaled by `strength`:
+/// alpha is kept where the png is white and scaled by `1 - strength` where it is black.
+pub(crate) fn modulate_by_luma(
+    alpha_mask: &AlphaMask,
+    luma: &AlphaMask,
+    strength: f64,
+) -> AlphaMask {
+    let data = alpha_mask
+        .data
+        .iter()
+        .zip(luma.data.iter())
+        .map(|(a, l)| {
+            let factor = 1.0 - strength + strength * *l as f64 / u8::MAX as f64;
+            (*a as f64 * factor).round().clamp(0.0, u8::MAX as f64) as u8
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Replaces each alpha with `expr` evaluated at the pixel, with the alpha `a`, the
+/// luminance `l` of the png, the coordinates `x`, `y` and the size `w`, `h`.
+pub(crate) fn apply_alpha_expr(
+    alpha_mask: &AlphaMask,
+    luma: &AlphaMask,
+    expr: &AlphaExpr,
+) -> AlphaMask {
+    let width = alpha_mask.width as usize;
+    let data = alpha_mask
+        .data
+        .iter()
+        .zip(luma.data.iter())
+        .enumerate()
+        .map(|(i, (a, l))| {
+            expr.eval(&Vars {
+                a: *a as f64,
+                l: *l as f64,
+                x: (i % width) as f64,
+                y: (i / width) as f64,
+                w: alpha_mask.width as f64,
+                h: alpha_mask.height as f64,
+            })
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Packs the alpha mask into 1 bit per pixel: 1 where alpha >= `threshold`, otherwise 0.
+/// Bits are stored MSB first and each row is padded to a byte boundary, same as 1-bit png.
+pub(crate) fn pack_bitmask(alpha_mask: &AlphaMask, threshold: u8) -> Vec<u8> {
+    let width = alpha_mask.width as usize;
+    if width == 0 {
+        return Vec::new();
+    }
+
+    alpha_mask
+        .data
+        .chunks(width)
+        .flat_map(|row| {
+            row.chunks(8).map(|v| {
+                v.iter().enumerate().fold(0u8, |acc, (i, a)| {
+                    if *a >= threshold {
+                        acc | (1 << (7 - i))
+                    } else {
+                        acc
+                    }
+                })
+            })
+        })
+        .collect()
+}
+
+/// Alpha at or above this is treated as inside the silhouette.
+const SILHOUETTE_THRESHOLD: u8 = 128;
+
+/// Softens the silhouette only outward: the inside becomes fully opaque
+/// and the outside fades out over `radius` pixels from the nearest inside pixel.
+pub(crate) fn edge_feather(alpha_mask: &AlphaMask, radius: u32) -> AlphaMask {
+    let distances = distance_to_silhouette(alpha_mask);
+    let range = radius as f64 + 1.0;
+
+    let data = distances
+        .iter()
+        .map(|d| {
+            let v = (range - d) / range * u8::MAX as f64;
+            v.round().clamp(0.0, u8::MAX as f64) as u8
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Makes the band of silhouette pixels within `width` pixels of its boundary opaque,
+/// and everything else, including the interior, transparent.
+pub(crate) fn edge_mask(alpha_mask: &AlphaMask, width: u32) -> AlphaMask {
+    let to_outside = distance_transform(alpha_mask, |v| v < SILHOUETTE_THRESHOLD);
+
+    let data = to_outside
+        .iter()
+        .map(|d| {
+            if *d > 0.0 && *d <= width as f64 {
+                u8::MAX
+            } else {
+                0
+            }
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Euclidean distance from each pixel to the nearest pixel inside the silhouette.
+/// Inside pixels are 0. When there is no inside pixel, every distance is huge.
+pub(crate) fn distance_to_silhouette(alpha_mask: &AlphaMask) -> Vec<f64> {
+    distance_transform(alpha_mask, |v| v >= SILHOUETTE_THRESHOLD)
+}
+
+/// Thresholds the mask to binary, then anti-aliases only around the resulting boundary.
+/// The transition is `aa` pixels wide on each side; 0 keeps the mask binary.
+pub(crate) fn cutout(alpha_mask: &AlphaMask, threshold: u8, aa: u32) -> AlphaMask {
+    let to_outside = distance_transform(alpha_mask, |v| v < threshold);
+    let to_inside = distance_transform(alpha_mask, |v| v >= threshold);
+
+    let data = alpha_mask
+        .data
+        .iter()
+        .zip(to_outside.iter().zip(to_inside.iter()))
+        .map(|(v, (to_outside, to_inside))| {
+            let inside = *v >= threshold;
+            if aa == 0 {
+                return if inside { u8::MAX } else { 0 };
+            }
+            let signed = if inside {
+                to_outside - 0.5
+            } else {
+                0.5 - to_inside
+            };
+            let coverage = (0.5 + signed / (2.0 * aa as f64)).clamp(0.0, 1.0);
+            (coverage * u8::MAX as f64).round() as u8
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Euclidean distance from each pixel to the nearest pixel matching `target`.
+fn distance_transform<F: Fn(u8) -> bool>(alpha_mask: &AlphaMask, target: F) -> Vec<f64> {
+    let width = alpha_mask.width as usize;
+    let height = alpha_mask.height as usize;
+
+    let mut squared: Vec<f64> = alpha_mask
+        .data
+        .iter()
+        .map(|v| if target(*v) { 0.0 } else { DISTANCE_INF })
+        .collect();
+
+    let mut column = vec![0.0; height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = squared[y * width + x];
+        }
+        let transformed = squared_distance_1d(&column);
+        for y in 0..height {
+            squared[y * width + x] = transformed[y];
+        }
+    }
+
+    for row in squared.chunks_mut(width.max(1)) {
+        let transformed = squared_distance_1d(row);
+        row.copy_from_slice(&transformed);
+    }
+
+    squared.iter().map(|v| v.sqrt()).collect()
+}
+
+const DISTANCE_INF: f64 = 1e20;
+
+/// One dimensional squared distance transform (Felzenszwalb & Huttenlocher).
+fn squared_distance_1d(f: &[f64]) -> Vec<f64> {
+    let n = f.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    let mut result = vec![0.0; n];
+    let mut vertices = vec![0usize; n];
+    let mut boundaries = vec![0.0; n + 1];
+    let mut k = 0;
+    boundaries[0] = f64::NEG_INFINITY;
+    boundaries[1] = f64::INFINITY;
+
+    let parabola = |q: usize| f[q] + (q * q) as f64;
+    let intersection =
+        |q: usize, v: usize| (parabola(q) - parabola(v)) / (2.0 * q as f64 - 2.0 * v as f64);
+    for q in 1..n {
+        let mut s = intersection(q, vertices[k]);
+        // boundaries[0] is -inf and s is always finite, so k never underflows.
+        while s <= boundaries[k] {
+            k -= 1;
+            s = intersection(q, vertices[k]);
+        }
+        k += 1;
+        vertices[k] = q;
+        boundaries[k] = s;
+        boundaries[k + 1] = f64::INFINITY;
+    }
+
+    k = 0;
+    for (q, r) in result.iter_mut().enumerate() {
+        while boundaries[k + 1] < q as f64 {
+            k += 1;
+        }
+        let v = vertices[k];
+        let d = q as f64 - v as f64;
+        *r = d * d + f[v];
+    }
+
+    result
+}
+
+/// Flips each alpha to `255 - v`, for masks where black is opaque.
+pub(crate) fn invert(alpha_mask: &AlphaMask) -> AlphaMask {
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data: alpha_mask.data.iter().map(|v| u8::MAX - v).collect(),
+    }
+}
+
+/// Maps each alpha to the nearest of `levels` evenly spaced values from 0 to 255.
+pub(crate) fn posterize(alpha_mask: &AlphaMask, levels: u16) -> AlphaMask {
+    let steps = (levels.max(2) - 1) as u32;
+    let max = u8::MAX as u32;
+
+    let data = alpha_mask
+        .data
+        .iter()
+        .map(|v| {
+            let level = (*v as u32 * steps + max / 2) / max;
+            ((level * max + steps / 2) / steps) as u8
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Makes the mask binary: alpha at or above `threshold` becomes opaque and the rest transparent.
+pub(crate) fn threshold(alpha_mask: &AlphaMask, threshold: u8) -> AlphaMask {
+    let data = alpha_mask
+        .data
+        .iter()
+        .map(|v| if *v >= threshold { u8::MAX } else { 0 })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Treats mask values as depth and maps them linearly to alpha:
+/// opaque at `near`, transparent at `far`, clamped outside of them.
+pub(crate) fn alpha_from_depth(alpha_mask: &AlphaMask, near: u8, far: u8) -> AlphaMask {
+    let data = alpha_mask
+        .data
+        .iter()
+        .map(|d| {
+            let t = (*d as f64 - near as f64) / (far as f64 - near as f64);
+            lerp_alpha(u8::MAX, 0, t.clamp(0.0, 1.0))
+        })
+        .collect();
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
+    }
+}
+
+/// Remaps alpha so that its histogram follows the one of `reference`.
+pub(crate) fn match_histogram(alpha_mask: &AlphaMask, reference: &AlphaMask) -> AlphaMask {
+    let source_cdf = cumulative_histogram(&alpha_mask.data);
+    let reference_cdf = cumulative_histogram(&reference.data);
+
+    let mut mapping = [0u8; 256];
+    if !reference.data.is_empty() {
+        for (v, m) in mapping.iter_mut().enumerate() {
+            *m = reference_cdf
+                .iter()
+                .position(|r| *r >= source_cdf[v])
+                .unwrap_or(u8::MAX as usize) as u8;
+        }
+    } else {
+        for (v, m) in mapping.iter_mut().enumerate() {
+            *m = v as u8;
+        }
+    }
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data: alpha_mask
+            .data
+            .iter()
+            .map(|v| mapping[*v as usize])
+            .collect(),
+    }
+}
+
+/// Cumulative distribution of alpha values, normalized to 0.0-1.0.
+fn cumulative_histogram(data: &[u8]) -> [f64; 256] {
+    let mut histogram = [0usize; 256];
+    for v in data {
+        histogram[*v as usize] += 1;
+    }
+
+    let mut result = [0.0; 256];
+    let mut sum = 0;
+    for (r, h) in result.iter_mut().zip(histogram.iter()) {
+        sum += h;
+        *r = sum as f64 / data.len().max(1) as f64;
+    }
+
+    result
+}
+
+fn lerp_alpha(start: u8, end: u8, t: f64) -> u8 {
+    let v = start as f64 + (end as f64 - start as f64) * t;
+    v.round().clamp(0.0, u8::MAX as f64) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod pack_bitmask {
+        use super::*;
+
+        #[test]
+        fn success_when_row_is_padded() {
+            let alpha_mask = AlphaMask {
+                width: 10,
+                height: 2,
+                data: vec![
+                    255, 0, 128, 127, 0, 0, 0, 255, 255, 127, //
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 200,
+                ],
+            };
+
+            let result = pack_bitmask(&alpha_mask, 128);
+
+            assert_eq!(result, vec![0b10100001, 0b10000000, 0, 0b01000000]);
+        }
+
+        #[test]
+        fn success_when_round_trip_with_read_byte_depth_1() {
+            let alpha_mask = AlphaMask {
+                width: 8,
+                height: 1,
+                data: vec![0, 255, 255, 0, 255, 0, 0, 255],
+            };
+
+            let result = pack_bitmask(&alpha_mask, 1);
+
+            assert_eq!(
+                crate::pna::read_byte_depth_1(&result[0]),
+                [0, 1, 1, 0, 1, 0, 0, 1]
+            );
+        }
+    }
+
+    mod distance_to_silhouette {
+        use super::*;
+
+        #[test]
+        fn success_when_single_inside_pixel() {
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 3,
+                data: vec![0, 0, 0, 0, 255, 0, 0, 0, 0],
+            };
+
+            let result = distance_to_silhouette(&alpha_mask);
+
+            let diagonal = 2.0f64.sqrt();
+            assert_eq!(
+                result,
+                vec![diagonal, 1.0, diagonal, 1.0, 0.0, 1.0, diagonal, 1.0, diagonal]
+            );
+        }
+
+        #[test]
+        fn success_when_inside_is_in_a_row() {
+            let alpha_mask = AlphaMask {
+                width: 5,
+                height: 1,
+                data: vec![255, 0, 0, 0, 200],
+            };
+
+            let result = distance_to_silhouette(&alpha_mask);
+
+            assert_eq!(result, vec![0.0, 1.0, 2.0, 1.0, 0.0]);
+        }
+    }
+
+    mod cutout {
+        use super::*;
+
+        #[test]
+        fn success_when_anti_aliased() {
+            let alpha_mask = AlphaMask {
+                width: 6,
+                height: 1,
+                data: vec![250, 240, 200, 30, 10, 0],
+            };
+
+            let result = cutout(&alpha_mask, 128, 1);
+
+            assert_eq!(result.data, vec![255, 255, 191, 64, 0, 0]);
+        }
+
+        #[test]
+        fn success_when_no_anti_aliasing() {
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![127, 128, 0, 255],
+            };
+
+            let result = cutout(&alpha_mask, 128, 0);
+
+            assert_eq!(result.data, vec![0, 255, 0, 255]);
+        }
+    }
+
+    mod edge_feather {
+        use super::*;
+
+        #[test]
+        fn success_when_radius_is_two() {
+            let alpha_mask = AlphaMask {
+                width: 5,
+                height: 1,
+                data: vec![200, 100, 0, 0, 0],
+            };
+
+            let result = edge_feather(&alpha_mask, 2);
+
+            assert_eq!(result.data, vec![255, 170, 85, 0, 0]);
+        }
+
+        #[test]
+        fn success_when_no_inside_pixel() {
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![0, 127],
+            };
+
+            let result = edge_feather(&alpha_mask, 4);
+
+            assert_eq!(result.data, vec![0, 0]);
+        }
+    }
+
+    mod invert {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_mask() {
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 2,
+                data: vec![0, 1, 128, 255],
+            };
+
+            let result = invert(&alpha_mask);
+
+            assert_eq!(result.width, 2);
+            assert_eq!(result.height, 2);
+            assert_eq!(result.data, vec![255, 254, 127, 0]);
+        }
+    }
+
+    mod posterize {
+        use super::*;
+
+        #[test]
+        fn success_when_four_levels() {
+            let alpha_mask = AlphaMask {
+                width: 6,
+                height: 1,
+                data: vec![0, 42, 43, 127, 128, 255],
+            };
+
+            let result = posterize(&alpha_mask, 4);
+
+            assert_eq!(result.data, vec![0, 0, 85, 85, 170, 255]);
+        }
+
+        #[test]
+        fn success_when_two_levels() {
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 1,
+                data: vec![127, 128, 255],
+            };
+
+            let result = posterize(&alpha_mask, 2);
+
+            assert_eq!(result.data, vec![0, 255, 255]);
+        }
+
+        #[test]
+        fn success_when_256_levels_is_identity() {
+            let alpha_mask = AlphaMask {
+                width: 256,
+                height: 1,
+                data: (0..=u8::MAX).collect(),
+            };
+
+            let result = posterize(&alpha_mask, 256);
+
+            assert_eq!(result, alpha_mask);
+        }
+    }
+
+    mod threshold {
+        use super::*;
+
+        #[test]
+        fn success_when_threshold_is_128() {
+            let alpha_mask = AlphaMask {
+                width: 5,
+                height: 1,
+                data: vec![0, 127, 128, 200, 255],
+            };
+
+            let result = threshold(&alpha_mask, 128);
+
+            assert_eq!(result.data, vec![0, 0, 255, 255, 255]);
+        }
+
+        #[test]
+        fn success_when_threshold_is_0() {
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![0, 1],
+            };
+
+            let result = threshold(&alpha_mask, 0);
+
+            assert_eq!(result.data, vec![255, 255]);
+        }
+    }
+
+    mod alpha_from_depth {
+        use super::*;
+
+        #[test]
+        fn success_when_near_is_smaller() {
+            let alpha_mask = AlphaMask {
+                width: 5,
+                height: 1,
+                data: vec![0, 50, 100, 150, 200],
+            };
+
+            let result = alpha_from_depth(&alpha_mask, 50, 150);
+
+            assert_eq!(result.data, vec![u8::MAX, u8::MAX, 128, 0, 0]);
+        }
+
+        #[test]
+        fn success_when_near_is_larger() {
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 1,
+                data: vec![0, 128, u8::MAX],
+            };
+
+            let result = alpha_from_depth(&alpha_mask, u8::MAX, 0);
+
+            assert_eq!(result.data, vec![0, 128, u8::MAX]);
+        }
+    }
+
+    mod match_histogram {
+        use super::*;
+
+        #[test]
+        fn success_when_levels_are_remapped() {
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![0, 0, 10, 20],
+            };
+            let reference = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![100, 100, 200, 255],
+            };
+
+            let result = match_histogram(&alpha_mask, &reference);
+
+            assert_eq!(result.data, vec![100, 100, 200, 255]);
+        }
+
+        #[test]
+        fn success_when_same_histogram_is_identity() {
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 1,
+                data: vec![30, 0, 255],
+            };
+
+            let result = match_histogram(&alpha_mask, &alpha_mask);
+
+            assert_eq!(result, alpha_mask);
+        }
+    }
+
+    mod edge_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_interior_is_cleared() {
+            let mut alpha_mask = solid_alpha_mask(5, 5, 0);
+            for y in 0..5 {
+                for x in 0..5 {
+                    if (0 < x && x < 4) && (0 < y && y < 4) {
+                        alpha_mask.data[y * 5 + x] = u8::MAX;
+                    }
+                }
+            }
+
+            let result = edge_mask(&alpha_mask, 1);
+
+            #[rustfmt::skip]
+            assert_eq!(
+                result.data,
+                vec![
+                    0, 0, 0, 0, 0,
+                    0, 255, 255, 255, 0,
+                    0, 255, 0, 255, 0,
+                    0, 255, 255, 255, 0,
+                    0, 0, 0, 0, 0,
+                ]
+            );
+        }
+
+        #[test]
+        fn success_when_no_silhouette() {
+            let alpha_mask = solid_alpha_mask(3, 3, 0);
+
+            assert_eq!(edge_mask(&alpha_mask, 2), alpha_mask);
+        }
+    }
+
+    mod opaque_edges {
+        use super::*;
+
+        #[test]
+        fn success_when_one_ring() {
+            let alpha_mask = solid_alpha_mask(4, 3, 0);
+
+            let result = opaque_edges(&alpha_mask, 1);
+
+            #[rustfmt::skip]
+            assert_eq!(
+                result.data,
+                vec![
+                    255, 255, 255, 255,
+                    255, 0, 0, 255,
+                    255, 255, 255, 255,
+                ]
+            );
+        }
+
+        #[test]
+        fn success_when_rings_exceed_size() {
+            let alpha_mask = solid_alpha_mask(3, 3, 10);
+
+            assert_eq!(opaque_edges(&alpha_mask, 5).data, vec![u8::MAX; 9]);
+            assert_eq!(opaque_edges(&alpha_mask, 0), alpha_mask);
+        }
+    }
+
+    mod apply_alpha_expr {
+        use super::*;
+
+        #[test]
+        fn success_when_expr_uses_every_var() {
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 2,
+                data: vec![255, 100, 200, 0],
+            };
+            let luma = AlphaMask {
+                width: 2,
+                height: 2,
+                data: vec![0, 255, 51, 255],
+            };
+            let expr = "a * l / 255 + (x + y * w) * 10 / h".parse().unwrap();
+
+            let result = apply_alpha_expr(&alpha_mask, &luma, &expr);
+
+            assert_eq!(result.data, vec![0, 105, 50, 15]);
+        }
+    }
+
+    mod modulate_by_luma {
+        use super::*;
+
+        #[test]
+        fn success_when_half_strength() {
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![255, 255, 200, 0],
+            };
+            let luma = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![255, 0, 51, 255],
+            };
+
+            let result = modulate_by_luma(&alpha_mask, &luma, 0.5);
+
+            assert_eq!(result.data, vec![255, 128, 120, 0]);
+        }
+
+        #[test]
+        fn success_when_zero_strength() {
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![10, 200],
+            };
+            let luma = AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![0, 0],
+            };
+
+            assert_eq!(modulate_by_luma(&alpha_mask, &luma, 0.0), alpha_mask);
+        }
+    }
+
+    mod vignette {
+        use super::*;
+
+        #[test]
+        fn success_when_corners_are_faded() {
+            let alpha_mask = solid_alpha_mask(5, 5, u8::MAX);
+
+            let result = vignette(&alpha_mask, 1.0);
+
+            assert_eq!(result.data[0], 0);
+            assert_eq!(result.data[4], 0);
+            assert_eq!(result.data[12], u8::MAX);
+            assert_eq!(result.data[2], u8::MAX);
+            assert!(result.data[1] > 0 && result.data[1] < u8::MAX);
+        }
+
+        #[test]
+        fn success_when_strength_is_zero() {
+            let alpha_mask = solid_alpha_mask(4, 3, 100);
+
+            assert_eq!(vignette(&alpha_mask, 0.0), alpha_mask);
+        }
+    }
+
+    mod solid_alpha_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_size() {
+            let result = solid_alpha_mask(3, 2, 77);
+
+            assert_eq!(
+                result,
+                AlphaMask {
+                    width: 3,
+                    height: 2,
+                    data: vec![77; 6],
+                }
+            );
+        }
+    }
+
+    mod gradient_alpha_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_ltr() {
+            let gradient = Gradient {
+                direction: GradientDirection::Ltr,
+                start_alpha: 0,
+                end_alpha: u8::MAX,
+            };
+
+            let result = gradient_alpha_mask(3, 2, &gradient);
+
+            assert_eq!(result.data, vec![0, 128, 255, 0, 128, 255]);
+        }
+
+        #[test]
+        fn success_when_btt() {
+            let gradient = Gradient {
+                direction: GradientDirection::Btt,
+                start_alpha: u8::MAX,
+                end_alpha: 0,
+            };
+
+            let result = gradient_alpha_mask(1, 2, &gradient);
+
+            assert_eq!(result.data, vec![0, 255]);
+        }
+
+        #[test]
+        fn success_when_radial() {
+            let gradient = Gradient {
+                direction: GradientDirection::Radial,
+                start_alpha: u8::MAX,
+                end_alpha: 0,
+            };
+
+            let result = gradient_alpha_mask(3, 3, &gradient);
+
+            assert_eq!(result.data[4], 255);
+            assert_eq!(result.data[0], 0);
+            assert_eq!(result.data[1], 75);
+        }
+
+        #[test]
+        fn success_when_single_pixel() {
+            let gradient = Gradient {
+                direction: GradientDirection::Radial,
+                start_alpha: 10,
+                end_alpha: 0,
+            };
+
+            let result = gradient_alpha_mask(1, 1, &gradient);
+
+            assert_eq!(result.data, vec![10]);
+        }
+    }
+}
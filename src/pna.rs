@@ -1,107 +1,673 @@
-use std::{borrow::Cow, u8};
+use std::collections::{BTreeSet, HashMap};
 
+use clap::ValueEnum;
 use png::{BitDepth, ColorType, Info};
 
-use crate::error::MergeError;
+use crate::{
+    error::MergeError,
+    mask::{invert, threshold},
+};
+
+/// Which channel of a `GrayscaleAlpha` pna is used as the alpha mask.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AlphaSource {
+    /// Use the alpha channel.
+    #[default]
+    Alpha,
+    /// Use the gray value.
+    Gray,
+}
+
+/// How the color of a pna without an alpha channel is reduced to the mask.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LumaWeights {
+    /// ITU-R BT.601 luma: 0.299 r + 0.587 g + 0.114 b.
+    #[default]
+    Bt601,
+    /// ITU-R BT.709 luma: 0.2126 r + 0.7152 g + 0.0722 b.
+    Bt709,
+    /// Flat average of r, g and b, as in earlier versions.
+    Average,
+}
+
+impl LumaWeights {
+    /// Weighted sum in fixed-point, so the result is the same on every platform.
+    /// Gray stays as it is, since the weights of each mode add up to the divisor.
+    fn luma(self, r: u8, g: u8, b: u8) -> u8 {
+        let (weights, divisor) = match self {
+            Self::Bt601 => ([299, 587, 114], 1000),
+            Self::Bt709 => ([2126, 7152, 722], 10000),
+            Self::Average => return ((r as u16 + g as u16 + b as u16) / 3) as u8,
+        };
+        let sum = r as u32 * weights[0] + g as u32 * weights[1] + b as u32 * weights[2];
+
+        ((sum + divisor / 2) / divisor) as u8
+    }
+}
+
+/// How the mask is combined with the alpha the png already has.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum MergeMode {
+    /// The mask replaces the alpha channel of the png.
+    #[default]
+    Replace,
+    /// The mask is multiplied with the alpha of the png.
+    Multiply,
+}
+
+/// Channel of the output pixel.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Channel {
+    R,
+    G,
+    B,
+    #[default]
+    A,
+}
+
+/// Where the mask is written in the output pixel, and what fills the other channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Packing {
+    pub channel: Channel,
+    /// Value of the other channels. `None` keeps the channels of the png, with its own
+    /// alpha (or tRNS) in the alpha channel when the mask goes elsewhere.
+    pub fill: Option<u8>,
+}
+
+impl Packing {
+    /// Returns the pixel with `mask` in the packed channel.
+    fn pack<T: Copy>(&self, rgba: &[T], mask: T, fill: Option<T>) -> [T; 4] {
+        let mut pixel = match fill {
+            Some(v) => [v; 4],
+            None => [rgba[0], rgba[1], rgba[2], rgba[3]],
+        };
+        pixel[self.channel as usize] = mask;
+        pixel
+    }
+}
+
+/// How [`merge_pna`](crate::merge_pna) turns the pna into the alpha of the png.
+///
+/// The default is the plain merge: the alpha channel of the pna (or its BT.601 luma)
+/// replaces the alpha of the png. Set only the fields that differ with
+/// `..Default::default()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MergeOptions {
+    /// Which channel of a grayscale-alpha pna is the mask.
+    pub alpha_source: AlphaSource,
+    /// How a pna without an alpha channel is reduced to gray.
+    pub luma_weights: LumaWeights,
+    /// Flips the mask to `255 - v`, for masks where black is opaque.
+    pub invert: bool,
+    /// Makes the mask binary: opaque at or above the value, transparent below.
+    pub threshold: Option<u8>,
+    /// Whether the mask replaces or is multiplied with the alpha of the png.
+    pub merge_mode: MergeMode,
+    /// Pixels whose r, g and b are all at or below the value become fully transparent.
+    pub black_tolerance: Option<u8>,
+    /// Colors of the png are premultiplied by its own alpha, and divided by it first.
+    pub premultiplied: bool,
+    /// Pixels whose mask is exactly the value keep the alpha of the png.
+    pub mask_passthrough_value: Option<u8>,
+    /// Fails with `MergeError::DataSizeMismatch` when a buffer holds more data than its
+    /// `Info` describes, instead of ignoring the rest.
+    pub strict_data_size: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct AlphaMask {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
 
 pub(crate) fn merge_pna(
     png_buf: &[u8],
     png_info: &Info,
     pna_buf: &[u8],
     pna_info: &Info,
+    options: &MergeOptions,
 ) -> Result<Vec<u8>, MergeError> {
-    if png_info.width != pna_info.width || png_info.height != pna_info.height {
-        return Err(MergeError::SizePngAndPnaAreDifferent);
+    let mut alpha_mask = pna_to_alpha_mask(
+        pna_buf,
+        pna_info,
+        options.alpha_source,
+        options.luma_weights,
+        options.strict_data_size,
+    )?;
+    if options.invert {
+        alpha_mask = invert(&alpha_mask);
+    }
+    if let Some(v) = options.threshold {
+        alpha_mask = threshold(&alpha_mask, v);
     }
 
-    let pixel_size = (png_info.width * png_info.height) as usize;
+    merge_alpha_mask(png_buf, png_info, &alpha_mask, options, Packing::default())
+}
+
+/// `info` for a single row of its image, so the conversions can work a row at a time.
+pub(crate) fn row_info<'a>(info: &Info<'a>) -> Info<'a> {
+    let mut row_info = info.clone();
+    row_info.height = 1;
+    row_info.interlaced = false;
+    row_info
+}
+
+pub(crate) fn pna_to_alpha_mask(
+    pna_buf: &[u8],
+    pna_info: &Info,
+    alpha_source: AlphaSource,
+    luma_weights: LumaWeights,
+    strict: bool,
+) -> Result<AlphaMask, MergeError> {
+    let pixel_size = sample_count(pna_info.width, pna_info.height, 1)?;
+
+    let mut data = buf_to_alpha_mask(pna_buf, pna_info, alpha_source, luma_weights)?;
+    adjust_length(&mut data, pixel_size, strict)?;
+
+    Ok(AlphaMask {
+        width: pna_info.width,
+        height: pna_info.height,
+        data,
+    })
+}
+
+/// With `MergeMode::Replace`, the mask replaces the alpha channel of the png. For color
+/// types without one, it is multiplied with the transparency of the tRNS chunk instead,
+/// so that is kept. With `MergeMode::Multiply`, it is multiplied with the alpha of the png
+/// for every color type.
+/// Of `options`, only the fields about the merge itself are used; the mask is taken as
+/// it is. With `black_tolerance`, near-black pixels become fully transparent regardless
+/// of the mask, and with `mask_passthrough_value`, pixels whose mask is exactly that
+/// value keep the alpha of the png (or of its tRNS) unchanged.
+/// `packing`: the channel the resulting alpha is written to, by default the alpha channel.
+pub(crate) fn merge_alpha_mask(
+    png_buf: &[u8],
+    png_info: &Info,
+    alpha_mask: &AlphaMask,
+    options: &MergeOptions,
+    packing: Packing,
+) -> Result<Vec<u8>, MergeError> {
+    check_size(png_info, alpha_mask)?;
+
+    let sample_size = sample_count(png_info.width, png_info.height, 4)?;
+    let replaces_alpha = replaces_alpha(png_info.color_type, options.merge_mode);
 
     let mut png_rgba = buf_to_rgba(png_buf, png_info)?;
-    adjust_length(&mut png_rgba, pixel_size * 4)?;
+    adjust_length(&mut png_rgba, sample_size, options.strict_data_size)?;
+    if options.premultiplied {
+        unpremultiply(&mut png_rgba);
+    }
+
+    Ok(png_rgba
+        .chunks_exact(4)
+        .zip(alpha_mask.data.iter())
+        .flat_map(|(rgba, alpha)| {
+            let alpha = match options.black_tolerance {
+                Some(t) if rgba[0] <= t && rgba[1] <= t && rgba[2] <= t => 0,
+                _ if options.mask_passthrough_value == Some(*alpha) => rgba[3],
+                _ if replaces_alpha => *alpha,
+                _ => ((rgba[3] as u32 * *alpha as u32 + 127) / u8::MAX as u32) as u8,
+            };
+            packing.pack(rgba, alpha, packing.fill)
+        })
+        .collect())
+}
+
+/// Number of values of an image of `channels` values per pixel. Fails rather than
+/// wrapping around when the count of a huge (or crafted) size doesn't fit in `usize`.
+pub(crate) fn sample_count(width: u32, height: u32, channels: usize) -> Result<usize, MergeError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|v| v.checked_mul(channels))
+        .ok_or(MergeError::ImageTooLarge { width, height })
+}
+
+/// Fails unless the mask, which has the size of the pna it came from, fits the png.
+pub(crate) fn check_size(png_info: &Info, alpha_mask: &AlphaMask) -> Result<(), MergeError> {
+    let png = (png_info.width, png_info.height);
+    let pna = (alpha_mask.width, alpha_mask.height);
+    if png != pna {
+        return Err(MergeError::SizePngAndPnaAreDifferent { png, pna });
+    }
+
+    Ok(())
+}
+
+/// Same as `merge_alpha_mask` keeping 16 bits per channel. The 8-bit mask is scaled
+/// up to 16 bits. Returns big-endian samples, the layout of a 16-bit png.
+pub(crate) fn merge_alpha_mask_16(
+    png_buf: &[u8],
+    png_info: &Info,
+    alpha_mask: &AlphaMask,
+    options: &MergeOptions,
+    packing: Packing,
+) -> Result<Vec<u8>, MergeError> {
+    check_size(png_info, alpha_mask)?;
 
-    let mut pna_alpha_mask = buf_to_alpha_mask(pna_buf, pna_info)?;
-    adjust_length(&mut pna_alpha_mask, pixel_size)?;
+    let sample_size = sample_count(png_info.width, png_info.height, 4)?;
+    let replaces_alpha = replaces_alpha(png_info.color_type, options.merge_mode);
+
+    let mut png_rgba = buf_to_rgba_16(png_buf, png_info)?;
+    adjust_length(&mut png_rgba, sample_size, options.strict_data_size)?;
+    if options.premultiplied {
+        unpremultiply_16(&mut png_rgba);
+    }
 
     Ok(png_rgba
         .chunks_exact(4)
-        .zip(pna_alpha_mask.iter())
-        .flat_map(|v| [v.0[0], v.0[1], v.0[2], *v.1])
+        .zip(alpha_mask.data.iter())
+        .flat_map(|(rgba, alpha)| {
+            let alpha = match options.black_tolerance {
+                Some(t) if rgba[..3].iter().all(|v| round_to_8_bit(*v) <= t) => 0,
+                _ if options.mask_passthrough_value == Some(*alpha) => rgba[3],
+                _ if replaces_alpha => *alpha as u16 * 257,
+                _ => ((rgba[3] as u32 * *alpha as u32 * 257 + 32767) / u16::MAX as u32) as u16,
+            };
+            packing.pack(rgba, alpha, packing.fill.map(|v| v as u16 * 257))
+        })
+        .flat_map(u16::to_be_bytes)
         .collect())
 }
 
-fn adjust_length(buf: &mut Vec<u8>, size: usize) -> Result<(), MergeError> {
-    if buf.len() < size {
-        Err(MergeError::LessDataSize)
+/// Whether the mask is taken as the alpha as it is, rather than multiplied with the
+/// png's own. A png without an alpha channel only has the transparency of tRNS, which
+/// is always kept.
+fn replaces_alpha(color_type: ColorType, merge_mode: MergeMode) -> bool {
+    merge_mode == MergeMode::Replace
+        && matches!(color_type, ColorType::GrayscaleAlpha | ColorType::Rgba)
+}
+
+/// Mask from the png's own alpha channel, or from its luminance when it has none.
+pub(crate) fn own_alpha_mask(
+    png_buf: &[u8],
+    png_info: &Info,
+    luma_weights: LumaWeights,
+    strict: bool,
+) -> Result<AlphaMask, MergeError> {
+    match png_info.color_type {
+        ColorType::GrayscaleAlpha | ColorType::Rgba => {
+            let pixel_size = sample_count(png_info.width, png_info.height, 1)?;
+            let mut data: Vec<u8> = buf_to_rgba(png_buf, png_info)?
+                .chunks_exact(4)
+                .map(|v| v[3])
+                .collect();
+            adjust_length(&mut data, pixel_size, strict)?;
+
+            Ok(AlphaMask {
+                width: png_info.width,
+                height: png_info.height,
+                data,
+            })
+        }
+        _ => pna_to_alpha_mask(png_buf, png_info, AlphaSource::Gray, luma_weights, strict),
+    }
+}
+
+/// Mask from the color distance between the png and a background plate of the same
+/// size: transparent where they match, rising in proportion to the euclidean rgb
+/// distance until it is opaque at `threshold`.
+pub(crate) fn difference_mask(
+    png_buf: &[u8],
+    png_info: &Info,
+    plate_buf: &[u8],
+    plate_info: &Info,
+    threshold: f64,
+    strict: bool,
+) -> Result<AlphaMask, MergeError> {
+    let png = (png_info.width, png_info.height);
+    let pna = (plate_info.width, plate_info.height);
+    if png != pna {
+        return Err(MergeError::SizePngAndPnaAreDifferent { png, pna });
+    }
+
+    let sample_size = sample_count(png_info.width, png_info.height, 4)?;
+    let mut png_rgba = buf_to_rgba(png_buf, png_info)?;
+    adjust_length(&mut png_rgba, sample_size, strict)?;
+    let mut plate_rgba = buf_to_rgba(plate_buf, plate_info)?;
+    adjust_length(&mut plate_rgba, sample_size, strict)?;
+
+    let data = png_rgba
+        .chunks_exact(4)
+        .zip(plate_rgba.chunks_exact(4))
+        .map(|(a, b)| {
+            let distance = a[..3]
+                .iter()
+                .zip(&b[..3])
+                .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+                .sum::<f64>()
+                .sqrt();
+            (distance / threshold * u8::MAX as f64)
+                .round()
+                .min(u8::MAX as f64) as u8
+        })
+        .collect();
+
+    Ok(AlphaMask {
+        width: png_info.width,
+        height: png_info.height,
+        data,
+    })
+}
+
+/// For an indexed png, makes the mask fully opaque where `is_masked` is false for the
+/// pixel's palette index. Masks of other color types are returned unchanged.
+pub(crate) fn restrict_mask_to_indices(
+    png_buf: &[u8],
+    png_info: &Info,
+    alpha_mask: &AlphaMask,
+    is_masked: impl Fn(usize) -> bool,
+) -> AlphaMask {
+    let data = if png_info.color_type == ColorType::Indexed {
+        read_samples(png_buf, png_info, read_bytes_for_usize)
+            .iter()
+            .zip(alpha_mask.data.iter())
+            .map(|(index, alpha)| if is_masked(*index) { *alpha } else { u8::MAX })
+            .collect()
     } else {
-        buf.resize(size, 0);
-        Ok(())
+        alpha_mask.data.clone()
+    };
+
+    AlphaMask {
+        width: alpha_mask.width,
+        height: alpha_mask.height,
+        data,
     }
 }
 
-fn buf_to_rgba(buf: &[u8], info: &Info) -> Result<Vec<u8>, MergeError> {
-    let bytes = match info.color_type {
-        ColorType::Indexed => {
-            return buf_to_rgba_from_indexed(buf, &info.bit_depth, info.palette.as_ref())
+/// Returns true when the color type is grayscale, or when every pixel has r == g == b.
+pub(crate) fn is_effectively_grayscale(buf: &[u8], info: &Info) -> Result<bool, MergeError> {
+    match info.color_type {
+        ColorType::Grayscale | ColorType::GrayscaleAlpha => Ok(true),
+        _ => Ok(buf_to_rgba(buf, info)?
+            .chunks_exact(4)
+            .all(|v| v[0] == v[1] && v[1] == v[2])),
+    }
+}
+
+/// Alpha values at or below this are not counted as covered.
+const COVERAGE_EPSILON: u8 = 2;
+
+/// Returns percentage of pixels whose alpha is above `COVERAGE_EPSILON`.
+pub(crate) fn alpha_coverage(alpha_mask: &AlphaMask) -> f64 {
+    if alpha_mask.data.is_empty() {
+        return 0.0;
+    }
+
+    let covered = alpha_mask
+        .data
+        .iter()
+        .filter(|v| **v > COVERAGE_EPSILON)
+        .count();
+
+    covered as f64 * 100.0 / alpha_mask.data.len() as f64
+}
+
+/// Splits 8-bit rgba into rgb and its alpha. The colors are composited onto
+/// `background` when given, and kept as they are otherwise.
+pub(crate) fn split_rgba(rgba: &[u8], background: Option<[u8; 3]>) -> (Vec<u8>, Vec<u8>) {
+    let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+    let mut alpha = Vec::with_capacity(rgba.len() / 4);
+
+    for v in rgba.chunks_exact(4) {
+        let a = v[3] as u32;
+        match background {
+            Some(background) => rgb.extend(v[..3].iter().zip(background).map(|(c, b)| {
+                ((*c as u32 * a + b as u32 * (u8::MAX as u32 - a) + 127) / u8::MAX as u32) as u8
+            })),
+            None => rgb.extend_from_slice(&v[..3]),
+        }
+        alpha.push(v[3]);
+    }
+
+    (rgb, alpha)
+}
+
+/// Converts premultiplied rgba to straight alpha in place.
+/// Fully transparent pixels are left as they are.
+fn unpremultiply(rgba: &mut [u8]) {
+    for v in rgba.chunks_exact_mut(4) {
+        let a = v[3] as u32;
+        if a == 0 || a == u8::MAX as u32 {
+            continue;
+        }
+        for c in &mut v[..3] {
+            *c = ((*c as u32 * u8::MAX as u32 + a / 2) / a).min(u8::MAX as u32) as u8;
+        }
+    }
+}
+
+/// Same as `unpremultiply` for 16-bit rgba.
+fn unpremultiply_16(rgba: &mut [u16]) {
+    for v in rgba.chunks_exact_mut(4) {
+        let a = v[3] as u32;
+        if a == 0 || a == u16::MAX as u32 {
+            continue;
+        }
+        for c in &mut v[..3] {
+            *c = ((*c as u32 * u16::MAX as u32 + a / 2) / a).min(u16::MAX as u32) as u16;
+        }
+    }
+}
+
+/// Cuts the converted buffer to `size` values. A shorter buffer fails with `LessDataSize`;
+/// a longer one is cut, unless `strict`, when it fails with `DataSizeMismatch` so that
+/// data left over by a malformed image is not dropped unnoticed.
+fn adjust_length<T>(buf: &mut Vec<T>, size: usize, strict: bool) -> Result<(), MergeError> {
+    if buf.len() < size {
+        return Err(MergeError::LessDataSize);
+    }
+    if strict && buf.len() != size {
+        return Err(MergeError::DataSizeMismatch {
+            expected: size,
+            actual: buf.len(),
+        });
+    }
+
+    buf.truncate(size);
+    Ok(())
+}
+
+/// Converts the decoded frame to 8-bit rgba. For color types without an alpha channel,
+/// the alpha comes from the tRNS chunk when there is one, and is opaque otherwise.
+pub(crate) fn buf_to_rgba(buf: &[u8], info: &Info) -> Result<Vec<u8>, MergeError> {
+    let mut rgba = buf_to_rgba_ignoring_trns(buf, info)?;
+    if let Some(alpha) = trns_alpha(buf, info) {
+        for (pixel, a) in rgba.chunks_exact_mut(4).zip(alpha) {
+            pixel[3] = a;
         }
-        _ => read_bytes_for_bit_depth_8(buf, &info.bit_depth),
+    }
+
+    Ok(rgba)
+}
+
+fn buf_to_rgba_ignoring_trns(buf: &[u8], info: &Info) -> Result<Vec<u8>, MergeError> {
+    let bytes = match info.color_type {
+        ColorType::Indexed => return buf_to_rgba_from_indexed(buf, info),
+        _ if info.bit_depth == BitDepth::Sixteen => whole_pixels(buf, 2)?
+            .map(|v| round_to_8_bit(u16::from_be_bytes([v[0], v[1]])))
+            .collect(),
+        _ => read_samples(buf, info, read_bytes_for_bit_depth_8),
     };
 
     match info.color_type {
         ColorType::Grayscale => Ok(bytes.iter().flat_map(|v| [*v, *v, *v, u8::MAX]).collect()),
-        ColorType::GrayscaleAlpha => Ok(bytes
-            .chunks_exact(2)
+        ColorType::GrayscaleAlpha => Ok(whole_pixels(&bytes, 2)?
             .flat_map(|v| [v[0], v[0], v[0], v[1]])
             .collect()),
-        ColorType::Rgb => Ok(bytes
-            .chunks_exact(3)
+        ColorType::Rgb => Ok(whole_pixels(&bytes, 3)?
             .flat_map(|v| [v[0], v[1], v[2], u8::MAX])
             .collect()),
-        ColorType::Rgba => Ok(bytes
-            .chunks_exact(4)
+        ColorType::Rgba => Ok(whole_pixels(&bytes, 4)?
             .flat_map(|v| [v[0], v[1], v[2], v[3]])
             .collect()),
         ColorType::Indexed => unreachable!("early returned."),
     }
 }
 
-fn buf_to_rgba_from_indexed(
-    buf: &[u8],
-    bit_depth: &BitDepth,
-    palette_raw: Option<&Cow<[u8]>>,
-) -> Result<Vec<u8>, MergeError> {
-    let pallete = match palette_raw {
+/// Same as `buf_to_rgba` with 16 bits per channel. Shallower samples are scaled up,
+/// so that 255 becomes 65535.
+pub(crate) fn buf_to_rgba_16(buf: &[u8], info: &Info) -> Result<Vec<u16>, MergeError> {
+    if info.bit_depth != BitDepth::Sixteen || info.color_type == ColorType::Indexed {
+        return Ok(buf_to_rgba(buf, info)?
+            .iter()
+            .map(|v| *v as u16 * 257)
+            .collect());
+    }
+
+    let samples: Vec<u16> = whole_pixels(buf, 2)?
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .collect();
+    let mut rgba: Vec<u16> = match info.color_type {
+        ColorType::Grayscale => samples
+            .iter()
+            .flat_map(|v| [*v, *v, *v, u16::MAX])
+            .collect(),
+        ColorType::GrayscaleAlpha => whole_pixels(&samples, 2)?
+            .flat_map(|v| [v[0], v[0], v[0], v[1]])
+            .collect(),
+        ColorType::Rgb => whole_pixels(&samples, 3)?
+            .flat_map(|v| [v[0], v[1], v[2], u16::MAX])
+            .collect(),
+        ColorType::Rgba => whole_pixels(&samples, 4)?.flatten().copied().collect(),
+        ColorType::Indexed => unreachable!("early returned."),
+    };
+    if let Some(alpha) = trns_alpha(buf, info) {
+        for (pixel, a) in rgba.chunks_exact_mut(4).zip(alpha) {
+            pixel[3] = a as u16 * 257;
+        }
+    }
+
+    Ok(rgba)
+}
+
+/// Splits the samples into pixels of `size` values (or a 16-bit sample into its two
+/// bytes). Fails on a partial pixel at the end, which `chunks_exact` would drop.
+fn whole_pixels<T>(
+    samples: &[T],
+    size: usize,
+) -> Result<std::slice::ChunksExact<'_, T>, MergeError> {
+    let pixels = samples.chunks_exact(size);
+    match pixels.remainder().len() {
+        0 => Ok(pixels),
+        leftover => Err(MergeError::PartialPixel { size, leftover }),
+    }
+}
+
+/// Alpha of each pixel given by the tRNS chunk: the entry of the palette index for
+/// indexed pngs (opaque past the end of the chunk), and transparent only where the
+/// sample equals the key for grayscale and rgb pngs. The key is compared with the raw
+/// samples, before scaling to 8 bits. `None` when there is no tRNS chunk to honor.
+fn trns_alpha(buf: &[u8], info: &Info) -> Option<Vec<u8>> {
+    let trns = info.trns.as_ref()?;
+    let samples = read_samples(buf, info, read_bytes_for_usize);
+    let key: Vec<usize> = trns
+        .chunks_exact(2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]) as usize)
+        .collect();
+
+    match info.color_type {
+        ColorType::Indexed => Some(
+            samples
+                .iter()
+                .map(|v| trns.get(*v).copied().unwrap_or(u8::MAX))
+                .collect(),
+        ),
+        ColorType::Grayscale if !key.is_empty() => Some(
+            samples
+                .iter()
+                .map(|v| if *v == key[0] { 0 } else { u8::MAX })
+                .collect(),
+        ),
+        ColorType::Rgb if key.len() >= 3 => Some(
+            samples
+                .chunks_exact(3)
+                .map(|v| if v == &key[..3] { 0 } else { u8::MAX })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Rounds each big-endian 16-bit sample to the nearest 8-bit value.
+pub(crate) fn to_8_bit(buf: &[u8]) -> Vec<u8> {
+    read_bytes_depth_16(buf)
+        .into_iter()
+        .map(round_to_8_bit)
+        .collect()
+}
+
+/// Nearest 8-bit value of a 16-bit sample, so that 65535 becomes 255 and midtones
+/// round rather than lose the low byte.
+fn round_to_8_bit(v: u16) -> u8 {
+    ((v as u32 * u8::MAX as u32 + u16::MAX as u32 / 2) / u16::MAX as u32) as u8
+}
+
+fn buf_to_rgba_from_indexed(buf: &[u8], info: &Info) -> Result<Vec<u8>, MergeError> {
+    let pallete = match info.palette.as_ref() {
         Some(v) => split_palette(v)?,
         None => return Err(MergeError::PaletteNotFoundWhenIndexedPng),
     };
-    let indices = read_bytes_for_usize(buf, bit_depth);
+    let indices = read_samples(buf, info, read_bytes_for_usize);
 
     indices
         .iter()
         .try_fold(Vec::new(), |mut acc, v| {
-            pallete.get(*v).map(|p| {
-                acc.push(p[0]);
-                acc.push(p[1]);
-                acc.push(p[2]);
-                acc.push(u8::MAX);
-                acc
-            })
+            pallete
+                .get(*v)
+                .map(|p| {
+                    acc.push(p[0]);
+                    acc.push(p[1]);
+                    acc.push(p[2]);
+                    acc.push(u8::MAX);
+                    acc
+                })
+                .ok_or(*v)
+        })
+        .map_err(|index| MergeError::InvalidIndexForPalette {
+            index,
+            palette_len: pallete.len(),
         })
-        .ok_or(MergeError::InvalidIndexForPalette)
 }
 
-fn buf_to_alpha_mask(buf: &[u8], info: &Info) -> Result<Vec<u8>, MergeError> {
+fn buf_to_alpha_mask(
+    buf: &[u8],
+    info: &Info,
+    alpha_source: AlphaSource,
+    luma_weights: LumaWeights,
+) -> Result<Vec<u8>, MergeError> {
     let rgba = buf_to_rgba(buf, info)?;
 
+    if info.color_type == ColorType::GrayscaleAlpha && alpha_source == AlphaSource::Alpha {
+        return Ok(rgba.chunks_exact(4).map(|v| v[3]).collect());
+    }
+
     Ok(rgba
         .chunks_exact(4)
-        .flat_map(|v| {
-            // TODO: alpha blend?
-            let v = (v[0] as u16 + v[1] as u16 + v[2] as u16) / 3;
-            [v as u8]
-        })
+        .map(|v| luma_weights.luma(v[0], v[1], v[2]))
         .collect())
 }
 
+/// Reads the samples of the frame with `read`. Rows of depths below 8 bits are padded
+/// to a byte boundary, so each row is read on its own and its padding is dropped,
+/// keeping one sample per channel of each pixel.
+fn read_samples<T>(buf: &[u8], info: &Info, read: fn(&[u8], &BitDepth) -> Vec<T>) -> Vec<T> {
+    let bits = info.bit_depth as usize;
+    if bits >= 8 {
+        return read(buf, &info.bit_depth);
+    }
+
+    let samples_per_row = info.width as usize * info.color_type.samples();
+    let row_bytes = (samples_per_row * bits).div_ceil(8).max(1);
+    buf.chunks(row_bytes)
+        .flat_map(|row| {
+            let mut samples = read(row, &info.bit_depth);
+            samples.truncate(samples_per_row);
+            samples
+        })
+        .collect()
+}
+
 fn read_bytes_for_bit_depth_8(buf: &[u8], bit_depth: &BitDepth) -> Vec<u8> {
     match bit_depth {
         BitDepth::One => buf
@@ -120,7 +686,10 @@ fn read_bytes_for_bit_depth_8(buf: &[u8], bit_depth: &BitDepth) -> Vec<u8> {
             .map(|v| bit_to_u8(v, 4))
             .collect(),
         BitDepth::Eight => buf.to_vec(),
-        BitDepth::Sixteen => buf.chunks_exact(2).flat_map(|v| [v[0]]).collect(),
+        BitDepth::Sixteen => read_bytes_depth_16(buf)
+            .into_iter()
+            .map(round_to_8_bit)
+            .collect(),
     }
 }
 
@@ -142,100 +711,1128 @@ fn read_bytes_for_usize(buf: &[u8], bit_depth: &BitDepth) -> Vec<usize> {
             .map(|v| v as usize)
             .collect(),
         BitDepth::Eight => buf.iter().map(|v| *v as usize).collect(),
-        BitDepth::Sixteen => buf
-            .chunks_exact(2)
-            .flat_map(|v| [(((v[0] as usize) << 8) | v[1] as usize)])
+        BitDepth::Sixteen => read_bytes_depth_16(buf)
+            .iter()
+            .map(|v| *v as usize)
             .collect(),
     }
 }
 
-fn bit_to_u8(v: u8, bit: u32) -> u8 {
-    let v = v << (8 - bit);
-    if v.trailing_zeros() == (8 - bit) {
-        v | (u8::MAX >> bit)
-    } else {
-        v
+fn read_bytes_depth_16(buf: &[u8]) -> Vec<u16> {
+    buf.chunks_exact(2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .collect()
+}
+
+fn bit_to_u8(v: u8, bit: u32) -> u8 {
+    let v = v << (8 - bit);
+    if v.trailing_zeros() == (8 - bit) {
+        v | (u8::MAX >> bit)
+    } else {
+        v
+    }
+}
+
+pub(crate) fn read_byte_depth_1(v: &u8) -> [u8; 8] {
+    [
+        (v & (1 << 7)) >> 7,
+        (v & (1 << 6)) >> 6,
+        (v & (1 << 5)) >> 5,
+        (v & (1 << 4)) >> 4,
+        (v & (1 << 3)) >> 3,
+        (v & (1 << 2)) >> 2,
+        (v & (1 << 1)) >> 1,
+        (v & (1 << 0)),
+    ]
+}
+
+fn read_byte_depth_2(v: &u8) -> [u8; 4] {
+    [
+        (v & ((1 << 6) | (1 << 7))) >> 6,
+        (v & ((1 << 4) | (1 << 5))) >> 4,
+        (v & ((1 << 2) | (1 << 3))) >> 2,
+        (v & ((1 << 0) | (1 << 1))),
+    ]
+}
+
+fn read_byte_depth_4(v: &u8) -> [u8; 2] {
+    [
+        (v & ((1 << 4) | (1 << 5) | (1 << 6) | (1 << 7))) >> 4,
+        (v & ((1 << 0) | (1 << 1) | (1 << 2) | (1 << 3))),
+    ]
+}
+
+/// Quantizes 8-bit gray samples to `bit_depth` and packs them MSB first,
+/// padding each row to a byte boundary. The inverse of `read_byte_depth_*`.
+pub(crate) fn pack_gray_samples(samples: &[u8], width: u32, bit_depth: BitDepth) -> Vec<u8> {
+    let bits = match bit_depth {
+        BitDepth::One => 1,
+        BitDepth::Two => 2,
+        BitDepth::Four => 4,
+        BitDepth::Eight => return samples.to_vec(),
+        BitDepth::Sixteen => {
+            return samples
+                .iter()
+                .flat_map(|v| (*v as u16 * 257).to_be_bytes())
+                .collect()
+        }
+    };
+    let max = (1u32 << bits) - 1;
+    let per_byte = 8 / bits;
+
+    if width == 0 {
+        return Vec::new();
+    }
+
+    samples
+        .chunks(width as usize)
+        .flat_map(|row| {
+            row.chunks(per_byte as usize).map(move |v| {
+                v.iter().enumerate().fold(0u8, |acc, (i, sample)| {
+                    let q = (*sample as u32 * max + 127) / u8::MAX as u32;
+                    acc | (q << (8 - bits * (i as u32 + 1))) as u8
+                })
+            })
+        })
+        .collect()
+}
+
+/// Rgba image re-quantized to a palette, with the alpha of the entries in tRNS.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IndexedImage {
+    pub bit_depth: BitDepth,
+    /// Rgb of each entry, as in PLTE.
+    pub palette: Vec<u8>,
+    /// Alpha of the entries up to the last that is not opaque; empty when all are.
+    pub trns: Vec<u8>,
+    /// Indices packed at `bit_depth`, as the image data of an indexed png.
+    pub data: Vec<u8>,
+}
+
+/// Gives each distinct color of 8-bit `rgba` a palette entry, or `None` when there are
+/// more than 256. Fully transparent pixels share one entry whatever their color, and
+/// entries that are not opaque come first so that tRNS stays short.
+pub(crate) fn index_rgba(rgba: &[u8], width: u32) -> Option<IndexedImage> {
+    let color = |v: &[u8]| match v[3] {
+        0 => [0; 4],
+        _ => [v[0], v[1], v[2], v[3]],
+    };
+    let mut colors = BTreeSet::new();
+    for v in rgba.chunks_exact(4) {
+        colors.insert(color(v));
+        if colors.len() > 256 {
+            return None;
+        }
+    }
+    let mut colors: Vec<[u8; 4]> = colors.into_iter().collect();
+    colors.sort_by_key(|v| v[3] == u8::MAX);
+    let indices: HashMap<[u8; 4], u8> = colors
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (*v, i as u8))
+        .collect();
+
+    let (bit_depth, step) = match colors.len() {
+        0..=2 => (BitDepth::One, 255),
+        3..=4 => (BitDepth::Two, 85),
+        5..=16 => (BitDepth::Four, 17),
+        _ => (BitDepth::Eight, 1),
+    };
+    // Scaled by `step`, the indices come out of the quantization of `pack_gray_samples`
+    // as they are.
+    let samples: Vec<u8> = rgba
+        .chunks_exact(4)
+        .map(|v| indices[&color(v)] * step)
+        .collect();
+
+    Some(IndexedImage {
+        bit_depth,
+        palette: colors.iter().flat_map(|v| [v[0], v[1], v[2]]).collect(),
+        trns: colors
+            .iter()
+            .take_while(|v| v[3] != u8::MAX)
+            .map(|v| v[3])
+            .collect(),
+        data: pack_gray_samples(&samples, width, bit_depth),
+    })
+}
+
+fn split_palette(palette_raw: &[u8]) -> Result<Vec<[u8; 3]>, MergeError> {
+    let mut result = Vec::new();
+    let palette_splited = palette_raw.chunks(3);
+
+    for p in palette_splited {
+        if p.len() != 3 {
+            return Err(MergeError::InvalidPalette {
+                len: palette_raw.len(),
+            });
+        }
+
+        result.push([p[0], p[1], p[2]]);
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    mod luma {
+        use super::*;
+
+        #[test]
+        fn success_when_bt601() {
+            assert_eq!(LumaWeights::Bt601.luma(0, 255, 0), 150);
+            assert_eq!(LumaWeights::Bt601.luma(0, 0, 255), 29);
+            assert_eq!(LumaWeights::Bt601.luma(255, 0, 0), 76);
+        }
+
+        #[test]
+        fn success_when_bt709() {
+            assert_eq!(LumaWeights::Bt709.luma(0, 255, 0), 182);
+            assert_eq!(LumaWeights::Bt709.luma(0, 0, 255), 18);
+            assert_eq!(LumaWeights::Bt709.luma(255, 0, 0), 54);
+        }
+
+        #[test]
+        fn success_when_average() {
+            assert_eq!(LumaWeights::Average.luma(0, 255, 0), 85);
+            assert_eq!(LumaWeights::Average.luma(10, 20, 31), 20);
+        }
+
+        #[test]
+        fn success_when_gray_is_kept() {
+            for weights in [LumaWeights::Bt601, LumaWeights::Bt709, LumaWeights::Average] {
+                for v in 0..=u8::MAX {
+                    assert_eq!(weights.luma(v, v, v), v);
+                }
+            }
+        }
+    }
+
+    mod row_info {
+        use super::*;
+
+        #[test]
+        fn success_when_rows_merged_one_by_one() {
+            let png_buf = [0b10000000, 0b01000000];
+            let mut png_info = Info::with_size(2, 2);
+            png_info.color_type = ColorType::Grayscale;
+            png_info.bit_depth = BitDepth::One;
+            let pna_buf = [0, 255, 128, 64];
+            let mut pna_info = Info::with_size(2, 2);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            let rows: Vec<u8> = (0..2)
+                .flat_map(|i| {
+                    merge_pna(
+                        &png_buf[i..i + 1],
+                        &row_info(&png_info),
+                        &pna_buf[i * 2..i * 2 + 2],
+                        &row_info(&pna_info),
+                        &MergeOptions::default(),
+                    )
+                    .unwrap()
+                })
+                .collect();
+
+            assert_eq!(
+                rows,
+                merge_pna(
+                    &png_buf,
+                    &png_info,
+                    &pna_buf,
+                    &pna_info,
+                    &MergeOptions::default()
+                )
+                .unwrap()
+            );
+            assert_eq!(row_info(&png_info).height, 1);
+        }
+    }
+
+    mod merge_pna {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_param() {
+            let png_buf = [u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX];
+            let mut png_info = Info::with_size(2, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0, 0];
+            let mut pna_info = Info::with_size(2, 1);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            let result = merge_pna(
+                &png_buf,
+                &png_info,
+                &pna_buf,
+                &pna_info,
+                &MergeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                result,
+                vec![u8::MAX, u8::MAX, u8::MAX, 0, u8::MAX, u8::MAX, u8::MAX, 0]
+            );
+        }
+
+        #[test]
+        fn success_when_single_pixel_grayscale_depth_1() {
+            let png_buf = [0b10000000];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Grayscale;
+            png_info.bit_depth = BitDepth::One;
+
+            let pna_buf = [0b11000000];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Two;
+
+            let result = merge_pna(
+                &png_buf,
+                &png_info,
+                &pna_buf,
+                &pna_info,
+                &MergeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![u8::MAX, u8::MAX, u8::MAX, u8::MAX]);
+        }
+
+        #[test]
+        fn success_when_single_pixel_indexed_depth_4() {
+            let png_buf = [0b00010000];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Indexed;
+            png_info.bit_depth = BitDepth::Four;
+            png_info.palette = Some(Cow::Owned(vec![0, 0, 0, 10, 20, 30]));
+
+            let pna_buf = [u8::MAX, 100];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::GrayscaleAlpha;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            let result = merge_pna(
+                &png_buf,
+                &png_info,
+                &pna_buf,
+                &pna_info,
+                &MergeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![10, 20, 30, 100]);
+        }
+
+        #[test]
+        fn success_when_single_pixel_rgb_depth_8() {
+            let png_buf = [1, 2, 3];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0, 0, 0, 0, 0, 0];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::Rgb;
+            pna_info.bit_depth = BitDepth::Sixteen;
+
+            let result = merge_pna(
+                &png_buf,
+                &png_info,
+                &pna_buf,
+                &pna_info,
+                &MergeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![1, 2, 3, 0]);
+        }
+
+        #[test]
+        fn success_when_single_pixel_rgba_depth_16() {
+            let png_buf = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Sixteen;
+
+            let pna_buf = [0x80, 0x00];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Sixteen;
+
+            let result = merge_pna(
+                &png_buf,
+                &png_info,
+                &pna_buf,
+                &pna_info,
+                &MergeOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![0x12, 0x56, 0x9a, 0x80]);
+        }
+
+        #[test]
+        fn success_when_options() {
+            let png_buf = [10, 20, 30, 200, 0, 0, 0, 200, 40, 50, 60, 200];
+            let mut png_info = Info::with_size(3, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0, 100, 255, 0, 0, 0, 200, 200, 200];
+            let mut pna_info = Info::with_size(3, 1);
+            pna_info.color_type = ColorType::Rgb;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            let options = MergeOptions {
+                invert: true,
+                threshold: Some(128),
+                merge_mode: MergeMode::Multiply,
+                black_tolerance: Some(0),
+                ..Default::default()
+            };
+            let result = merge_pna(&png_buf, &png_info, &pna_buf, &pna_info, &options).unwrap();
+
+            assert_eq!(result, vec![10, 20, 30, 200, 0, 0, 0, 0, 40, 50, 60, 0]);
+        }
+
+        #[test]
+        fn failed_when_strict_and_buf_is_too_long() {
+            let png_buf = [1, 2, 3, 4, 5, 6];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            let strict = MergeOptions {
+                strict_data_size: true,
+                ..Default::default()
+            };
+
+            assert_eq!(
+                merge_pna(
+                    &png_buf,
+                    &png_info,
+                    &pna_buf,
+                    &pna_info,
+                    &MergeOptions::default()
+                )
+                .unwrap(),
+                vec![1, 2, 3, 0]
+            );
+            assert!(matches!(
+                merge_pna(&png_buf, &png_info, &pna_buf, &pna_info, &strict),
+                Err(MergeError::DataSizeMismatch {
+                    expected: 4,
+                    actual: 8
+                })
+            ));
+            assert!(matches!(
+                merge_pna(&png_buf[..3], &png_info, &[0, 0], &pna_info, &strict),
+                Err(MergeError::DataSizeMismatch {
+                    expected: 1,
+                    actual: 2
+                })
+            ));
+        }
+
+        #[test]
+        fn failed_when_single_pixel_buf_is_empty() {
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0];
+            let mut pna_info = Info::with_size(1, 1);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            assert!(matches!(
+                merge_pna(
+                    &[],
+                    &png_info,
+                    &pna_buf,
+                    &pna_info,
+                    &MergeOptions::default()
+                ),
+                Err(MergeError::LessDataSize)
+            ));
+        }
+
+        #[test]
+        fn failed_when_size_is_different() {
+            let png_buf = [u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX];
+            let mut png_info = Info::with_size(2, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let pna_buf = [0, 0];
+            let mut pna_info = Info::with_size(1, 2);
+            pna_info.color_type = ColorType::Grayscale;
+            pna_info.bit_depth = BitDepth::Eight;
+
+            assert!(matches!(
+                merge_pna(
+                    &png_buf,
+                    &png_info,
+                    &pna_buf,
+                    &pna_info,
+                    &MergeOptions::default()
+                ),
+                Err(MergeError::SizePngAndPnaAreDifferent {
+                    png: (2, 1),
+                    pna: (1, 2)
+                })
+            ));
+        }
+    }
+
+    mod sample_count {
+        use super::*;
+
+        #[test]
+        fn success_when_count_fits() {
+            assert_eq!(sample_count(3, 2, 4).unwrap(), 24);
+            assert_eq!(sample_count(u32::MAX, 0, 4).unwrap(), 0);
+        }
+
+        #[test]
+        fn failed_when_count_overflows() {
+            assert!(matches!(
+                sample_count(u32::MAX, u32::MAX, 4),
+                Err(MergeError::ImageTooLarge {
+                    width: u32::MAX,
+                    height: u32::MAX
+                })
+            ));
+        }
+    }
+
+    mod round_to_8_bit {
+        use super::*;
+
+        #[test]
+        fn success_when_rounded_to_nearest() {
+            assert_eq!(round_to_8_bit(0x0000), 0);
+            assert_eq!(round_to_8_bit(0x0080), 0);
+            assert_eq!(round_to_8_bit(0x0081), 1);
+            assert_eq!(round_to_8_bit(0x7fff), 127);
+            assert_eq!(round_to_8_bit(0x8080), 128);
+            assert_eq!(round_to_8_bit(0xffff), 255);
+        }
+    }
+
+    mod merge_alpha_mask {
+        use super::*;
+
+        #[test]
+        fn failed_when_size_near_u32_max() {
+            let mut png_info = Info::with_size(u32::MAX, u32::MAX - 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: u32::MAX,
+                height: u32::MAX - 1,
+                data: Vec::new(),
+            };
+
+            for result in [
+                merge_alpha_mask(
+                    &[],
+                    &png_info,
+                    &alpha_mask,
+                    &MergeOptions::default(),
+                    Packing::default(),
+                ),
+                merge_alpha_mask_16(
+                    &[],
+                    &png_info,
+                    &alpha_mask,
+                    &MergeOptions::default(),
+                    Packing::default(),
+                ),
+            ] {
+                assert!(matches!(
+                    result,
+                    Err(MergeError::ImageTooLarge {
+                        width: u32::MAX,
+                        height: 4294967294
+                    })
+                ));
+            }
+        }
+
+        #[test]
+        fn success_when_black_is_transparent() {
+            let png_buf = [0, 0, 0, 4, 5, 4, 5, 5, 5, u8::MAX, 0, 0];
+            let mut png_info = Info::with_size(4, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![200, 200, 200, 100],
+            };
+
+            let result = merge_alpha_mask(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions {
+                    black_tolerance: Some(4),
+                    ..Default::default()
+                },
+                Packing::default(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                result,
+                vec![0, 0, 0, 0, 4, 5, 4, 200, 5, 5, 5, 200, u8::MAX, 0, 0, 100]
+            );
+        }
+
+        #[test]
+        fn success_when_mask_passthrough_value() {
+            let png_buf = [1, 2, 3, 40, 1, 2, 3, 40, 1, 2, 3, 40];
+            let mut png_info = Info::with_size(3, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 1,
+                data: vec![127, 128, 129],
+            };
+            let merge = |merge_mode| {
+                merge_alpha_mask(
+                    &png_buf[..],
+                    &png_info,
+                    &alpha_mask,
+                    &MergeOptions {
+                        merge_mode,
+                        mask_passthrough_value: Some(128),
+                        ..Default::default()
+                    },
+                    Packing::default(),
+                )
+                .unwrap()
+            };
+            let merge_16 = |merge_mode| {
+                merge_alpha_mask_16(
+                    &png_buf[..],
+                    &png_info,
+                    &alpha_mask,
+                    &MergeOptions {
+                        merge_mode,
+                        mask_passthrough_value: Some(128),
+                        ..Default::default()
+                    },
+                    Packing::default(),
+                )
+                .unwrap()
+            };
+
+            let alpha = |v: Vec<u8>| v.into_iter().skip(3).step_by(4).collect::<Vec<_>>();
+            assert_eq!(alpha(merge(MergeMode::Replace)), vec![127, 40, 129]);
+            assert_eq!(alpha(merge(MergeMode::Multiply)), vec![20, 40, 20]);
+            let alpha_16: Vec<u16> = merge_16(MergeMode::Replace)
+                .chunks_exact(8)
+                .map(|v| u16::from_be_bytes([v[6], v[7]]))
+                .collect();
+            assert_eq!(alpha_16, vec![32639, 40 * 257, 33153]);
+        }
+    }
+
+    mod merge_alpha_mask_with_trns {
+        use super::*;
+
+        #[test]
+        fn success_when_trns_is_multiplied() {
+            let png_buf = [0, 1, 2];
+            let mut png_info = Info::with_size(3, 1);
+            png_info.color_type = ColorType::Indexed;
+            png_info.bit_depth = BitDepth::Eight;
+            let palette_raw = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+            png_info.palette = Some(Cow::from(&palette_raw[..]));
+            png_info.trns = Some(Cow::from(&[0, 128][..]));
+            let alpha_mask = AlphaMask {
+                width: 3,
+                height: 1,
+                data: vec![u8::MAX, 128, 200],
+            };
+
+            let result = merge_alpha_mask(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions::default(),
+                Packing::default(),
+            )
+            .unwrap();
+            let result_16 = merge_alpha_mask_16(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions::default(),
+                Packing::default(),
+            )
+            .unwrap();
+
+            let alpha: Vec<u8> = result.iter().skip(3).step_by(4).copied().collect();
+            assert_eq!(alpha, vec![0, 64, 200]);
+            let alpha_16: Vec<u16> = result_16
+                .chunks_exact(8)
+                .map(|v| u16::from_be_bytes([v[6], v[7]]))
+                .collect();
+            assert_eq!(alpha_16, vec![0, 16513, 51400]);
+        }
+
+        #[test]
+        fn success_when_alpha_channel_is_replaced() {
+            let png_buf = [10, 20, 30, 0];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 1,
+                height: 1,
+                data: vec![200],
+            };
+
+            let result = merge_alpha_mask(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions::default(),
+                Packing::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![10, 20, 30, 200]);
+        }
+
+        #[test]
+        fn success_when_packed_into_color_channel() {
+            let png_buf = [10, 20, 30, 128];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 1,
+                height: 1,
+                data: vec![200],
+            };
+            let merge = |packing| {
+                merge_alpha_mask(
+                    &png_buf,
+                    &png_info,
+                    &alpha_mask,
+                    &MergeOptions::default(),
+                    packing,
+                )
+                .unwrap()
+            };
+
+            let kept = merge(Packing {
+                channel: Channel::G,
+                fill: None,
+            });
+            let filled = merge(Packing {
+                channel: Channel::R,
+                fill: Some(0),
+            });
+
+            assert_eq!(kept, vec![10, 200, 30, 128]);
+            assert_eq!(filled, vec![200, 0, 0, 0]);
+        }
+
+        #[test]
+        fn success_when_alpha_channel_is_multiplied() {
+            let png_buf = [10, 20, 30, 128];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 1,
+                height: 1,
+                data: vec![200],
+            };
+
+            let result = merge_alpha_mask(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions {
+                    merge_mode: MergeMode::Multiply,
+                    ..Default::default()
+                },
+                Packing::default(),
+            )
+            .unwrap();
+            let result_16 = merge_alpha_mask_16(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions {
+                    merge_mode: MergeMode::Multiply,
+                    ..Default::default()
+                },
+                Packing::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![10, 20, 30, 100]);
+            assert_eq!(&result_16[6..], &25801_u16.to_be_bytes());
+        }
+    }
+
+    mod merge_alpha_mask_16 {
+        use super::*;
+
+        #[test]
+        fn success_when_16_bit_png() {
+            let png_buf = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0, 1, 0, 2, 0, 3];
+            let mut png_info = Info::with_size(2, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Sixteen;
+            let alpha_mask = AlphaMask {
+                width: 2,
+                height: 1,
+                data: vec![128, 255],
+            };
+
+            let result = merge_alpha_mask_16(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions {
+                    black_tolerance: Some(0),
+                    ..Default::default()
+                },
+                Packing::default(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                result,
+                vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0x80, 0x80, 0, 1, 0, 2, 0, 3, 0, 0]
+            );
+        }
+
+        #[test]
+        fn success_when_8_bit_png_is_scaled_up() {
+            let png_buf = [0, 128, 255];
+            let mut png_info = Info::with_size(1, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 1,
+                height: 1,
+                data: vec![u8::MAX],
+            };
+
+            let result = merge_alpha_mask_16(
+                &png_buf,
+                &png_info,
+                &alpha_mask,
+                &MergeOptions::default(),
+                Packing::default(),
+            )
+            .unwrap();
+
+            assert_eq!(result, vec![0, 0, 0x80, 0x80, 0xff, 0xff, 0xff, 0xff]);
+        }
+    }
+
+    mod unpremultiply_16 {
+        use super::*;
+
+        #[test]
+        fn success_when_half_alpha() {
+            let mut rgba = [16384, 32767, 0, 32768, 1000, 2000, 3000, 0];
+
+            unpremultiply_16(&mut rgba);
+
+            assert_eq!(rgba, [32768, 65533, 0, 32768, 1000, 2000, 3000, 0]);
+        }
+    }
+
+    mod own_alpha_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_rgba() {
+            let png_buf = [10, 20, 30, 40, 50, 60, 70, 80];
+            let mut png_info = Info::with_size(2, 1);
+            png_info.color_type = ColorType::Rgba;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let result =
+                own_alpha_mask(&png_buf, &png_info, LumaWeights::default(), false).unwrap();
+
+            assert_eq!(result.data, vec![40, 80]);
+        }
+
+        #[test]
+        fn success_when_rgb() {
+            let png_buf = [10, 20, 30, 40, 50, 60];
+            let mut png_info = Info::with_size(2, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+
+            let result = own_alpha_mask(&png_buf, &png_info, LumaWeights::Average, false).unwrap();
+
+            assert_eq!(result.data, vec![20, 50]);
+        }
     }
-}
 
-fn read_byte_depth_1(v: &u8) -> [u8; 8] {
-    [
-        (v & (1 << 7)) >> 7,
-        (v & (1 << 6)) >> 6,
-        (v & (1 << 5)) >> 5,
-        (v & (1 << 4)) >> 4,
-        (v & (1 << 3)) >> 3,
-        (v & (1 << 2)) >> 2,
-        (v & (1 << 1)) >> 1,
-        (v & (1 << 0)),
-    ]
-}
+    mod restrict_mask_to_indices {
+        use super::*;
 
-fn read_byte_depth_2(v: &u8) -> [u8; 4] {
-    [
-        (v & ((1 << 6) | (1 << 7))) >> 6,
-        (v & ((1 << 4) | (1 << 5))) >> 4,
-        (v & ((1 << 2) | (1 << 3))) >> 2,
-        (v & ((1 << 0) | (1 << 1))),
-    ]
-}
+        #[test]
+        fn success_when_indexed() {
+            let png_buf = [0b00010010, 0b00110000];
+            let mut png_info = Info::with_size(4, 1);
+            png_info.color_type = ColorType::Indexed;
+            png_info.bit_depth = BitDepth::Four;
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![10, 20, 30, 40],
+            };
+
+            let result = restrict_mask_to_indices(&png_buf, &png_info, &alpha_mask, |i| i % 2 == 1);
+
+            assert_eq!(result.data, vec![10, u8::MAX, 30, u8::MAX]);
+        }
 
-fn read_byte_depth_4(v: &u8) -> [u8; 2] {
-    [
-        (v & ((1 << 4) | (1 << 5) | (1 << 6) | (1 << 7))) >> 4,
-        (v & ((1 << 0) | (1 << 1) | (1 << 2) | (1 << 3))),
-    ]
-}
+        #[test]
+        fn success_when_not_indexed() {
+            let png_buf = [0, 1, 2, 3];
+            let mut png_info = Info::with_size(4, 1);
+            png_info.color_type = ColorType::Grayscale;
+            png_info.bit_depth = BitDepth::Eight;
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![10, 20, 30, 40],
+            };
 
-fn split_palette(palette_raw: &Cow<[u8]>) -> Result<Vec<[u8; 3]>, MergeError> {
-    let mut result = Vec::new();
-    let palette_splited = palette_raw.chunks(3);
+            let result = restrict_mask_to_indices(&png_buf, &png_info, &alpha_mask, |_| false);
 
-    for p in palette_splited {
-        if p.len() != 3 {
-            return Err(MergeError::InvalidPalette);
+            assert_eq!(result, alpha_mask);
         }
-
-        result.push([p[0], p[1], p[2]]);
     }
 
-    Ok(result)
-}
+    mod split_rgba {
+        use super::*;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        #[test]
+        fn success_when_alpha_is_discarded() {
+            let rgba = [10, 20, 30, 0, 40, 50, 60, 128];
 
-    mod merge_pna {
+            let (rgb, alpha) = split_rgba(&rgba, None);
+
+            assert_eq!(rgb, [10, 20, 30, 40, 50, 60]);
+            assert_eq!(alpha, [0, 128]);
+        }
+
+        #[test]
+        fn success_when_composited_onto_background() {
+            let rgba = [10, 20, 30, 0, 255, 0, 100, 255, 200, 100, 0, 128];
+
+            let (rgb, alpha) = split_rgba(&rgba, Some([255, 255, 255]));
+
+            assert_eq!(rgb, [255, 255, 255, 255, 0, 100, 227, 177, 127]);
+            assert_eq!(alpha, [0, 255, 128]);
+        }
+    }
+
+    mod unpremultiply {
         use super::*;
 
         #[test]
-        fn success_when_valid_param() {
-            let png_buf = [u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX];
-            let mut png_info = Info::with_size(2, 1);
-            png_info.color_type = ColorType::Rgb;
-            png_info.bit_depth = BitDepth::Eight;
+        fn success_when_valid_rgba() {
+            let mut rgba = [
+                64,
+                32,
+                0,
+                128,
+                10,
+                20,
+                30,
+                0,
+                10,
+                20,
+                30,
+                u8::MAX,
+                200,
+                0,
+                0,
+                100,
+            ];
 
-            let pna_buf = [0, 0];
+            unpremultiply(&mut rgba);
+
+            assert_eq!(
+                rgba,
+                [
+                    128,
+                    64,
+                    0,
+                    128,
+                    10,
+                    20,
+                    30,
+                    0,
+                    10,
+                    20,
+                    30,
+                    u8::MAX,
+                    u8::MAX,
+                    0,
+                    0,
+                    100
+                ]
+            );
+        }
+    }
+
+    mod pna_to_alpha_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_buf() {
+            let pna_buf = [0b10000000];
             let mut pna_info = Info::with_size(2, 1);
             pna_info.color_type = ColorType::Grayscale;
-            pna_info.bit_depth = BitDepth::Eight;
+            pna_info.bit_depth = BitDepth::One;
 
-            let result = merge_pna(&png_buf, &png_info, &pna_buf, &pna_info).unwrap();
+            let result = pna_to_alpha_mask(
+                &pna_buf,
+                &pna_info,
+                AlphaSource::Alpha,
+                LumaWeights::default(),
+                false,
+            )
+            .unwrap();
 
             assert_eq!(
                 result,
-                vec![u8::MAX, u8::MAX, u8::MAX, 0, u8::MAX, u8::MAX, u8::MAX, 0]
+                AlphaMask {
+                    width: 2,
+                    height: 1,
+                    data: vec![u8::MAX, 0],
+                }
             );
         }
     }
 
+    mod is_effectively_grayscale {
+        use super::*;
+
+        #[test]
+        fn success_when_grayscale() {
+            let mut info = Info::with_size(1, 1);
+            info.color_type = ColorType::GrayscaleAlpha;
+            info.bit_depth = BitDepth::Eight;
+
+            assert!(is_effectively_grayscale(&[10, 20], &info).unwrap());
+        }
+
+        #[test]
+        fn success_when_rgb_is_gray() {
+            let mut info = Info::with_size(2, 1);
+            info.color_type = ColorType::Rgb;
+            info.bit_depth = BitDepth::Eight;
+
+            assert!(is_effectively_grayscale(&[10, 10, 10, 0, 0, 0], &info).unwrap());
+            assert!(!is_effectively_grayscale(&[10, 10, 10, 0, 1, 0], &info).unwrap());
+        }
+    }
+
+    mod alpha_coverage {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_mask() {
+            let alpha_mask = AlphaMask {
+                width: 4,
+                height: 1,
+                data: vec![0, COVERAGE_EPSILON, COVERAGE_EPSILON + 1, u8::MAX],
+            };
+
+            assert_eq!(alpha_coverage(&alpha_mask), 50.0);
+        }
+
+        #[test]
+        fn success_when_empty_mask() {
+            let alpha_mask = AlphaMask {
+                width: 0,
+                height: 0,
+                data: vec![],
+            };
+
+            assert_eq!(alpha_coverage(&alpha_mask), 0.0);
+        }
+    }
+
+    mod difference_mask {
+        use super::*;
+
+        #[test]
+        fn success_when_distance_is_scaled_by_threshold() {
+            let png_buf = [0, 0, 0, 30, 40, 0, 200, 200, 200];
+            let mut png_info = Info::with_size(3, 1);
+            png_info.color_type = ColorType::Rgb;
+            png_info.bit_depth = BitDepth::Eight;
+            let plate_buf = [0, 0, 0];
+            let mut plate_info = Info::with_size(3, 1);
+            plate_info.color_type = ColorType::Grayscale;
+            plate_info.bit_depth = BitDepth::Eight;
+
+            let result =
+                difference_mask(&png_buf, &png_info, &plate_buf, &plate_info, 100.0, false)
+                    .unwrap();
+
+            assert_eq!(result.data, vec![0, 128, u8::MAX]);
+        }
+
+        #[test]
+        fn failed_when_size_is_different() {
+            let png_info = Info::with_size(2, 1);
+            let plate_info = Info::with_size(1, 1);
+
+            assert!(matches!(
+                difference_mask(&[0; 2], &png_info, &[0], &plate_info, 64.0, false),
+                Err(MergeError::SizePngAndPnaAreDifferent {
+                    png: (2, 1),
+                    pna: (1, 1)
+                })
+            ));
+        }
+    }
+
     mod buf_to_rgba {
         use super::*;
 
         #[test]
         fn success_when_valid_buf_for_grayscale() {
-            let buf = [0b11000000];
+            let buf = [0b11000000, 0b00000000];
             let mut info = Info::with_size(2, 2);
             info.color_type = ColorType::Grayscale;
             info.bit_depth = BitDepth::Two;
@@ -293,9 +1890,57 @@ mod tests {
             );
         }
 
+        #[test]
+        fn success_when_indexed_with_trns() {
+            let buf = [0, 1, 2];
+            let mut info = Info::with_size(3, 1);
+            info.color_type = ColorType::Indexed;
+            info.bit_depth = BitDepth::Eight;
+            let palette_raw = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+            info.palette = Some(Cow::from(&palette_raw[..]));
+            info.trns = Some(Cow::from(&[0, 128][..]));
+
+            let result = buf_to_rgba(&buf, &info).unwrap();
+
+            assert_eq!(
+                result,
+                vec![10, 20, 30, 0, 40, 50, 60, 128, 70, 80, 90, u8::MAX]
+            );
+        }
+
+        #[test]
+        fn success_when_grayscale_with_trns() {
+            let buf = [0b00011011];
+            let mut info = Info::with_size(4, 1);
+            info.color_type = ColorType::Grayscale;
+            info.bit_depth = BitDepth::Two;
+            info.trns = Some(Cow::from(&[0, 2][..]));
+
+            let result = buf_to_rgba(&buf, &info).unwrap();
+
+            let alpha: Vec<u8> = result.iter().skip(3).step_by(4).copied().collect();
+            assert_eq!(alpha, vec![u8::MAX, u8::MAX, 0, u8::MAX]);
+        }
+
+        #[test]
+        fn success_when_rgb_16_bit_with_trns() {
+            let buf = [1, 2, 3, 4, 5, 6, 1, 2, 3, 4, 5, 7];
+            let mut info = Info::with_size(2, 1);
+            info.color_type = ColorType::Rgb;
+            info.bit_depth = BitDepth::Sixteen;
+            info.trns = Some(Cow::from(&[1, 2, 3, 4, 5, 6][..]));
+
+            let result = buf_to_rgba(&buf, &info).unwrap();
+            let result_16 = buf_to_rgba_16(&buf, &info).unwrap();
+
+            assert_eq!(result, vec![1, 3, 5, 0, 1, 3, 5, u8::MAX]);
+            assert_eq!(result_16[3], 0);
+            assert_eq!(result_16[7], u16::MAX);
+        }
+
         #[test]
         fn success_when_valid_buf_for_indexed() {
-            let buf = [0b11000000];
+            let buf = [0b11000000, 0b00000000];
             let mut info = Info::with_size(2, 2);
             info.color_type = ColorType::Indexed;
             info.bit_depth = BitDepth::One;
@@ -323,22 +1968,6 @@ mod tests {
                     0,
                     0,
                     u8::MAX,
-                    255,
-                    0,
-                    0,
-                    u8::MAX,
-                    255,
-                    0,
-                    0,
-                    u8::MAX,
-                    255,
-                    0,
-                    0,
-                    u8::MAX,
-                    255,
-                    0,
-                    0,
-                    u8::MAX,
                 ]
             );
         }
@@ -375,9 +2004,46 @@ mod tests {
 
             assert_eq!(
                 result,
-                vec![0b11000000, 0b00001100, 0b11000000, 0b00001100,]
+                vec![0b10111111, 0b00001100, 0b10111111, 0b00001100,]
             );
         }
+
+        #[test]
+        fn failed_when_partial_pixel() {
+            let mut info = Info::with_size(2, 1);
+            info.color_type = ColorType::Rgb;
+            info.bit_depth = BitDepth::Eight;
+
+            assert!(matches!(
+                buf_to_rgba(&[1, 2, 3, 4, 5], &info),
+                Err(MergeError::PartialPixel {
+                    size: 3,
+                    leftover: 2
+                })
+            ));
+
+            info.color_type = ColorType::GrayscaleAlpha;
+            info.bit_depth = BitDepth::Sixteen;
+            for result in [
+                buf_to_rgba(&[0; 7], &info).map(|_| ()),
+                buf_to_rgba_16(&[0; 7], &info).map(|_| ()),
+            ] {
+                assert!(matches!(
+                    result,
+                    Err(MergeError::PartialPixel {
+                        size: 2,
+                        leftover: 1
+                    })
+                ));
+            }
+            assert!(matches!(
+                buf_to_rgba_16(&[0; 6], &info),
+                Err(MergeError::PartialPixel {
+                    size: 2,
+                    leftover: 1
+                })
+            ));
+        }
     }
 
     mod buf_to_alpha_mask {
@@ -386,12 +2052,13 @@ mod tests {
 
         #[test]
         fn success_when_valid_buf_for_grayscale() {
-            let buf = [0b11000000];
+            let buf = [0b11000000, 0b00000000];
             let mut info = Info::with_size(2, 2);
             info.color_type = ColorType::Grayscale;
             info.bit_depth = BitDepth::Two;
 
-            let result = buf_to_alpha_mask(&buf, &info).unwrap();
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::default()).unwrap();
 
             assert_eq!(result, vec![u8::MAX, 0, 0, 0]);
         }
@@ -403,23 +2070,25 @@ mod tests {
             info.color_type = ColorType::Rgb;
             info.bit_depth = BitDepth::Four;
 
-            let result = buf_to_alpha_mask(&buf, &info).unwrap();
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::Average).unwrap();
 
             assert_eq!(result, vec![64, 170, 64]);
         }
 
         #[test]
         fn success_when_valid_buf_for_indexed() {
-            let buf = [0b11000000];
+            let buf = [0b11000000, 0b00000000];
             let mut info = Info::with_size(2, 2);
             info.color_type = ColorType::Indexed;
             info.bit_depth = BitDepth::One;
             let palette_raw = [255, 0, 0, 0, 0, 255];
             info.palette = Some(Cow::from(&palette_raw[..]));
 
-            let result = buf_to_alpha_mask(&buf, &info).unwrap();
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::Average).unwrap();
 
-            assert_eq!(result, vec![85, 85, 85, 85, 85, 85, 85, 85]);
+            assert_eq!(result, vec![85, 85, 85, 85]);
         }
 
         #[test]
@@ -429,11 +2098,38 @@ mod tests {
             info.color_type = ColorType::GrayscaleAlpha;
             info.bit_depth = BitDepth::Eight;
 
-            let result = buf_to_alpha_mask(&buf, &info).unwrap();
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::default()).unwrap();
+
+            assert_eq!(result, vec![48, 3]);
+        }
+
+        #[test]
+        fn success_when_valid_buf_for_grayscale_alpha_from_gray() {
+            let buf = [0b11000000, 0b00110000, 0b00001100, 0b00000011];
+            let mut info = Info::with_size(2, 1);
+            info.color_type = ColorType::GrayscaleAlpha;
+            info.bit_depth = BitDepth::Eight;
+
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Gray, LumaWeights::default()).unwrap();
 
             assert_eq!(result, vec![192, 12]);
         }
 
+        #[test]
+        fn success_when_valid_buf_for_grayscale_with_any_alpha_source() {
+            let buf = [0b11000000, 0b00000000];
+            let mut info = Info::with_size(2, 2);
+            info.color_type = ColorType::Grayscale;
+            info.bit_depth = BitDepth::Two;
+
+            assert_eq!(
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::default()).unwrap(),
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Gray, LumaWeights::default()).unwrap()
+            );
+        }
+
         #[test]
         fn success_when_valid_buf_for_rgba() {
             let buf = [
@@ -444,9 +2140,10 @@ mod tests {
             info.color_type = ColorType::Rgba;
             info.bit_depth = BitDepth::Sixteen;
 
-            let result = buf_to_alpha_mask(&buf, &info).unwrap();
+            let result =
+                buf_to_alpha_mask(&buf, &info, AlphaSource::Alpha, LumaWeights::Average).unwrap();
 
-            assert_eq!(result, vec![132]);
+            assert_eq!(result, vec![131]);
         }
     }
 
@@ -470,7 +2167,7 @@ mod tests {
 
             let result = read_bytes_for_bit_depth_8(&buf, &bit_depth);
 
-            assert_eq!(result, vec![0b00110110]);
+            assert_eq!(result, vec![0b00110111]);
         }
 
         // #[test]
@@ -495,10 +2192,10 @@ mod tests {
             assert_eq!(
                 result,
                 vec![
-                    0b00000011 as usize,
-                    0b00000110 as usize,
-                    0b00001100 as usize,
-                    0b00001001 as usize
+                    0b00000011_usize,
+                    0b00000110_usize,
+                    0b00001100_usize,
+                    0b00001001_usize
                 ]
             );
         }
@@ -510,7 +2207,7 @@ mod tests {
 
             let result = read_bytes_for_usize(&buf, &bit_depth);
 
-            assert_eq!(result, vec![0b0011011011001001 as usize]);
+            assert_eq!(result, vec![0b0011011011001001_usize]);
         }
 
         // #[test]
@@ -521,4 +2218,111 @@ mod tests {
         //     assert!(read_bytes_for_usize(&buf, &bit_depth).is_err());
         // }
     }
+
+    mod index_rgba {
+        use super::*;
+
+        use std::borrow::Cow;
+
+        fn decode(indexed: &IndexedImage, width: u32, height: u32) -> Vec<u8> {
+            let mut info = Info::with_size(width, height);
+            info.color_type = ColorType::Indexed;
+            info.bit_depth = indexed.bit_depth;
+            info.palette = Some(Cow::from(&indexed.palette[..]));
+            info.trns = Some(Cow::from(&indexed.trns[..]));
+            buf_to_rgba(&indexed.data, &info).unwrap()
+        }
+
+        #[test]
+        fn success_when_few_colors() {
+            let rgba = [
+                10, 20, 30, 255, 10, 20, 30, 0, 40, 50, 60, 0, 10, 20, 30, 128, 10, 20, 30, 255,
+            ];
+
+            let result = index_rgba(&rgba, 5).unwrap();
+
+            assert_eq!(result.bit_depth, BitDepth::Two);
+            assert_eq!(result.palette, vec![0, 0, 0, 10, 20, 30, 10, 20, 30]);
+            assert_eq!(result.trns, vec![0, 128]);
+            assert_eq!(
+                decode(&result, 5, 1),
+                vec![10, 20, 30, 255, 0, 0, 0, 0, 0, 0, 0, 0, 10, 20, 30, 128, 10, 20, 30, 255]
+            );
+        }
+
+        #[test]
+        fn success_when_256_colors() {
+            let rgba: Vec<u8> = (0..=u8::MAX).flat_map(|v| [v, 0, 0, 255]).collect();
+
+            let result = index_rgba(&rgba, 16).unwrap();
+
+            assert_eq!(result.bit_depth, BitDepth::Eight);
+            assert!(result.trns.is_empty());
+            assert_eq!(decode(&result, 16, 16), rgba);
+        }
+
+        #[test]
+        fn failed_when_too_many_colors() {
+            let rgba: Vec<u8> = (0..=256u32)
+                .flat_map(|v| [v as u8, (v >> 8) as u8, 0, 255])
+                .collect();
+
+            assert_eq!(index_rgba(&rgba, 257), None);
+        }
+    }
+
+    mod pack_gray_samples {
+        use super::*;
+
+        #[test]
+        fn success_when_depth_2_rows_are_padded() {
+            let samples = [0, 85, 170, u8::MAX, 0, u8::MAX, u8::MAX, 0, 0, 0];
+
+            let result = pack_gray_samples(&samples, 5, BitDepth::Two);
+
+            assert_eq!(result, vec![0b00011011, 0b00000000, 0b11110000, 0b00000000]);
+        }
+
+        #[test]
+        fn success_when_depth_4_round_trips_through_reader() {
+            let samples = [0, 17, 34, 255, 136];
+
+            let result = pack_gray_samples(&samples, 5, BitDepth::Four);
+
+            assert_eq!(result, vec![0x01, 0x2f, 0x80]);
+            assert_eq!(
+                read_bytes_for_usize(&result, &BitDepth::Four)[..5],
+                [0, 1, 2, 15, 8]
+            );
+        }
+    }
+
+    mod read_bytes_depth_16 {
+        use super::*;
+
+        #[test]
+        fn success_when_valid_bytes() {
+            let buf = [0b00110110, 0b11001001, 0xff, 0x00];
+
+            let result = read_bytes_depth_16(&buf);
+
+            assert_eq!(result, vec![0b0011011011001001, 0xff00]);
+        }
+
+        #[test]
+        fn success_when_matching_readers_for_usize_and_bit_depth_8() {
+            let buf = [0x12, 0x34, 0xff, 0xff, 0x00, 0x01, 0x80, 0x00];
+            let bit_depth = BitDepth::Sixteen;
+
+            let samples = read_bytes_depth_16(&buf);
+            let for_usize = read_bytes_for_usize(&buf, &bit_depth);
+            let for_bit_depth_8 = read_bytes_for_bit_depth_8(&buf, &bit_depth);
+
+            assert_eq!(
+                for_usize,
+                samples.iter().map(|v| *v as usize).collect::<Vec<usize>>()
+            );
+            assert_eq!(for_bit_depth_8, vec![0x12, 0xff, 0x00, 0x80]);
+        }
+    }
 }
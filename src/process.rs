@@ -1,59 +1,3141 @@
-use std::{fs::File, io::BufWriter};
-
-use png::{Decoder, Encoder};
-
-use crate::{config::Config, error::MergeError, pna::merge_pna};
-
-pub(crate) fn process(config: Config) -> Result<(), MergeError> {
-    let png_file = File::open(&config.png_path)?;
-    let png_decoder = Decoder::new(png_file);
-    let mut png_reader = png_decoder.read_info()?;
-    let mut png_buf = vec![0; png_reader.output_buffer_size()];
-    let _png_output_info = png_reader.next_frame(&mut png_buf)?;
-    let png_info = png_reader.info();
-
-    let pna_file = File::open(&config.pna_path)?;
-    let pna_decoder = Decoder::new(pna_file);
-    let mut pna_reader = pna_decoder.read_info()?;
-    let mut pna_buf = vec![0; pna_reader.output_buffer_size()];
-    let _pna_output_info = pna_reader.next_frame(&mut pna_buf)?;
-    let pna_info = pna_reader.info();
-
-    let merged_buf = merge_pna(&png_buf, &png_info, &pna_buf, &pna_info)?;
-
-    let output_file = File::create(&config.output_path)?;
-    let ref mut output_buf_writer = BufWriter::new(output_file);
-    let mut output_encoder = Encoder::new(output_buf_writer, png_info.width, png_info.height);
-    output_encoder.set_color(png::ColorType::Rgba);
-    output_encoder.set_depth(png::BitDepth::Eight);
+use std::{
+    cell::Cell,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use flate2::write::ZlibEncoder;
+use png::{
+    text_metadata::{ITXtChunk, TEXtChunk, ZTXtChunk},
+    AdaptiveFilterType, ColorType, Decoder, Encoder, FilterType, Info, Limits, PixelDimensions,
+    ScaledFloat, SrgbRenderingIntent, Transformations,
+};
+
+use crate::{
+    cache,
+    checksum::{write_checksum_file, ChecksumWriter, Hasher},
+    config::{is_stdio, Config, Grid, MaskSource, Sequence, Split},
+    error::{MergeError, Warning},
+    mask::{
+        alpha_from_depth, apply_alpha_expr, cutout, edge_feather, edge_mask, gradient_alpha_mask,
+        invert, match_histogram, modulate_by_luma, opaque_edges, pack_bitmask, posterize,
+        solid_alpha_mask, threshold, vignette,
+    },
+    pna::{
+        alpha_coverage, buf_to_rgba, check_size, difference_mask, index_rgba,
+        is_effectively_grayscale, merge_alpha_mask, merge_alpha_mask_16, own_alpha_mask,
+        pack_gray_samples, pna_to_alpha_mask, restrict_mask_to_indices, row_info, split_rgba,
+        to_8_bit, AlphaMask, AlphaSource, IndexedImage,
+    },
+    resize::{downscale_box, slice_cells},
+};
+
+/// Merges and writes every output of `config`, returning the warnings to report.
+pub(crate) fn process(config: &Config) -> Result<Vec<Warning>, MergeError> {
+    if let Some(split) = &config.split {
+        split_png(config, split)?;
+        return Ok(Vec::new());
+    }
+    if config.stream {
+        with_backup(&config.output_path, config, || stream_merge(config))?;
+        if config.preserve_mtime && !config.validate_only {
+            copy_source_mtime(config, &config.output_paths())?;
+        }
+        return Ok(Vec::new());
+    }
+
+    let started_at = Instant::now();
+    let (merged_buf, png_info, alpha_mask) = merge(config)?;
+
+    if config.validate_only {
+        return Ok(Vec::new());
+    }
+
+    let mut warnings = Vec::new();
+    let bit_depth = output_bit_depth(config, &png_info);
+    let output_paths = config.output_paths();
+    if config.emit.is_empty() {
+        let indexed = match config.output_color {
+            OutputColor::Rgba => None,
+            OutputColor::Indexed => {
+                let indexed = index_rgba(&merged_buf, png_info.width);
+                if indexed.is_none() {
+                    warnings.push(Warning::NotIndexed(config.output_path.clone()));
+                }
+                indexed
+            }
+        };
+        write_merged_png(
+            &config.output_path,
+            &png_info,
+            bit_depth,
+            &merged_buf,
+            indexed.as_ref(),
+            config,
+        )?;
+    }
+    if let Some(grid) = &config.grid {
+        write_grid_cells(config, grid, &merged_buf, &png_info, bit_depth)?;
+    }
+
+    // Thumbnail and channels are always 8-bit.
+    let (merged_buf, merged_buf_16) = match bit_depth {
+        png::BitDepth::Sixteen => (to_8_bit(&merged_buf), Some(merged_buf)),
+        _ => (merged_buf, None),
+    };
+
+    for (path, depth) in output_paths.iter().zip(&config.emit) {
+        let buf = match depth {
+            png::BitDepth::Sixteen => merged_buf_16
+                .as_deref()
+                .expect("--emit 16 always merges in 16 bits"),
+            _ => &merged_buf,
+        };
+        write_merged_png(path, &png_info, *depth, buf, None, config)?;
+    }
+
+    if config.preserve_mtime {
+        copy_source_mtime(config, &output_paths)?;
+    }
+
+    if let Some(thumbnail) = &config.thumbnail {
+        let thumbnail_buf = downscale_box(
+            &merged_buf,
+            png_info.width,
+            png_info.height,
+            thumbnail.width,
+            thumbnail.height,
+        );
+        write_png(
+            &thumbnail.path,
+            thumbnail.width,
+            thumbnail.height,
+            png::ColorType::Rgba,
+            png::BitDepth::Eight,
+            &thumbnail_buf,
+            config,
+        )?;
+    }
+
+    if let Some(dir) = &config.split_channels {
+        let stem = config
+            .png_path
+            .file_stem()
+            .expect("It's already checked that png file path is valid")
+            .to_string_lossy();
+        for (i, suffix) in ["r", "g", "b", "a"].iter().enumerate() {
+            let channel: Vec<u8> = merged_buf.iter().skip(i).step_by(4).copied().collect();
+            write_png(
+                &dir.join(format!("{}_{}.png", stem, suffix)),
+                png_info.width,
+                png_info.height,
+                png::ColorType::Grayscale,
+                config.split_depth,
+                &pack_gray_samples(&channel, png_info.width, config.split_depth),
+                config,
+            )?;
+        }
+    }
+
+    if let Some(bitmask) = &config.bitmask {
+        write_bitmask(
+            &bitmask.path,
+            &alpha_mask,
+            bitmask.threshold,
+            config.buffer_size,
+        )?;
+    }
+
+    if let Some(stats_csv) = &config.stats_csv {
+        let stats = MergeStats {
+            png_path: &config.png_path,
+            width: png_info.width,
+            height: png_info.height,
+            coverage: alpha_coverage(&alpha_mask),
+            output_bytes: std::fs::metadata(&output_paths[0])?.len(),
+            duration: started_at.elapsed(),
+        };
+        append_stats_csv(stats_csv, &stats)?;
+    }
+
+    Ok(warnings)
+}
+
+/// Writes each cell of the merged png as `PNG_NAME_INDEX.png` in the directory of the output.
+fn write_grid_cells(
+    config: &Config,
+    grid: &Grid,
+    merged_buf: &[u8],
+    png_info: &Info,
+    bit_depth: png::BitDepth,
+) -> Result<(), MergeError> {
+    let (cell_width, cell_height) = grid.layout.cell_size(png_info.width, png_info.height);
+    let pixel_size = match bit_depth {
+        png::BitDepth::Sixteen => 8,
+        _ => 4,
+    };
+    let dir = config.output_path.parent().unwrap_or(Path::new(""));
+    let stem = config
+        .png_path
+        .file_stem()
+        .expect("It's already checked that png file path is valid")
+        .to_string_lossy();
+
+    let cells = slice_cells(
+        merged_buf,
+        png_info.width,
+        png_info.height,
+        pixel_size,
+        cell_width,
+        cell_height,
+        grid.partial,
+    );
+    for (index, cell) in cells {
+        write_png(
+            &dir.join(format!("{}_{}.png", stem, index)),
+            cell_width,
+            cell_height,
+            png::ColorType::Rgba,
+            bit_depth,
+            &cell,
+            config,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Result of comparing a merge with the output already on disk.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ExistingOutput {
+    Missing,
+    Identical,
+    WouldChange,
+}
+
+/// Merges in memory and compares the pixels with the existing output without writing anything.
+pub(crate) fn diff_existing(config: &Config) -> Result<ExistingOutput, MergeError> {
+    if !config.output_path.exists() {
+        return Ok(ExistingOutput::Missing);
+    }
+
+    let (merged_buf, png_info, _) = merge(config)?;
+
+    let output_file = open_png(&config.output_path)?;
+    let (output_buf, output_info) =
+        decode_png(BufReader::with_capacity(config.buffer_size, output_file))?;
+    if (output_info.width, output_info.height) != (png_info.width, png_info.height) {
+        return Ok(ExistingOutput::WouldChange);
+    }
+
+    if buf_to_rgba(&output_buf, &output_info)? == merged_buf {
+        Ok(ExistingOutput::Identical)
+    } else {
+        Ok(ExistingOutput::WouldChange)
+    }
+}
+
+/// Decodes the png, derives and adjusts the mask, and returns the merged rgba
+/// with the png's info and the final mask.
+pub(crate) fn merge(config: &Config) -> Result<(Vec<u8>, PngInfo, AlphaMask), MergeError> {
+    if let (Some(color), MaskSource::Pna(pna_path)) =
+        (config.placeholder_color, &config.mask_source)
+    {
+        // The placeholder takes the size of the pna, so the mask is loaded first.
+        let alpha_mask = load_alpha_mask(pna_path, config)?;
+        let (png_buf, png_info) = placeholder_png(alpha_mask.width, alpha_mask.height, color);
+        return merge_decoded(config, &png_buf, png_info.into(), |_| Ok(alpha_mask));
+    }
+
+    let png_file = open_input_png(config)?;
+    let (png_buf, png_info) =
+        decode_input_png(BufReader::with_capacity(config.buffer_size, png_file))?;
+    log_decoded(config, &config.png_path, &png_info);
+
+    merge_decoded(config, &png_buf, png_info, |pna_path| {
+        load_alpha_mask(pna_path, config)
+    })
+}
+
+/// Returns a solid 8-bit rgb image filled with `color`.
+fn placeholder_png(width: u32, height: u32, color: [u8; 3]) -> (Vec<u8>, Info<'static>) {
+    let mut info = Info::with_size(width, height);
+    info.color_type = ColorType::Rgb;
+    info.bit_depth = png::BitDepth::Eight;
+    let buf = color.repeat(width as usize * height as usize);
+
+    (buf, info)
+}
+
+/// Bit depth of the merged rgba: 16 with `--keep-16-bit` for a 16-bit png or with
+/// `--emit 16`, otherwise 8.
+pub(crate) fn output_bit_depth(config: &Config, png_info: &Info) -> png::BitDepth {
+    if config.keep_16_bit && png_info.bit_depth == png::BitDepth::Sixteen
+        || config.emit.contains(&png::BitDepth::Sixteen)
+    {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    }
+}
+
+/// Merges each of `jobs` as a frame of the animated png of `sequence`, in order, with
+/// the metadata and output options of the first. Every frame must have the size of the first.
+pub(crate) fn merge_sequence(jobs: &[Config], sequence: &Sequence) -> Result<(), MergeError> {
+    let mut frames: Vec<(Vec<u8>, PngInfo)> = Vec::with_capacity(jobs.len());
+    for config in jobs {
+        let (merged_buf, png_info, _) = merge(config)?;
+        if let Some((_, first_info)) = frames.first() {
+            let expected = (first_info.width, first_info.height);
+            let actual = (png_info.width, png_info.height);
+            if actual != expected {
+                return Err(MergeError::FrameSizeMismatch {
+                    path: config.png_path.clone(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+        frames.push((merged_buf, png_info));
+    }
+
+    let (Some(config), Some((_, png_info))) = (jobs.first(), frames.first()) else {
+        return Ok(());
+    };
+    if config.validate_only {
+        return Ok(());
+    }
+
+    with_backup(&sequence.path, config, || {
+        write_sequence(&sequence.path, &frames, png_info, sequence, config)
+    })
+}
+
+/// Encodes the merged `frames` of a sequence as an animated png to `path`.
+fn write_sequence(
+    path: &Path,
+    frames: &[(Vec<u8>, PngInfo)],
+    png_info: &PngInfo,
+    sequence: &Sequence,
+    config: &Config,
+) -> Result<(), MergeError> {
+    let metadata = Metadata::for_output(png_info, config)?;
+    let mut hasher = config.checksum.map(|v| v.hasher());
+    let output = ChecksumWriter {
+        inner: create_output(path)?,
+        hasher: hasher.as_mut(),
+    };
+    let mut output_encoder = png_encoder(
+        BufWriter::with_capacity(config.buffer_size, output),
+        png_info.width,
+        png_info.height,
+        png::ColorType::Rgba,
+        output_bit_depth(config, png_info),
+        &metadata,
+        config,
+    )?;
+    // The first frame is the default image too, and the animation loops forever.
+    output_encoder.set_animated(frames.len() as u32, 0)?;
+    let mut output_writer = write_png_header(output_encoder, &metadata)?;
+    for (i, (merged_buf, _)) in frames.iter().enumerate() {
+        output_writer.set_frame_delay(sequence.delay(i), 1000)?;
+        output_writer.write_image_data(merged_buf)?;
+    }
+    output_writer.finish()?;
+
+    write_checksum(path, hasher, config)
+}
+
+/// Same as `process` for a pna mask, but one row at a time: only a few rows of the
+/// png, the pna and the output are in memory, however large the images are.
+fn stream_merge(config: &Config) -> Result<(), MergeError> {
+    let MaskSource::Pna(pna_path) = &config.mask_source else {
+        unreachable!("--stream conflicts with the generated masks");
+    };
+    let png_file = open_input_png(config)?;
+    let mut png_rows = RowReader::new(
+        BufReader::with_capacity(config.buffer_size, png_file),
+        config.decoder_limit,
+    )?;
+    let pna_file = open_png(pna_path)?;
+    let mut pna_rows = RowReader::new(
+        BufReader::with_capacity(config.buffer_size, pna_file),
+        config.decoder_limit,
+    )?;
+    let png_info = png_rows.png_info();
+    let pna_info = pna_rows.info().clone();
+    log_decoded(config, &config.png_path, &png_info);
+    log_decoded(config, pna_path, &pna_info);
+
+    let png_size = (png_info.width, png_info.height);
+    if let Some(expected) = config.assert_dimensions {
+        if expected != png_size {
+            return Err(MergeError::UnexpectedDimensions {
+                expected,
+                actual: png_size,
+            });
+        }
+    }
+    let pna_size = (pna_info.width, pna_info.height);
+    if png_size != pna_size {
+        return Err(MergeError::SizePngAndPnaAreDifferent {
+            png: png_size,
+            pna: pna_size,
+        });
+    }
+
+    let bit_depth = output_bit_depth(config, &png_info);
+    let merge_alpha = match bit_depth {
+        png::BitDepth::Sixteen => merge_alpha_mask_16,
+        _ => merge_alpha_mask,
+    };
+    let output: Box<dyn Write> = match config.validate_only {
+        true => Box::new(std::io::sink()),
+        false => create_output(&config.output_path)?,
+    };
+    let mut hasher = match config.validate_only {
+        true => None,
+        false => config.checksum.map(|v| v.hasher()),
+    };
+    let output = ChecksumWriter {
+        inner: output,
+        hasher: hasher.as_mut(),
+    };
+    let mut png_writer = png_writer(
+        BufWriter::with_capacity(config.buffer_size, output),
+        png_info.width,
+        png_info.height,
+        png::ColorType::Rgba,
+        bit_depth,
+        &Metadata::for_output(&png_info, config)?,
+        config,
+    )?;
+    let mut output_writer = png_writer.stream_writer()?;
+
+    let merge_options = config.merge_options();
+
+    let png_row_info = row_info(&png_info);
+    let pna_row_info = row_info(&pna_info);
+    let mut png_row = Vec::new();
+    let mut pna_row = Vec::new();
+    while png_rows.next_row(&mut png_row)? {
+        if !pna_rows.next_row(&mut pna_row)? {
+            return Err(MergeError::LessDataSize);
+        }
+        let mut alpha_mask = decoded_pna_to_alpha_mask(&pna_row, &pna_row_info, config)?;
+        if let Some(levels) = config.posterize_alpha {
+            alpha_mask = posterize(&alpha_mask, levels);
+        }
+        if let Some(v) = config.threshold {
+            alpha_mask = threshold(&alpha_mask, v);
+        }
+        let merged_row = merge_alpha(
+            &png_row,
+            &png_row_info,
+            &alpha_mask,
+            &merge_options,
+            config.packing,
+        )?;
+        output_writer.write_all(&merged_row)?;
+    }
+    output_writer.finish()?;
+    png_writer.finish()?;
+
+    write_checksum(&config.output_path, hasher, config)
+}
+
+/// Rows of a png as it is decoded, with no transformations. An interlaced png is
+/// decoded whole at the first row, as its rows come in seven passes.
+struct RowReader<R: Read> {
+    reader: png::Reader<IccpTap<R>>,
+    iccp_chunk: Option<Vec<u8>>,
+    frame: Option<Frame>,
+    limit: Option<usize>,
+}
+
+/// A whole decoded interlaced frame and the row to be read next.
+struct Frame {
+    buf: Vec<u8>,
+    line_size: usize,
+    next: usize,
+}
+
+impl<R: Read> RowReader<R> {
+    /// With `limit`, the decoder allocates at most that many bytes, and an interlaced
+    /// frame larger than that fails rather than being decoded whole.
+    fn new(reader: R, limit: Option<usize>) -> Result<Self, MergeError> {
+        let iccp_chunk = Rc::new(Cell::new(None));
+        let mut decoder = png_decoder(IccpTap::new(reader, Rc::clone(&iccp_chunk)));
+        if let Some(bytes) = limit {
+            decoder.set_limits(Limits { bytes });
+        }
+        // iCCP comes before the image data, so the header has passed it.
+        let reader = decoder.read_info()?;
+
+        Ok(Self {
+            reader,
+            iccp_chunk: iccp_chunk.take(),
+            frame: None,
+            limit,
+        })
+    }
+
+    fn info(&self) -> &Info<'static> {
+        self.reader.info()
+    }
+
+    fn png_info(&self) -> PngInfo {
+        PngInfo {
+            info: self.info().clone(),
+            iccp_chunk: self.iccp_chunk.clone(),
+        }
+    }
+
+    /// Copies the next row into `row`. Returns false after the last row.
+    fn next_row(&mut self, row: &mut Vec<u8>) -> Result<bool, MergeError> {
+        row.clear();
+        if !self.reader.info().interlaced {
+            return Ok(match self.reader.next_row()? {
+                Some(v) => {
+                    row.extend_from_slice(v.data());
+                    true
+                }
+                None => false,
+            });
+        }
+
+        if self.frame.is_none() {
+            if self
+                .limit
+                .is_some_and(|v| self.reader.output_buffer_size() > v)
+            {
+                return Err(png::DecodingError::LimitsExceeded.into());
+            }
+            let mut buf = vec![0; self.reader.output_buffer_size()];
+            let output_info = self.reader.next_frame(&mut buf)?;
+            buf.truncate(output_info.buffer_size());
+            self.frame = Some(Frame {
+                buf,
+                line_size: output_info.line_size,
+                next: 0,
+            });
+        }
+        let frame = self.frame.as_mut().expect("It's decoded above");
+        let start = frame.next * frame.line_size;
+        if start >= frame.buf.len() {
+            return Ok(false);
+        }
+        let end = frame.buf.len().min(start + frame.line_size);
+        row.extend_from_slice(&frame.buf[start..end]);
+        frame.next += 1;
+
+        Ok(true)
+    }
+}
+
+/// Same as `merge` for a png decoded by the caller. `load_pna` is called with the
+/// pna path when the mask source is pna.
+pub(crate) fn merge_decoded(
+    config: &Config,
+    png_buf: &[u8],
+    png_info: PngInfo,
+    load_pna: impl FnOnce(&Path) -> Result<AlphaMask, MergeError>,
+) -> Result<(Vec<u8>, PngInfo, AlphaMask), MergeError> {
+    if let Some(expected) = config.assert_dimensions {
+        let actual = (png_info.width, png_info.height);
+        if expected != actual {
+            return Err(MergeError::UnexpectedDimensions { expected, actual });
+        }
+    }
+
+    let mut alpha_mask = match &config.mask_source {
+        MaskSource::Pna(pna_path) => load_pna(pna_path)?,
+        MaskSource::Gradient(gradient) => {
+            gradient_alpha_mask(png_info.width, png_info.height, gradient)
+        }
+        MaskSource::Solid(alpha) => solid_alpha_mask(png_info.width, png_info.height, *alpha),
+        MaskSource::Edge(width) => edge_mask(
+            &own_alpha_mask(
+                png_buf,
+                &png_info,
+                config.luma_weights,
+                config.strict_data_size,
+            )?,
+            *width,
+        ),
+        MaskSource::Plate(plate) => {
+            let plate_file = open_png(&plate.path)?;
+            let (plate_buf, plate_info) =
+                decode_png(BufReader::with_capacity(config.buffer_size, plate_file))?;
+            difference_mask(
+                png_buf,
+                &png_info,
+                &plate_buf,
+                &plate_info,
+                plate.threshold,
+                config.strict_data_size,
+            )?
+        }
+    };
+
+    if let Some((near, far)) = config.alpha_from_depth {
+        alpha_mask = alpha_from_depth(&alpha_mask, near, far);
+    }
+
+    // Mask adjustments are applied in a fixed order:
+    // histogram match, vignette, luma modulation, cutout, edge feather, posterize,
+    // palette indices, opaque edges, alpha expression, threshold.
+    if let Some(reference_path) = &config.match_histogram {
+        let reference = load_alpha_mask(reference_path, config)?;
+        alpha_mask = match_histogram(&alpha_mask, &reference);
+    }
+    if let Some(v) = config.vignette {
+        alpha_mask = vignette(&alpha_mask, v);
+    }
+    if let Some(v) = config.modulate_by_luma {
+        check_size(&png_info, &alpha_mask)?;
+        let luma = pna_to_alpha_mask(
+            png_buf,
+            &png_info,
+            AlphaSource::Gray,
+            config.luma_weights,
+            config.strict_data_size,
+        )?;
+        alpha_mask = modulate_by_luma(&alpha_mask, &luma, v);
+    }
+
+    if let Some(v) = &config.cutout {
+        alpha_mask = cutout(&alpha_mask, v.threshold, v.aa);
+    }
+
+    if let Some(radius) = config.edge_feather {
+        alpha_mask = edge_feather(&alpha_mask, radius);
+    }
+
+    if let Some(levels) = config.posterize_alpha {
+        alpha_mask = posterize(&alpha_mask, levels);
+    }
+
+    if let Some(indices) = &config.mask_indices {
+        alpha_mask =
+            restrict_mask_to_indices(png_buf, &png_info, &alpha_mask, |i| indices.contains(i));
+    }
+
+    if let Some(rings) = config.opaque_edges {
+        alpha_mask = opaque_edges(&alpha_mask, rings);
+    }
+
+    if let Some(expr) = &config.alpha_expr {
+        check_size(&png_info, &alpha_mask)?;
+        let luma = pna_to_alpha_mask(
+            png_buf,
+            &png_info,
+            AlphaSource::Gray,
+            config.luma_weights,
+            config.strict_data_size,
+        )?;
+        alpha_mask = apply_alpha_expr(&alpha_mask, &luma, expr);
+    }
+
+    if let Some(v) = config.threshold {
+        alpha_mask = threshold(&alpha_mask, v);
+    }
+
+    if let Some(min_coverage) = config.min_coverage {
+        let coverage = alpha_coverage(&alpha_mask);
+        if coverage < min_coverage {
+            return Err(MergeError::CoverageTooLow {
+                coverage,
+                min_coverage,
+            });
+        }
+    }
+
+    let merge_alpha = match output_bit_depth(config, &png_info) {
+        png::BitDepth::Sixteen => merge_alpha_mask_16,
+        _ => merge_alpha_mask,
+    };
+    let merged_buf = merge_alpha(
+        png_buf,
+        &png_info,
+        &alpha_mask,
+        &config.merge_options(),
+        config.packing,
+    )?;
+
+    Ok((merged_buf, png_info, alpha_mask))
+}
+
+/// Sets the modification time of each of `targets` to the newest one of the png and pna.
+fn copy_source_mtime(config: &Config, targets: &[PathBuf]) -> Result<(), MergeError> {
+    let mut sources = vec![config.png_path.as_path()];
+    if let MaskSource::Pna(pna_path) = &config.mask_source {
+        sources.push(pna_path);
+    }
+    for path in targets {
+        copy_newest_mtime(&sources, path)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the modification time of `target` to the newest one among `sources`.
+fn copy_newest_mtime(sources: &[&Path], target: &Path) -> Result<(), MergeError> {
+    let mut newest = None;
+    for path in sources {
+        newest = newest.max(Some(std::fs::metadata(path)?.modified()?));
+    }
+
+    if let Some(modified) = newest {
+        OpenOptions::new()
+            .write(true)
+            .open(target)?
+            .set_modified(modified)?;
+    }
+
+    Ok(())
+}
+
+/// One row of `--stats-csv`.
+struct MergeStats<'a> {
+    png_path: &'a Path,
+    width: u32,
+    height: u32,
+    coverage: f64,
+    output_bytes: u64,
+    duration: Duration,
+}
+
+const STATS_CSV_HEADER: &str = "input_path,width,height,alpha_coverage,output_bytes,duration_ms\n";
+
+/// Appends a row to the csv, writing the header first when the file is new or empty.
+/// The row is written with a single call so that concurrent runs don't interleave lines.
+fn append_stats_csv(path: &Path, stats: &MergeStats) -> Result<(), MergeError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    let mut row = String::new();
+    if file.metadata()?.len() == 0 {
+        row.push_str(STATS_CSV_HEADER);
+    }
+    row.push_str(&format!(
+        "{},{},{},{:.2},{},{}\n",
+        csv_field(&stats.png_path.to_string_lossy()),
+        stats.width,
+        stats.height,
+        stats.coverage,
+        stats.output_bytes,
+        stats.duration.as_millis()
+    ));
+    file.write_all(row.as_bytes())?;
+
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Writes width and height as little-endian u32, then the rows packed by `pack_bitmask`.
+fn write_bitmask(
+    path: &Path,
+    alpha_mask: &AlphaMask,
+    threshold: u8,
+    buffer_size: usize,
+) -> Result<(), MergeError> {
+    let output_file = create_output_file(path)?;
+    let mut output_buf_writer = BufWriter::with_capacity(buffer_size, output_file);
+    output_buf_writer.write_all(&alpha_mask.width.to_le_bytes())?;
+    output_buf_writer.write_all(&alpha_mask.height.to_le_bytes())?;
+    output_buf_writer.write_all(&pack_bitmask(alpha_mask, threshold))?;
+    output_buf_writer.flush()?;
+
+    Ok(())
+}
+
+/// Color type of the merged png.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum OutputColor {
+    /// 8-bit rgba (16-bit with `--keep-16-bit`).
+    #[default]
+    Rgba,
+    /// A palette of the colors of the merged png with their alpha in tRNS, or rgba when
+    /// it has more than 256.
+    Indexed,
+}
+
+/// zlib compression level of written pngs.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CompressionLevel {
+    Default,
+    Fast,
+    Best,
+}
+
+impl From<CompressionLevel> for png::Compression {
+    fn from(v: CompressionLevel) -> Self {
+        match v {
+            CompressionLevel::Default => Self::Default,
+            CompressionLevel::Fast => Self::Fast,
+            CompressionLevel::Best => Self::Best,
+        }
+    }
+}
+
+/// Ancillary chunk of the input png that `--strip-chunks` leaves out of the output.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StrippedChunk {
+    Text,
+    Phys,
+    Gama,
+    Srgb,
+    Iccp,
+}
+
+/// Row filter of written pngs. `Adaptive` picks the best filter for each row.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FilterMode {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive,
+}
+
+impl FilterMode {
+    fn apply<W: Write>(self, encoder: &mut Encoder<W>) {
+        let filter = match self {
+            Self::None => FilterType::NoFilter,
+            Self::Sub => FilterType::Sub,
+            Self::Up => FilterType::Up,
+            Self::Avg => FilterType::Avg,
+            Self::Paeth => FilterType::Paeth,
+            Self::Adaptive => {
+                encoder.set_adaptive_filter(AdaptiveFilterType::Adaptive);
+                return;
+            }
+        };
+        encoder.set_adaptive_filter(AdaptiveFilterType::NonAdaptive);
+        encoder.set_filter(filter);
+    }
+}
+
+/// Ancillary chunks of the input png that are carried over to the output.
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    pub latin1_text: Vec<TEXtChunk>,
+    pub compressed_latin1_text: Vec<ZTXtChunk>,
+    pub utf8_text: Vec<ITXtChunk>,
+    /// Data of the iCCP chunk: the profile name and the compressed ICC profile.
+    pub iccp_chunk: Option<Vec<u8>>,
+    pub srgb: Option<SrgbRenderingIntent>,
+    pub source_gamma: Option<ScaledFloat>,
+    /// Physical size of a pixel from pHYs. Left out of the output when `None`.
+    pub pixel_dims: Option<PixelDimensions>,
+}
+
+impl Metadata {
+    pub(crate) fn of(info: &PngInfo) -> Self {
+        Self {
+            latin1_text: info.uncompressed_latin1_text.clone(),
+            compressed_latin1_text: info.compressed_latin1_text.clone(),
+            utf8_text: info.utf8_text.clone(),
+            // Copied as it was read, unless the decoder couldn't read the profile from it.
+            iccp_chunk: info
+                .iccp_chunk
+                .clone()
+                .filter(|_| info.icc_profile.is_some()),
+            srgb: info.srgb,
+            source_gamma: info.source_gamma,
+            pixel_dims: info.pixel_dims,
+        }
+    }
+
+    /// Metadata of the input png without the chunks of `--strip-chunks`, with the profile
+    /// of `--icc` when it is given.
+    pub(crate) fn for_output(info: &PngInfo, config: &Config) -> Result<Self, MergeError> {
+        let mut metadata = Self::of(info);
+        for chunk in &config.strip_chunks {
+            match chunk {
+                StrippedChunk::Text => {
+                    metadata.latin1_text.clear();
+                    metadata.compressed_latin1_text.clear();
+                    metadata.utf8_text.clear();
+                }
+                StrippedChunk::Phys => metadata.pixel_dims = None,
+                StrippedChunk::Gama => metadata.source_gamma = None,
+                StrippedChunk::Srgb => metadata.srgb = None,
+                StrippedChunk::Iccp => metadata.iccp_chunk = None,
+            }
+        }
+        // A profile given with --icc is written even when the input's is stripped.
+        if let Some(profile) = &config.icc_profile {
+            metadata.iccp_chunk = Some(iccp_chunk_data(profile)?);
+        }
+        Ok(metadata)
+    }
+}
+
+/// Data of an iCCP chunk: the profile name, the compression method (0 is zlib) and the
+/// compressed profile.
+fn iccp_chunk_data(profile: &[u8]) -> Result<Vec<u8>, MergeError> {
+    let mut data = b"ICC profile\0\0".to_vec();
+    let mut encoder = ZlibEncoder::new(&mut data, flate2::Compression::default());
+    encoder.write_all(profile)?;
+    encoder.finish()?;
+
+    Ok(data)
+}
+
+pub(crate) fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    config: &Config,
+) -> Result<(), MergeError> {
+    write_png_with_metadata(
+        path,
+        width,
+        height,
+        color_type,
+        bit_depth,
+        buf,
+        &Metadata::default(),
+        config,
+    )
+}
+
+/// Same as `write_png`, also writing the chunks of `metadata`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_png_with_metadata(
+    path: &Path,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<(), MergeError> {
+    let output_file = create_output(path)?;
+    encode_png(
+        BufWriter::with_capacity(config.buffer_size, output_file),
+        width,
+        height,
+        color_type,
+        bit_depth,
+        buf,
+        metadata,
+        config,
+    )
+}
+
+/// Writes the merged rgba png with the metadata of the input. With `--max-output-bytes`,
+/// it is encoded in memory first and nothing is written when it is over the limit.
+/// With `--checksum`, the hash of the written bytes goes to a sidecar file.
+pub(crate) fn write_merged_png(
+    path: &Path,
+    png_info: &PngInfo,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    indexed: Option<&IndexedImage>,
+    config: &Config,
+) -> Result<(), MergeError> {
+    with_backup(path, config, || {
+        encode_merged_png_to(path, png_info, bit_depth, buf, indexed, config)
+    })
+}
+
+fn encode_merged_png_to(
+    path: &Path,
+    png_info: &PngInfo,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    indexed: Option<&IndexedImage>,
+    config: &Config,
+) -> Result<(), MergeError> {
+    if config.max_output_bytes.is_none() {
+        let metadata = Metadata::for_output(png_info, config)?;
+        let mut hasher = config.checksum.map(|v| v.hasher());
+        let output = ChecksumWriter {
+            inner: create_output(path)?,
+            hasher: hasher.as_mut(),
+        };
+        encode_merged_png(
+            BufWriter::with_capacity(config.buffer_size, output),
+            png_info,
+            bit_depth,
+            buf,
+            indexed,
+            &metadata,
+            config,
+        )?;
+        return write_checksum(path, hasher, config);
+    }
+
+    // Encoded in memory first, so nothing is written when it is too large.
+    let encoded = encode_merged_png_to_vec(png_info, bit_depth, buf, indexed, config)?;
+    write_encoded(path, &encoded, config)
+}
+
+/// Encodes the merged png in memory, failing when it is larger than `--max-output-bytes`.
+pub(crate) fn encode_merged_png_to_vec(
+    png_info: &PngInfo,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    indexed: Option<&IndexedImage>,
+    config: &Config,
+) -> Result<Vec<u8>, MergeError> {
+    let metadata = Metadata::for_output(png_info, config)?;
+    let mut encoded = Vec::new();
+    encode_merged_png(
+        &mut encoded,
+        png_info,
+        bit_depth,
+        buf,
+        indexed,
+        &metadata,
+        config,
+    )?;
+    if let Some(max) = config.max_output_bytes {
+        let size = encoded.len() as u64;
+        if size > max {
+            return Err(MergeError::OutputTooLarge { size, max });
+        }
+    }
+
+    Ok(encoded)
+}
+
+/// Writes already encoded bytes to the output path, backing up an existing file
+/// with `--backup` and writing its `--checksum`.
+#[cfg(feature = "archive")]
+pub(crate) fn write_output(path: &Path, encoded: &[u8], config: &Config) -> Result<(), MergeError> {
+    with_backup(path, config, || write_encoded(path, encoded, config))
+}
+
+fn write_encoded(path: &Path, encoded: &[u8], config: &Config) -> Result<(), MergeError> {
+    create_output(path)?.write_all(encoded)?;
+    let mut hasher = config.checksum.map(|v| v.hasher());
+    if let Some(hasher) = &mut hasher {
+        hasher.update(encoded);
+    }
+
+    write_checksum(path, hasher, config)
+}
+
+/// Encodes the merged rgba `buf`, or `indexed` in its place for `--output indexed`.
+fn encode_merged_png<W: Write>(
+    writer: W,
+    png_info: &Info,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    indexed: Option<&IndexedImage>,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<(), MergeError> {
+    let Some(indexed) = indexed else {
+        return encode_png(
+            writer,
+            png_info.width,
+            png_info.height,
+            png::ColorType::Rgba,
+            bit_depth,
+            buf,
+            metadata,
+            config,
+        );
+    };
+
+    let mut output_encoder = png_encoder(
+        writer,
+        png_info.width,
+        png_info.height,
+        png::ColorType::Indexed,
+        indexed.bit_depth,
+        metadata,
+        config,
+    )?;
+    output_encoder.set_palette(&indexed.palette[..]);
+    if !indexed.trns.is_empty() {
+        output_encoder.set_trns(&indexed.trns[..]);
+    }
+    let mut output_writer = write_png_header(output_encoder, metadata)?;
+    output_writer.write_image_data(&indexed.data)?;
+    output_writer.finish()?;
+
+    Ok(())
+}
+
+/// Writes the `--checksum` sidecar of `path` from the hasher the png went through.
+fn write_checksum(path: &Path, hasher: Option<Hasher>, config: &Config) -> Result<(), MergeError> {
+    let (Some(algorithm), Some(hasher)) = (config.checksum, hasher) else {
+        return Ok(());
+    };
+    write_checksum_file(path, algorithm, &hasher.finalize_hex())?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_png<W: Write>(
+    writer: W,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    buf: &[u8],
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<(), MergeError> {
+    let mut output_writer = png_writer(
+        writer, width, height, color_type, bit_depth, metadata, config,
+    )?;
+    output_writer.write_image_data(buf)?;
+    output_writer.finish()?;
+
+    Ok(())
+}
+
+/// Starts a png with the header and the metadata chunks, ready for the image data.
+fn png_writer<W: Write>(
+    writer: W,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<png::Writer<W>, MergeError> {
+    let output_encoder = png_encoder(
+        writer, width, height, color_type, bit_depth, metadata, config,
+    )?;
+    write_png_header(output_encoder, metadata)
+}
+
+/// Encoder with the output options and the pixel size and color space of `metadata`,
+/// before the header is written.
+fn png_encoder<'a, W: Write>(
+    writer: W,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<Encoder<'a, W>, MergeError> {
+    let mut info = Info::with_size(width, height);
+    // Given to the encoder rather than written after the header, so that iCCP comes
+    // before the PLTE of an indexed png as the spec requires. The png version this is
+    // pinned to writes the field as the chunk data, without compressing it again.
+    info.icc_profile = metadata.iccp_chunk.clone().map(Into::into);
+    let mut output_encoder = Encoder::with_info(writer, info)?;
+    output_encoder.set_color(color_type);
+    output_encoder.set_depth(bit_depth);
+    // Left unset, the encoder keeps its own defaults.
+    if let Some(compression) = config.compression {
+        output_encoder.set_compression(compression.into());
+    }
+    if let Some(filter) = config.filter {
+        filter.apply(&mut output_encoder);
+    }
+    output_encoder.set_pixel_dims(metadata.pixel_dims);
+    // An ICC profile overrides sRGB, which overrides gAMA. The encoder writes the gAMA
+    // that sRGB implies along with it, so the given one is dropped then.
+    match (&metadata.iccp_chunk, metadata.srgb, metadata.source_gamma) {
+        (None, Some(srgb), _) => output_encoder.set_srgb(srgb),
+        (_, _, Some(gamma)) => output_encoder.set_source_gamma(gamma),
+        _ => {}
+    }
+
+    Ok(output_encoder)
+}
+
+/// Writes the header and the chunks of `metadata` that come after it.
+fn write_png_header<W: Write>(
+    output_encoder: Encoder<W>,
+    metadata: &Metadata,
+) -> Result<png::Writer<W>, MergeError> {
     let mut output_writer = output_encoder.write_header()?;
-    output_writer.write_image_data(&merged_buf)?;
+    // Chunks are written as they were read, so compressed text stays compressed.
+    for chunk in &metadata.latin1_text {
+        output_writer.write_text_chunk(chunk)?;
+    }
+    for chunk in &metadata.compressed_latin1_text {
+        output_writer.write_text_chunk(chunk)?;
+    }
+    for chunk in &metadata.utf8_text {
+        output_writer.write_text_chunk(chunk)?;
+    }
+
+    Ok(output_writer)
+}
+
+/// Runs `write` for the output at `path`, first moving an existing file aside to
+/// `backup_path(path)` with `--backup`. When `write` fails the backup is moved back,
+/// so a failed merge never loses the previous output.
+fn with_backup(
+    path: &Path,
+    config: &Config,
+    write: impl FnOnce() -> Result<(), MergeError>,
+) -> Result<(), MergeError> {
+    if !config.backup || config.validate_only || is_stdio(path) || !path.is_file() {
+        return write();
+    }
+    let backup = backup_path(path);
+    std::fs::rename(path, &backup)?;
+    let result = write();
+    if result.is_err() {
+        // The partial output may not exist yet, and if restoring fails the backup
+        // stays where it is: the error of `write` is the one worth reporting.
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::rename(&backup, path);
+    }
+    result
+}
+
+/// `NAME.bak` beside `path`, or `NAME.bak.N` with the first free N from 1.
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".bak");
+    let first = PathBuf::from(name);
+    if !first.exists() {
+        return first;
+    }
+    (1..)
+        .map(|n| {
+            let mut name = first.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        })
+        .find(|v| !v.exists())
+        .expect("some numbered backup is free")
+}
+
+/// Opens the output file, or stdout for `-`.
+fn create_output(path: &Path) -> Result<Box<dyn Write>, MergeError> {
+    if is_stdio(path) {
+        return Ok(Box::new(std::io::stdout().lock()));
+    }
+    Ok(Box::new(create_output_file(path)?))
+}
+
+fn create_output_file(path: &Path) -> Result<File, MergeError> {
+    File::create(path).map_err(|e| match e.kind() {
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+            MergeError::OutputPermissionDenied(path.to_path_buf())
+        }
+        _ => MergeError::Io(e),
+    })
+}
+
+/// Writes the colors of the input png as rgb to the output path and its alpha as
+/// a grayscale pna, the reverse of merging.
+fn split_png(config: &Config, split: &Split) -> Result<(), MergeError> {
+    let png_file = open_input_png(config)?;
+    let (png_buf, png_info) =
+        decode_input_png(BufReader::with_capacity(config.buffer_size, png_file))?;
+    log_decoded(config, &config.png_path, &png_info);
+    let rgba = buf_to_rgba(&png_buf, &png_info)?;
+    let (rgb, alpha) = split_rgba(&rgba, split.background);
+
+    if config.validate_only {
+        return Ok(());
+    }
+
+    with_backup(&config.output_path, config, || {
+        write_png_with_metadata(
+            &config.output_path,
+            png_info.width,
+            png_info.height,
+            png::ColorType::Rgb,
+            png::BitDepth::Eight,
+            &rgb,
+            &Metadata::for_output(&png_info, config)?,
+            config,
+        )
+    })?;
+    with_backup(&split.pna_path, config, || {
+        write_png(
+            &split.pna_path,
+            png_info.width,
+            png_info.height,
+            png::ColorType::Grayscale,
+            png::BitDepth::Eight,
+            &alpha,
+            config,
+        )
+    })
+}
+
+/// Returns decoded properties of the input png as a single line of JSON.
+pub(crate) fn dump_info(config: &Config) -> Result<String, MergeError> {
+    let png_file = open_input_png(config)?;
+    let reader = png_decoder(BufReader::with_capacity(config.buffer_size, png_file)).read_info()?;
+
+    Ok(info_to_json(reader.info()))
+}
+
+fn color_type_name(color_type: ColorType) -> &'static str {
+    match color_type {
+        ColorType::Grayscale => "grayscale",
+        ColorType::Rgb => "rgb",
+        ColorType::Indexed => "indexed",
+        ColorType::GrayscaleAlpha => "grayscale_alpha",
+        ColorType::Rgba => "rgba",
+    }
+}
+
+/// Prints the size and format of a decoded png with `--verbose`.
+fn log_decoded(config: &Config, path: &Path, info: &Info) {
+    config.log_level.verbose(format_args!(
+        "{}: {}x{}, {}, {}-bit",
+        path.display(),
+        info.width,
+        info.height,
+        color_type_name(info.color_type),
+        info.bit_depth as u8
+    ));
+}
+
+fn info_to_json(info: &Info) -> String {
+    let color_type = color_type_name(info.color_type);
+    let has_alpha = matches!(info.color_type, ColorType::GrayscaleAlpha | ColorType::Rgba)
+        || info.trns.is_some();
+
+    format!(
+        "{{\"width\":{},\"height\":{},\"color_type\":\"{}\",\"bit_depth\":{},\"interlaced\":{},\"has_palette\":{},\"has_trns\":{},\"has_alpha\":{}}}",
+        info.width,
+        info.height,
+        color_type,
+        info.bit_depth as u8,
+        info.interlaced,
+        info.palette.is_some(),
+        info.trns.is_some(),
+        has_alpha
+    )
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Opens the input png, or stdin for `-`. The signature of stdin is left to the decoder.
+fn open_input_png(config: &Config) -> Result<Box<dyn Read>, MergeError> {
+    if is_stdio(&config.png_path) {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    Ok(Box::new(open_png(&config.png_path)?))
+}
+
+/// Opens the file and checks the png signature, so that other formats fail with
+/// `NotAPng` instead of a decoding error.
+fn open_png(path: &Path) -> Result<File, MergeError> {
+    let mut file = File::open(path)?;
+
+    let mut signature = [0; 8];
+    match file.read_exact(&mut signature) {
+        Ok(()) if signature == PNG_SIGNATURE => {}
+        Ok(()) => {
+            return Err(MergeError::NotAPng {
+                path: path.to_path_buf(),
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            return Err(MergeError::NotAPng {
+                path: path.to_path_buf(),
+            })
+        }
+        Err(e) => return Err(e.into()),
+    }
+    file.rewind()?;
+
+    Ok(file)
+}
+
+fn png_decoder<R: Read>(reader: R) -> Decoder<R> {
+    let mut decoder = Decoder::new(reader);
+    decoder.set_transformations(Transformations::IDENTITY);
+    decoder
+}
+
+/// Info of a decoded input png, with its iCCP chunk as it was in the file: the decoder
+/// only keeps the decompressed profile, and the chunk is copied to the output unchanged.
+#[derive(Clone, Debug)]
+pub(crate) struct PngInfo {
+    pub info: Info<'static>,
+    pub iccp_chunk: Option<Vec<u8>>,
+}
+
+impl Deref for PngInfo {
+    type Target = Info<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.info
+    }
+}
+
+impl From<Info<'static>> for PngInfo {
+    fn from(info: Info<'static>) -> Self {
+        Self {
+            info,
+            iccp_chunk: None,
+        }
+    }
+}
+
+/// Passes a png through while keeping the data of its first iCCP chunk.
+struct IccpTap<R> {
+    inner: R,
+    iccp_chunk: Rc<Cell<Option<Vec<u8>>>>,
+    state: TapState,
+}
+
+enum TapState {
+    /// Passing this many bytes: the signature, or the data and CRC of a chunk not kept.
+    Skip(u64),
+    /// Reading the length and type of the next chunk.
+    Header(Vec<u8>),
+    /// Keeping the data of the iCCP chunk, with this many bytes still to come.
+    Keep(Vec<u8>, u64),
+    /// At the image data, after which iCCP can't come.
+    Done,
+}
+
+impl<R> IccpTap<R> {
+    fn new(inner: R, iccp_chunk: Rc<Cell<Option<Vec<u8>>>>) -> Self {
+        Self {
+            inner,
+            iccp_chunk,
+            state: TapState::Skip(PNG_SIGNATURE.len() as u64),
+        }
+    }
+}
+
+impl<R: Read> Read for IccpTap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let mut bytes = &buf[..len];
+        while !bytes.is_empty() {
+            let taken = match &mut self.state {
+                TapState::Skip(left) => {
+                    let taken = bytes.len().min(*left as usize);
+                    *left -= taken as u64;
+                    if *left == 0 {
+                        self.state = TapState::Header(Vec::with_capacity(8));
+                    }
+                    taken
+                }
+                TapState::Header(header) => {
+                    let taken = bytes.len().min(8 - header.len());
+                    header.extend_from_slice(&bytes[..taken]);
+                    if header.len() == 8 {
+                        let data_len =
+                            u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                        self.state = match &header[4..] {
+                            b"IDAT" => TapState::Done,
+                            b"iCCP" if data_len > 0 => TapState::Keep(Vec::new(), data_len as u64),
+                            _ => TapState::Skip(data_len as u64 + 4),
+                        };
+                    }
+                    taken
+                }
+                TapState::Keep(data, left) => {
+                    let taken = bytes.len().min(*left as usize);
+                    data.extend_from_slice(&bytes[..taken]);
+                    *left -= taken as u64;
+                    if *left == 0 {
+                        // The decoder ignores any later iCCP, so only the first is kept.
+                        self.iccp_chunk.set(Some(std::mem::take(data)));
+                        self.state = TapState::Done;
+                    }
+                    taken
+                }
+                TapState::Done => break,
+            };
+            bytes = &bytes[taken..];
+        }
+
+        Ok(len)
+    }
+}
+
+/// Same as `decode_png` for the input png, keeping its iCCP chunk.
+pub(crate) fn decode_input_png<R: Read>(reader: R) -> Result<(Vec<u8>, PngInfo), MergeError> {
+    let iccp_chunk = Rc::new(Cell::new(None));
+    let (buf, info) = decode_png(IccpTap::new(reader, Rc::clone(&iccp_chunk)))?;
+
+    Ok((
+        buf,
+        PngInfo {
+            info,
+            iccp_chunk: iccp_chunk.take(),
+        },
+    ))
+}
+
+/// Decodes the first frame with no transformations, so the buffer keeps the layout
+/// described by the returned `Info`: palette indices stay unexpanded, tRNS is not
+/// applied and 16-bit samples are kept. `buf_to_rgba` does all of the conversion.
+pub(crate) fn decode_png<R: Read>(reader: R) -> Result<(Vec<u8>, Info<'static>), MergeError> {
+    let mut reader = png_decoder(reader).read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let output_info = reader.next_frame(&mut buf)?;
+    buf.truncate(output_info.buffer_size());
+    // Reads the chunks after the image data for their text. They only carry metadata,
+    // so a damaged tail doesn't fail the merge.
+    let _ = reader.finish();
+
+    Ok((buf, reader.info().clone()))
+}
+
+fn load_alpha_mask(pna_path: &Path, config: &Config) -> Result<AlphaMask, MergeError> {
+    let mut cache_variant = format!("{:?}+{:?}", config.alpha_source, config.luma_weights);
+    if config.expect_pna_grayscale {
+        // Only masks that passed the check are cached under this variant.
+        cache_variant.push_str("+grayscale");
+    }
+    if config.invert_alpha {
+        cache_variant.push_str("+inverted");
+    }
+    if config.cache_decoded_pna {
+        if let Some(alpha_mask) = cache::load_alpha_mask(pna_path, &cache_variant) {
+            return Ok(alpha_mask);
+        }
+    }
+
+    let pna_file = open_png(pna_path)?;
+    let (pna_buf, pna_info) = decode_png(BufReader::with_capacity(config.buffer_size, pna_file))?;
+    log_decoded(config, pna_path, &pna_info);
+    let alpha_mask = decoded_pna_to_alpha_mask(&pna_buf, &pna_info, config)?;
+
+    if config.cache_decoded_pna {
+        cache::save_alpha_mask(pna_path, &cache_variant, &alpha_mask);
+    }
+
+    Ok(alpha_mask)
+}
+
+/// Checks the decoded pna against `--expect-pna-grayscale` and reduces it to the mask,
+/// inverted with `--invert-alpha`.
+pub(crate) fn decoded_pna_to_alpha_mask(
+    pna_buf: &[u8],
+    pna_info: &Info,
+    config: &Config,
+) -> Result<AlphaMask, MergeError> {
+    if config.expect_pna_grayscale && !is_effectively_grayscale(pna_buf, pna_info)? {
+        return Err(MergeError::PnaIsNotGrayscale(pna_info.color_type));
+    }
+
+    let alpha_mask = pna_to_alpha_mask(
+        pna_buf,
+        pna_info,
+        config.alpha_source,
+        config.luma_weights,
+        config.strict_data_size,
+    )?;
+
+    if config.invert_alpha {
+        Ok(invert(&alpha_mask))
+    } else {
+        Ok(alpha_mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Types and data of the chunks of an encoded png, in order.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut rest = &png[PNG_SIGNATURE.len()..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            chunks.push((rest[4..8].try_into().unwrap(), &rest[8..8 + len]));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    mod process {
+        use std::path::PathBuf;
+
+        use super::*;
+        use crate::config::{Bitmask, Thumbnail};
+
+        #[test]
+        fn success_when_valid_config() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let pna_path = dir.clone().join("surface0000.pna");
+            let output_path = dir.clone().join("surface0000_new.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path,
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+        }
+
+        #[test]
+        fn success_when_gradient_mask() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_gradient.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Gradient(crate::mask::Gradient {
+                    direction: crate::mask::GradientDirection::Ltr,
+                    start_alpha: u8::MAX,
+                    end_alpha: 0,
+                }),
+                output_path: output_path.clone(),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+            std::fs::remove_file(output_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_solid_mask() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_solid.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Solid(128),
+                output_path: output_path.clone(),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let output_file = File::open(&output_path).unwrap();
+            let (output_buf, _) = decode_png(BufReader::new(output_file)).unwrap();
+            assert!(output_buf.chunks_exact(4).all(|v| v[3] == 128));
+            std::fs::remove_file(output_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_thumbnail() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let pna_path = dir.clone().join("surface0000.pna");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_thumb_main.png");
+            let thumbnail_path = std::env::temp_dir().join("merge-pna_surface0000_thumb.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                thumbnail: Some(Thumbnail {
+                    path: thumbnail_path.clone(),
+                    width: 16,
+                    height: 8,
+                }),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let reader = Decoder::new(File::open(&thumbnail_path).unwrap())
+                .read_info()
+                .unwrap();
+            assert_eq!((reader.info().width, reader.info().height), (16, 8));
+
+            std::fs::remove_file(output_path).unwrap();
+            std::fs::remove_file(thumbnail_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_bitmask() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_bitmask.png");
+            let bitmask_path = std::env::temp_dir().join("merge-pna_surface0000.bitmask");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Gradient(crate::mask::Gradient {
+                    direction: crate::mask::GradientDirection::Ltr,
+                    start_alpha: u8::MAX,
+                    end_alpha: 0,
+                }),
+                output_path: output_path.clone(),
+                bitmask: Some(Bitmask {
+                    path: bitmask_path.clone(),
+                    threshold: 128,
+                }),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let bitmask = std::fs::read(&bitmask_path).unwrap();
+            let width = u32::from_le_bytes([bitmask[0], bitmask[1], bitmask[2], bitmask[3]]);
+            let height = u32::from_le_bytes([bitmask[4], bitmask[5], bitmask[6], bitmask[7]]);
+            let row_len = (width as usize).div_ceil(8);
+            assert_eq!(bitmask.len(), 8 + row_len * height as usize);
+            assert_eq!(bitmask[8] & 0b10000000, 0b10000000);
+
+            std::fs::remove_file(output_path).unwrap();
+            std::fs::remove_file(bitmask_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_split_channels() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let pna_path = dir.clone().join("surface0000.pna");
+            let split_dir = std::env::temp_dir().join("merge-pna_split_channels");
+            std::fs::create_dir_all(&split_dir).unwrap();
+            let output_path = split_dir.join("surface0000_new.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                split_channels: Some(split_dir.clone()),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let (merged, _) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            for (i, suffix) in ["r", "g", "b", "a"].iter().enumerate() {
+                let path = split_dir.join(format!("surface0000_{}.png", suffix));
+                let (channel, info) = decode_png(File::open(&path).unwrap()).unwrap();
+                assert_eq!(info.color_type, png::ColorType::Grayscale);
+                assert!(channel.iter().eq(merged.iter().skip(i).step_by(4)));
+            }
+
+            std::fs::remove_dir_all(split_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_encoder_options() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let config = |output_path: &Path, compression, filter| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.to_path_buf(),
+                compression,
+                filter,
+                ..Default::default()
+            };
+            let default_path = std::env::temp_dir().join("merge-pna_encoder_default.png");
+            let fast_path = std::env::temp_dir().join("merge-pna_encoder_fast.png");
+
+            process(&config(&default_path, None, None)).unwrap();
+            process(&config(
+                &fast_path,
+                Some(CompressionLevel::Fast),
+                Some(FilterMode::None),
+            ))
+            .unwrap();
+
+            let (default_buf, _) = decode_png(File::open(&default_path).unwrap()).unwrap();
+            let (fast_buf, _) = decode_png(File::open(&fast_path).unwrap()).unwrap();
+            assert_eq!(fast_buf, default_buf);
+            assert_ne!(
+                std::fs::read(&fast_path).unwrap(),
+                std::fs::read(&default_path).unwrap()
+            );
+
+            std::fs::remove_file(default_path).unwrap();
+            std::fs::remove_file(fast_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_keep_16_bit() {
+            let work_dir = std::env::temp_dir().join("merge-pna_keep_16_bit");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("deep.png");
+            let output_path = work_dir.join("deep_new.png");
+            let png_buf = [
+                0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0xff, 0, 1, 0x80, 0x01,
+            ];
+            write_png(
+                &png_path,
+                2,
+                1,
+                png::ColorType::Rgb,
+                png::BitDepth::Sixteen,
+                &png_buf,
+                &Config::default(),
+            )
+            .unwrap();
+            let config = |keep_16_bit| Config {
+                png_path: png_path.clone(),
+                mask_source: MaskSource::Solid(u8::MAX),
+                output_path: output_path.clone(),
+                keep_16_bit,
+                ..Default::default()
+            };
+
+            process(&config(true)).unwrap();
+            let (merged, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+            assert_eq!(
+                merged,
+                [
+                    0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0xff, 0xff, 0xff, 0, 1, 0x80, 0x01,
+                    0xff, 0xff
+                ]
+            );
+
+            process(&config(false)).unwrap();
+            let (merged, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(info.bit_depth, png::BitDepth::Eight);
+            assert_eq!(merged, [0x12, 0x56, 0x9a, 0xff, 0xff, 0, 0x80, 0xff]);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_stream() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_stream");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let output_path = work_dir.join("surface0000_new.png");
+            let config = |stream| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                posterize_alpha: Some(4),
+                stream,
+                ..Default::default()
+            };
+
+            process(&config(true)).unwrap();
+
+            let (expected_buf, expected_info, _) = merge(&config(false)).unwrap();
+            let (actual_buf, actual_info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(
+                (actual_info.width, actual_info.height),
+                (expected_info.width, expected_info.height)
+            );
+            assert_eq!(actual_buf, expected_buf);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        #[cfg(all(feature = "checksum-sha256", feature = "checksum-blake3"))]
+        fn success_when_checksum() {
+            use crate::checksum::ChecksumAlgorithm;
+
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_checksum");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let output_path = work_dir.join("surface0000_new.png");
+
+            for (algorithm, stream, max_output_bytes) in [
+                (ChecksumAlgorithm::Sha256, false, None),
+                (ChecksumAlgorithm::Blake3, false, Some(u64::MAX)),
+                (ChecksumAlgorithm::Sha256, true, None),
+            ] {
+                let config = Config {
+                    png_path: dir.join("surface0000.png"),
+                    mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                    output_path: output_path.clone(),
+                    max_output_bytes,
+                    checksum: Some(algorithm),
+                    stream,
+                    ..Default::default()
+                };
+
+                process(&config).unwrap();
+
+                let mut hasher = algorithm.hasher();
+                hasher.update(&std::fs::read(&output_path).unwrap());
+                let sidecar =
+                    work_dir.join(format!("surface0000_new.png.{}", algorithm.extension()));
+                assert_eq!(
+                    std::fs::read_to_string(sidecar).unwrap(),
+                    format!("{}  surface0000_new.png\n", hasher.finalize_hex())
+                );
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_stream_exceeds_decoder_limit() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_stream_decoder_limit");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: work_dir.join("out.png"),
+                stream: true,
+                decoder_limit: Some(16),
+                ..Default::default()
+            };
+
+            let result = process(&config);
+
+            assert!(matches!(
+                result,
+                Err(MergeError::DecodingError(
+                    png::DecodingError::LimitsExceeded
+                ))
+            ));
+            assert!(!work_dir.join("out.png").exists());
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_stream_with_different_size() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_stream_different_size");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let pna_path = work_dir.join("small.pna");
+            write_png(
+                &pna_path,
+                1,
+                1,
+                png::ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &[0],
+                &Config::default(),
+            )
+            .unwrap();
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: work_dir.join("out.png"),
+                stream: true,
+                ..Default::default()
+            };
+
+            let result = process(&config);
+
+            assert!(matches!(
+                result,
+                Err(MergeError::SizePngAndPnaAreDifferent { .. })
+            ));
+            assert!(!work_dir.join("out.png").exists());
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_emit_8_and_16() {
+            let work_dir = std::env::temp_dir().join("merge-pna_emit_8_and_16");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("deep.png");
+            let png_buf = [
+                0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0xff, 0, 1, 0x80, 0x01,
+            ];
+            write_png(
+                &png_path,
+                2,
+                1,
+                png::ColorType::Rgb,
+                png::BitDepth::Sixteen,
+                &png_buf,
+                &Config::default(),
+            )
+            .unwrap();
+            let config = Config {
+                png_path: png_path.clone(),
+                mask_source: MaskSource::Solid(u8::MAX),
+                output_path: work_dir.join("deep_new.png"),
+                emit: vec![png::BitDepth::Eight, png::BitDepth::Sixteen],
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            assert!(!work_dir.join("deep_new.png").exists());
+            let (merged, info) =
+                decode_png(File::open(work_dir.join("deep_new_16.png")).unwrap()).unwrap();
+            assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+            assert_eq!(
+                merged,
+                [
+                    0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0xff, 0xff, 0xff, 0, 1, 0x80, 0x01,
+                    0xff, 0xff
+                ]
+            );
+            let (merged, info) =
+                decode_png(File::open(work_dir.join("deep_new_8.png")).unwrap()).unwrap();
+            assert_eq!(info.bit_depth, png::BitDepth::Eight);
+            assert_eq!(merged, [0x12, 0x56, 0x9a, 0xff, 0xff, 0, 0x80, 0xff]);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_output_is_too_large() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let output_path = std::env::temp_dir().join("merge-pna_max_output_bytes.png");
+            let _ = std::fs::remove_file(&output_path);
+            let config = |max_output_bytes| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                max_output_bytes: Some(max_output_bytes),
+                ..Default::default()
+            };
+
+            assert!(matches!(
+                process(&config(100)),
+                Err(MergeError::OutputTooLarge { max: 100, .. })
+            ));
+            assert!(!output_path.exists());
+
+            process(&config(u64::MAX)).unwrap();
+            assert!(output_path.exists());
+
+            std::fs::remove_file(output_path).unwrap();
+        }
+
+        #[test]
+        fn success_when_backup() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_backup");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let output_path = work_dir.join("out.png");
+            std::fs::write(&output_path, b"old").unwrap();
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                backup: true,
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+            assert_eq!(std::fs::read(work_dir.join("out.png.bak")).unwrap(), b"old");
+            let merged = std::fs::read(&output_path).unwrap();
+
+            process(&config).unwrap();
+            assert_eq!(std::fs::read(work_dir.join("out.png.bak")).unwrap(), b"old");
+            assert_eq!(
+                std::fs::read(work_dir.join("out.png.bak.1")).unwrap(),
+                merged
+            );
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_backup_is_restored_on_failure() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_backup_restored");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let output_path = work_dir.join("out.png");
+            std::fs::write(&output_path, b"old").unwrap();
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                backup: true,
+                max_output_bytes: Some(100),
+                ..Default::default()
+            };
+
+            assert!(matches!(
+                process(&config),
+                Err(MergeError::OutputTooLarge { max: 100, .. })
+            ));
+            assert_eq!(std::fs::read(&output_path).unwrap(), b"old");
+            assert!(!work_dir.join("out.png.bak").exists());
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_pixel_dims_are_kept() {
+            let work_dir = std::env::temp_dir().join("merge-pna_pixel_dims");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let pixel_dims = PixelDimensions {
+                xppu: 11811,
+                yppu: 5906,
+                unit: png::Unit::Meter,
+            };
+            for (name, pixel_dims) in [("a", Some(pixel_dims)), ("b", None)] {
+                let png_path = work_dir.join(format!("{}.png", name));
+                {
+                    let mut encoder = Encoder::new(File::create(&png_path).unwrap(), 1, 1);
+                    encoder.set_color(ColorType::Grayscale);
+                    encoder.set_pixel_dims(pixel_dims);
+                    let mut writer = encoder.write_header().unwrap();
+                    writer.write_image_data(&[0]).unwrap();
+                }
+                let output_path = work_dir.join(format!("{}_new.png", name));
+                let config = Config {
+                    png_path,
+                    mask_source: MaskSource::Solid(128),
+                    output_path: output_path.clone(),
+                    ..Default::default()
+                };
+
+                process(&config).unwrap();
+
+                let (_, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+                assert_eq!(
+                    info.pixel_dims.map(|v| (v.xppu, v.yppu, v.unit)),
+                    pixel_dims.map(|v| (v.xppu, v.yppu, v.unit))
+                );
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_color_space_is_kept() {
+            let work_dir = std::env::temp_dir().join("merge-pna_color_space");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let gamma = ScaledFloat::from_scaled(55000);
+            let srgb_gamma = ScaledFloat::from_scaled(45455);
+            let profile = vec![7; 300];
+            // (source gamma, sRGB, ICC profile, expected gamma, expected sRGB, expected profile)
+            let cases = [
+                (Some(gamma), None, None, Some(gamma), None, None),
+                (
+                    Some(gamma),
+                    Some(SrgbRenderingIntent::Perceptual),
+                    None,
+                    Some(srgb_gamma),
+                    Some(SrgbRenderingIntent::Perceptual),
+                    None,
+                ),
+                (
+                    Some(gamma),
+                    None,
+                    Some(profile.clone()),
+                    Some(gamma),
+                    None,
+                    Some(profile.clone()),
+                ),
+                (None, None, None, None, None, None),
+            ];
+            for (i, (gamma, srgb, icc_profile, expected_gamma, expected_srgb, expected_profile)) in
+                cases.into_iter().enumerate()
+            {
+                let png_path = work_dir.join(format!("{}.png", i));
+                write_png_with_metadata(
+                    &png_path,
+                    1,
+                    1,
+                    ColorType::Grayscale,
+                    png::BitDepth::Eight,
+                    &[0],
+                    &Metadata {
+                        iccp_chunk: icc_profile.map(|v| iccp_chunk_data(&v).unwrap()),
+                        srgb,
+                        source_gamma: gamma,
+                        ..Default::default()
+                    },
+                    &Config::default(),
+                )
+                .unwrap();
+                let output_path = work_dir.join(format!("{}_new.png", i));
+                let config = Config {
+                    png_path,
+                    mask_source: MaskSource::Solid(128),
+                    output_path: output_path.clone(),
+                    ..Default::default()
+                };
+
+                process(&config).unwrap();
+
+                let (_, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+                assert_eq!(info.source_gamma, expected_gamma);
+                assert_eq!(info.srgb, expected_srgb);
+                assert_eq!(info.icc_profile.map(|v| v.to_vec()), expected_profile);
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_icc_profile_is_embedded() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let output_path = std::env::temp_dir().join("merge-pna_icc_profile.png");
+            let profile: Vec<u8> = (0..=255).cycle().take(1000).collect();
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                icc_profile: Some(profile.clone()),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let (_, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            assert_eq!(info.icc_profile.as_deref(), Some(profile.as_slice()));
+
+            std::fs::remove_file(output_path).unwrap();
+        }
 
-    Ok(())
-}
+        #[test]
+        fn success_when_iccp_chunk_is_copied() {
+            let work_dir = std::env::temp_dir().join("merge-pna_iccp_chunk");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            // A name and a compression level other than the ones written for --icc.
+            let mut iccp_chunk = b"Custom\0\0".to_vec();
+            let mut encoder = ZlibEncoder::new(&mut iccp_chunk, flate2::Compression::best());
+            encoder.write_all(&[7; 300]).unwrap();
+            encoder.finish().unwrap();
+            write_png_with_metadata(
+                &png_path,
+                1,
+                1,
+                ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &[0],
+                &Metadata {
+                    iccp_chunk: Some(iccp_chunk.clone()),
+                    ..Default::default()
+                },
+                &Config::default(),
+            )
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            for stream in [false, true] {
+                let config = Config {
+                    png_path: png_path.clone(),
+                    mask_source: MaskSource::Pna(png_path.clone()),
+                    output_path: work_dir.join("a_new.png"),
+                    stream,
+                    ..Default::default()
+                };
 
-    mod process {
-        use std::path::PathBuf;
+                process(&config).unwrap();
 
-        use super::*;
+                let output = std::fs::read(&config.output_path).unwrap();
+                let copied = chunks(&output)
+                    .into_iter()
+                    .find(|v| &v.0 == b"iCCP")
+                    .map(|v| v.1.to_vec());
+                assert_eq!(copied, Some(iccp_chunk.clone()));
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
 
         #[test]
-        fn success_when_valid_config() {
+        fn success_when_chunks_are_stripped() {
+            let work_dir = std::env::temp_dir().join("merge-pna_strip_chunks");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            write_png_with_metadata(
+                &png_path,
+                1,
+                1,
+                ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &[0],
+                &Metadata {
+                    latin1_text: vec![TEXtChunk::new("Artist", "tukinami")],
+                    iccp_chunk: Some(iccp_chunk_data(&[7; 300]).unwrap()),
+                    source_gamma: Some(ScaledFloat::new(1.0 / 2.2)),
+                    pixel_dims: Some(PixelDimensions {
+                        xppu: 2835,
+                        yppu: 2835,
+                        unit: png::Unit::Meter,
+                    }),
+                    ..Default::default()
+                },
+                &Config::default(),
+            )
+            .unwrap();
+
+            for stream in [false, true] {
+                let config = Config {
+                    png_path: png_path.clone(),
+                    mask_source: MaskSource::Pna(png_path.clone()),
+                    output_path: work_dir.join("a_new.png"),
+                    strip_chunks: vec![
+                        StrippedChunk::Text,
+                        StrippedChunk::Phys,
+                        StrippedChunk::Iccp,
+                    ],
+                    stream,
+                    ..Default::default()
+                };
+
+                process(&config).unwrap();
+
+                let output = std::fs::read(&config.output_path).unwrap();
+                let types: Vec<[u8; 4]> = chunks(&output).into_iter().map(|v| v.0).collect();
+                assert!(types.contains(b"gAMA"));
+                for stripped in [b"tEXt", b"pHYs", b"iCCP"] {
+                    assert!(!types.contains(stripped));
+                }
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_text_chunks_are_kept() {
+            let work_dir = std::env::temp_dir().join("merge-pna_text_chunks");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            {
+                let mut encoder = Encoder::new(File::create(&png_path).unwrap(), 2, 1);
+                encoder.set_color(ColorType::Rgb);
+                encoder
+                    .add_text_chunk("Artist".to_string(), "tukinami".to_string())
+                    .unwrap();
+                let mut writer = encoder.write_header().unwrap();
+                writer
+                    .write_text_chunk(&ITXtChunk::new("Comment", "月波"))
+                    .unwrap();
+                writer.write_image_data(&[0; 6]).unwrap();
+                writer
+                    .write_text_chunk(&ZTXtChunk::new("Copyright", "(c) 2024"))
+                    .unwrap();
+            }
+            let pna_path = work_dir.join("a.pna");
+            write_png(
+                &pna_path,
+                2,
+                1,
+                ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &[0, 255],
+                &Config::default(),
+            )
+            .unwrap();
+            let output_path = work_dir.join("a_new.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let (_, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            let latin1: Vec<_> = info
+                .uncompressed_latin1_text
+                .iter()
+                .map(|v| (v.keyword.as_str(), v.text.as_str()))
+                .collect();
+            assert_eq!(latin1, vec![("Artist", "tukinami")]);
+            assert_eq!(info.utf8_text[0].keyword, "Comment");
+            assert_eq!(info.utf8_text[0].get_text().unwrap(), "月波");
+            assert_eq!(info.compressed_latin1_text[0].keyword, "Copyright");
+            assert_eq!(
+                info.compressed_latin1_text[0].get_text().unwrap(),
+                "(c) 2024"
+            );
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_grid() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_grid");
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let output_path = work_dir.join("surface0000_new.png");
+            let config = Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: output_path.clone(),
+                grid: Some(Grid {
+                    layout: crate::config::GridLayout::Count { cols: 2, rows: 1 },
+                    partial: crate::resize::PartialCells::Pad,
+                }),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let (merged, info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            let cell_width = info.width.div_ceil(2) as usize;
+            let width = info.width as usize;
+            for index in 0..2 {
+                let path = work_dir.join(format!("surface0000_{}.png", index));
+                let (cell, cell_info) = decode_png(File::open(&path).unwrap()).unwrap();
+                assert_eq!(cell_info.width as usize, cell_width);
+                assert_eq!(cell_info.height, info.height);
+                let x0 = index * cell_width;
+                let copy_width = cell_width.min(width - x0);
+                for y in 0..info.height as usize {
+                    let src = &merged[(y * width + x0) * 4..(y * width + x0 + copy_width) * 4];
+                    let dst = &cell[y * cell_width * 4..(y * cell_width + copy_width) * 4];
+                    assert_eq!(src, dst);
+                }
+            }
+            assert!(!work_dir.join("surface0000_2.png").exists());
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_split_merged_png() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_split");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let merged_path = work_dir.join("surface0000_merged.png");
+            let rgb_path = work_dir.join("surface0000.png");
+            let pna_path = work_dir.join("surface0000.pna");
+            process(&Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                output_path: merged_path.clone(),
+                ..Default::default()
+            })
+            .unwrap();
+
+            let config = Config {
+                png_path: merged_path.clone(),
+                mask_source: MaskSource::Pna(pna_path.clone()),
+                output_path: rgb_path.clone(),
+                split: Some(Split {
+                    pna_path: pna_path.clone(),
+                    background: None,
+                }),
+                ..Default::default()
+            };
+            process(&config).unwrap();
+
+            let (merged, _) = decode_png(File::open(&merged_path).unwrap()).unwrap();
+            let (rgb, rgb_info) = decode_png(File::open(&rgb_path).unwrap()).unwrap();
+            let (alpha, alpha_info) = decode_png(File::open(&pna_path).unwrap()).unwrap();
+            assert_eq!(rgb_info.color_type, png::ColorType::Rgb);
+            assert_eq!(alpha_info.color_type, png::ColorType::Grayscale);
+            let expected_rgb: Vec<u8> = merged
+                .chunks_exact(4)
+                .flat_map(|v| v[..3].to_vec())
+                .collect();
+            assert_eq!(rgb, expected_rgb);
+            assert!(alpha.iter().eq(merged.iter().skip(3).step_by(4)));
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_split_channels_depth_2() {
             let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
             let png_path = dir.clone().join("surface0000.png");
             let pna_path = dir.clone().join("surface0000.pna");
-            let output_path = dir.clone().join("surface0000_new.png");
+            let split_dir = std::env::temp_dir().join("merge-pna_split_channels_depth_2");
+            std::fs::create_dir_all(&split_dir).unwrap();
+            let output_path = split_dir.join("surface0000_new.png");
             let config = Config {
                 png_path,
-                pna_path,
-                output_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                split_channels: Some(split_dir.clone()),
+                split_depth: png::BitDepth::Two,
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let (merged, merged_info) = decode_png(File::open(&output_path).unwrap()).unwrap();
+            let path = split_dir.join("surface0000_a.png");
+            let (channel, info) = decode_png(File::open(&path).unwrap()).unwrap();
+            assert_eq!(info.color_type, png::ColorType::Grayscale);
+            assert_eq!(info.bit_depth, png::BitDepth::Two);
+            let alpha: Vec<u8> = merged.iter().skip(3).step_by(4).copied().collect();
+            assert_eq!(
+                channel,
+                pack_gray_samples(&alpha, merged_info.width, png::BitDepth::Two)
+            );
+
+            std::fs::remove_dir_all(split_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_validate_only() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let pna_path = dir.clone().join("surface0000.pna");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_validate.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                validate_only: true,
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            assert!(!output_path.exists());
+        }
+
+        #[test]
+        fn failed_when_pna_is_not_grayscale() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_not_gray.png");
+            let config = Config {
+                png_path: png_path.clone(),
+                mask_source: MaskSource::Pna(png_path),
+                output_path: output_path.clone(),
+                expect_pna_grayscale: true,
+                ..Default::default()
+            };
+
+            assert!(matches!(
+                process(&config),
+                Err(MergeError::PnaIsNotGrayscale(_))
+            ));
+            assert!(!output_path.exists());
+        }
+
+        #[test]
+        fn failed_when_dimensions_are_unexpected() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let png_path = dir.clone().join("surface0000.png");
+            let pna_path = dir.clone().join("surface0000.pna");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_unexpected.png");
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: output_path.clone(),
+                assert_dimensions: Some((1, 1)),
+                ..Default::default()
+            };
+
+            assert!(matches!(
+                process(&config),
+                Err(MergeError::UnexpectedDimensions {
+                    expected: (1, 1),
+                    ..
+                })
+            ));
+            assert!(!output_path.exists());
+        }
+    }
+
+    mod merge {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        #[test]
+        fn success_when_placeholder_color() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let pna_path = dir.join("surface0000.pna");
+            let config = Config {
+                png_path: pna_path.clone(),
+                mask_source: MaskSource::Pna(pna_path),
+                placeholder_color: Some([10, 20, 30]),
+                ..Default::default()
+            };
+
+            let (merged_buf, png_info, alpha_mask) = merge(&config).unwrap();
+
+            assert_eq!(png_info.width, alpha_mask.width);
+            assert_eq!(png_info.height, alpha_mask.height);
+            assert_eq!(merged_buf.len(), alpha_mask.data.len() * 4);
+            for (pixel, alpha) in merged_buf.chunks_exact(4).zip(alpha_mask.data.iter()) {
+                assert_eq!(pixel, [10, 20, 30, *alpha]);
+            }
+        }
+
+        #[test]
+        fn success_when_invert_alpha() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let config = |invert_alpha| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                invert_alpha,
+                ..Default::default()
+            };
+
+            let (_, _, alpha_mask) = merge(&config(false)).unwrap();
+            let (merged_buf, _, inverted_mask) = merge(&config(true)).unwrap();
+
+            assert!(inverted_mask
+                .data
+                .iter()
+                .zip(alpha_mask.data.iter())
+                .all(|(a, b)| *a == u8::MAX - b));
+            assert!(merged_buf
+                .iter()
+                .skip(3)
+                .step_by(4)
+                .eq(inverted_mask.data.iter()));
+        }
+
+        #[test]
+        fn success_when_threshold() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let config = |threshold| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source: MaskSource::Pna(dir.join("surface0000.pna")),
+                threshold,
+                ..Default::default()
+            };
+
+            let (_, _, alpha_mask) = merge(&config(None)).unwrap();
+            let (merged_buf, _, binary_mask) = merge(&config(Some(100))).unwrap();
+
+            assert!(binary_mask
+                .data
+                .iter()
+                .zip(alpha_mask.data.iter())
+                .all(|(a, b)| *a == if *b >= 100 { u8::MAX } else { 0 }));
+            assert!(merged_buf
+                .iter()
+                .skip(3)
+                .step_by(4)
+                .all(|v| *v == 0 || *v == u8::MAX));
+        }
+    }
+
+    mod output_indexed {
+        use super::*;
+
+        #[test]
+        fn success_when_few_colors() {
+            let work_dir = std::env::temp_dir().join("merge-pna_output_indexed");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            let pna_path = work_dir.join("a.pna");
+            write_png(
+                &png_path,
+                3,
+                2,
+                png::ColorType::Rgb,
+                png::BitDepth::Eight,
+                &[
+                    10, 20, 30, 10, 20, 30, 40, 50, 60, 40, 50, 60, 10, 20, 30, 0, 0, 0,
+                ],
+                &Config::default(),
+            )
+            .unwrap();
+            write_png(
+                &pna_path,
+                3,
+                2,
+                png::ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &[255, 0, 255, 128, 0, 255],
+                &Config::default(),
+            )
+            .unwrap();
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Pna(pna_path),
+                output_path: work_dir.join("a_new.png"),
+                output_color: OutputColor::Indexed,
+                ..Default::default()
+            };
+
+            let warnings = process(&config).unwrap();
+
+            let (expected_buf, _, _) = merge(&config).unwrap();
+            let decoder = Decoder::new(File::open(&config.output_path).unwrap());
+            let mut reader = decoder.read_info().unwrap();
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let output_info = reader.next_frame(&mut buf).unwrap();
+            buf.truncate(output_info.buffer_size());
+            assert!(warnings.is_empty());
+            assert_eq!(output_info.color_type, png::ColorType::Indexed);
+            assert_eq!(output_info.bit_depth, png::BitDepth::Four);
+            // Transparent pixels share one black entry.
+            let expected_buf: Vec<u8> = expected_buf
+                .chunks_exact(4)
+                .flat_map(|v| {
+                    if v[3] == 0 {
+                        [0; 4]
+                    } else {
+                        [v[0], v[1], v[2], v[3]]
+                    }
+                })
+                .collect();
+            assert_eq!(buf_to_rgba(&buf, reader.info()).unwrap(), expected_buf);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_icc_profile_precedes_palette() {
+            let work_dir = std::env::temp_dir().join("merge-pna_output_indexed_icc");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            write_png(
+                &png_path,
+                2,
+                1,
+                png::ColorType::Rgb,
+                png::BitDepth::Eight,
+                &[10, 20, 30, 40, 50, 60],
+                &Config::default(),
+            )
+            .unwrap();
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Solid(128),
+                output_path: work_dir.join("a_new.png"),
+                output_color: OutputColor::Indexed,
+                icc_profile: Some(vec![1, 2, 3]),
+                ..Default::default()
+            };
+
+            process(&config).unwrap();
+
+            let output = std::fs::read(&config.output_path).unwrap();
+            let types: Vec<[u8; 4]> = chunks(&output).into_iter().map(|v| v.0).collect();
+            let position = |chunk_type: &[u8; 4]| types.iter().position(|v| v == chunk_type);
+            assert!(position(b"iCCP").unwrap() < position(b"PLTE").unwrap());
+            assert!(position(b"PLTE").unwrap() < position(b"tRNS").unwrap());
+            let (_, info) = decode_png(output.as_slice()).unwrap();
+            assert_eq!(info.icc_profile.as_deref(), Some([1, 2, 3].as_slice()));
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn success_when_too_many_colors() {
+            let work_dir = std::env::temp_dir().join("merge-pna_output_indexed_fallback");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let png_path = work_dir.join("a.png");
+            let png_buf: Vec<u8> = (0..17 * 17)
+                .flat_map(|v| [v as u8, (v >> 8) as u8, 0])
+                .collect();
+            write_png(
+                &png_path,
+                17,
+                17,
+                png::ColorType::Rgb,
+                png::BitDepth::Eight,
+                &png_buf,
+                &Config::default(),
+            )
+            .unwrap();
+            let config = Config {
+                png_path,
+                mask_source: MaskSource::Solid(200),
+                output_path: work_dir.join("a_new.png"),
+                output_color: OutputColor::Indexed,
+                ..Default::default()
+            };
+
+            let warnings = process(&config).unwrap();
+
+            let (_, output_info) = decode_png(File::open(&config.output_path).unwrap()).unwrap();
+            assert!(matches!(
+                &warnings[..],
+                [Warning::NotIndexed(path)] if *path == config.output_path
+            ));
+            assert_eq!(output_info.color_type, png::ColorType::Rgba);
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+    }
+
+    mod merge_sequence {
+        use std::{collections::HashMap, path::PathBuf};
+
+        use super::*;
+
+        #[test]
+        fn success_when_delays() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_sequence");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let frame = |mask_source| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source,
+                ..Default::default()
+            };
+            let jobs = [
+                frame(MaskSource::Pna(dir.join("surface0000.pna"))),
+                frame(MaskSource::Solid(100)),
+                frame(MaskSource::Solid(200)),
+            ];
+            let sequence = Sequence {
+                path: work_dir.join("anim.png"),
+                delays: HashMap::from([(1, 250), (5, 1)]),
+                default_delay: 40,
+            };
+
+            merge_sequence(&jobs, &sequence).unwrap();
+
+            let decoder = Decoder::new(File::open(&sequence.path).unwrap());
+            let mut reader = decoder.read_info().unwrap();
+            assert_eq!(
+                reader
+                    .info()
+                    .animation_control
+                    .map(|v| (v.num_frames, v.num_plays)),
+                Some((3, 0))
+            );
+            let mut buf = vec![0; reader.output_buffer_size()];
+            for (job, delay) in jobs.iter().zip([40, 250, 40]) {
+                reader.next_frame(&mut buf).unwrap();
+                let frame_control = reader.info().frame_control.unwrap();
+                assert_eq!(
+                    (frame_control.delay_num, frame_control.delay_den),
+                    (delay, 1000)
+                );
+                assert_eq!(buf, merge(job).unwrap().0);
+            }
+
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+
+        #[test]
+        fn failed_when_frame_size_mismatch() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let work_dir = std::env::temp_dir().join("merge-pna_sequence_size_mismatch");
+            std::fs::create_dir_all(&work_dir).unwrap();
+            let small_path = work_dir.join("small.png");
+            write_png(
+                &small_path,
+                1,
+                1,
+                png::ColorType::Rgb,
+                png::BitDepth::Eight,
+                &[0, 0, 0],
+                &Config::default(),
+            )
+            .unwrap();
+            let jobs = [
+                Config {
+                    png_path: dir.join("surface0000.png"),
+                    mask_source: MaskSource::Solid(100),
+                    ..Default::default()
+                },
+                Config {
+                    png_path: small_path.clone(),
+                    mask_source: MaskSource::Solid(100),
+                    ..Default::default()
+                },
+            ];
+            let sequence = Sequence {
+                path: work_dir.join("anim.png"),
+                delays: HashMap::new(),
+                default_delay: 100,
+            };
+
+            let result = merge_sequence(&jobs, &sequence);
+
+            assert!(matches!(
+                result,
+                Err(MergeError::FrameSizeMismatch { path, actual: (1, 1), .. }) if path == small_path
+            ));
+            assert!(!sequence.path.exists());
+            std::fs::remove_dir_all(work_dir).unwrap();
+        }
+    }
+
+    mod diff_existing {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        #[test]
+        fn success_when_compared_with_existing_output() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+            let output_path = std::env::temp_dir().join("merge-pna_surface0000_diff.png");
+            let _ = std::fs::remove_file(&output_path);
+            let config = |mask_source| Config {
+                png_path: dir.join("surface0000.png"),
+                mask_source,
+                output_path: output_path.clone(),
+                ..Default::default()
+            };
+            let pna_mask = || MaskSource::Pna(dir.join("surface0000.pna"));
+
+            assert_eq!(
+                diff_existing(&config(pna_mask())).unwrap(),
+                ExistingOutput::Missing
+            );
+
+            process(&config(pna_mask())).unwrap();
+            assert_eq!(
+                diff_existing(&config(pna_mask())).unwrap(),
+                ExistingOutput::Identical
+            );
+
+            let result = diff_existing(&config(MaskSource::Solid(0))).unwrap();
+            std::fs::remove_file(&output_path).unwrap();
+            assert_eq!(result, ExistingOutput::WouldChange);
+        }
+    }
+
+    mod copy_newest_mtime {
+        use std::time::SystemTime;
+
+        use super::*;
+
+        #[test]
+        fn success_when_newest_source_is_copied() {
+            let dir = std::env::temp_dir();
+            let old_path = dir.join("merge-pna_mtime_old.png");
+            let new_path = dir.join("merge-pna_mtime_new.pna");
+            let target_path = dir.join("merge-pna_mtime_target.png");
+            let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+            let new_time = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000);
+            for (path, time) in [(&old_path, old_time), (&new_path, new_time)] {
+                File::create(path).unwrap().set_modified(time).unwrap();
+            }
+            File::create(&target_path).unwrap();
+
+            copy_newest_mtime(&[&old_path, &new_path], &target_path).unwrap();
+
+            let modified = std::fs::metadata(&target_path).unwrap().modified().unwrap();
+            for path in [&old_path, &new_path, &target_path] {
+                std::fs::remove_file(path).unwrap();
+            }
+            assert_eq!(modified, new_time);
+        }
+    }
+
+    mod append_stats_csv {
+        use super::*;
+
+        #[test]
+        fn success_when_header_is_written_once() {
+            let csv_path = std::env::temp_dir().join("merge-pna_stats_append.csv");
+            let _ = std::fs::remove_file(&csv_path);
+            let png_path = Path::new("a,b.png");
+            let stats = MergeStats {
+                png_path,
+                width: 2,
+                height: 3,
+                coverage: 50.0,
+                output_bytes: 100,
+                duration: Duration::from_millis(7),
             };
 
-            process(config).unwrap();
+            append_stats_csv(&csv_path, &stats).unwrap();
+            append_stats_csv(&csv_path, &stats).unwrap();
+
+            let content = std::fs::read_to_string(&csv_path).unwrap();
+            std::fs::remove_file(&csv_path).unwrap();
+            assert_eq!(
+                content,
+                format!(
+                    "{}{}{}",
+                    STATS_CSV_HEADER,
+                    "\"a,b.png\",2,3,50.00,100,7\n",
+                    "\"a,b.png\",2,3,50.00,100,7\n"
+                )
+            );
+        }
+    }
+
+    mod info_to_json {
+        use png::BitDepth;
+
+        use super::*;
+
+        #[test]
+        fn success_when_indexed_with_trns() {
+            let mut info = Info::with_size(3, 2);
+            info.color_type = ColorType::Indexed;
+            info.bit_depth = BitDepth::Four;
+            info.palette = Some(vec![0, 0, 0].into());
+            info.trns = Some(vec![0].into());
+
+            assert_eq!(
+                info_to_json(&info),
+                r#"{"width":3,"height":2,"color_type":"indexed","bit_depth":4,"interlaced":false,"has_palette":true,"has_trns":true,"has_alpha":true}"#
+            );
+        }
+
+        #[test]
+        fn success_when_rgb() {
+            let mut info = Info::with_size(1, 1);
+            info.color_type = ColorType::Rgb;
+            info.bit_depth = BitDepth::Sixteen;
+
+            assert_eq!(
+                info_to_json(&info),
+                r#"{"width":1,"height":1,"color_type":"rgb","bit_depth":16,"interlaced":false,"has_palette":false,"has_trns":false,"has_alpha":false}"#
+            );
+        }
+    }
+
+    mod decode_png {
+        use png::{BitDepth, ColorType};
+
+        use super::*;
+
+        #[test]
+        fn success_when_indexed_with_trns() {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut encoded, 3, 1);
+                encoder.set_color(ColorType::Indexed);
+                encoder.set_depth(BitDepth::Eight);
+                encoder.set_palette(vec![255, 0, 0, 0, 255, 0, 0, 0, 255]);
+                encoder.set_trns(vec![0, 128]);
+                let mut writer = encoder.write_header().unwrap();
+                writer.write_image_data(&[0, 1, 2]).unwrap();
+            }
+
+            let (buf, info) = decode_png(encoded.as_slice()).unwrap();
+
+            assert_eq!(info.color_type, ColorType::Indexed);
+            assert_eq!(info.bit_depth, BitDepth::Eight);
+            assert_eq!(info.trns.as_deref(), Some(&[0, 128][..]));
+            assert_eq!(buf, vec![0, 1, 2]);
+        }
+
+        #[test]
+        fn success_when_sixteen_bit_is_kept() {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut encoded, 1, 1);
+                encoder.set_color(ColorType::Grayscale);
+                encoder.set_depth(BitDepth::Sixteen);
+                let mut writer = encoder.write_header().unwrap();
+                writer.write_image_data(&[0x12, 0x34]).unwrap();
+            }
+
+            let (buf, info) = decode_png(encoded.as_slice()).unwrap();
+
+            assert_eq!(info.bit_depth, BitDepth::Sixteen);
+            assert_eq!(buf, vec![0x12, 0x34]);
+        }
+
+        #[test]
+        fn success_when_padded_rows_match_color_type() {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut encoded, 3, 2);
+                encoder.set_color(ColorType::Grayscale);
+                encoder.set_depth(BitDepth::One);
+                let mut writer = encoder.write_header().unwrap();
+                writer.write_image_data(&[0b10100000, 0b01000000]).unwrap();
+            }
+
+            let (buf, info) = decode_png(encoded.as_slice()).unwrap();
+
+            assert_eq!(info.color_type, ColorType::Grayscale);
+            assert_eq!(info.bit_depth, BitDepth::One);
+            assert_eq!(buf, vec![0b10100000, 0b01000000]);
+            let gray: Vec<u8> = buf_to_rgba(&buf, &info)
+                .unwrap()
+                .chunks_exact(4)
+                .map(|v| v[0])
+                .collect();
+            assert_eq!(gray, vec![u8::MAX, 0, u8::MAX, 0, u8::MAX, 0]);
+        }
+    }
+
+    mod iccp_tap {
+        use super::*;
+
+        #[test]
+        fn success_when_read_byte_by_byte() {
+            let iccp_chunk = iccp_chunk_data(&[7; 300]).unwrap();
+            let mut encoded = Vec::new();
+            let mut writer = png_writer(
+                &mut encoded,
+                1,
+                1,
+                ColorType::Grayscale,
+                png::BitDepth::Eight,
+                &Metadata {
+                    iccp_chunk: Some(iccp_chunk.clone()),
+                    ..Default::default()
+                },
+                &Config::default(),
+            )
+            .unwrap();
+            writer.write_image_data(&[0]).unwrap();
+            writer.finish().unwrap();
+
+            let kept = Rc::new(Cell::new(None));
+            let mut tap = IccpTap::new(encoded.as_slice(), Rc::clone(&kept));
+            let mut read = Vec::new();
+            let mut byte = [0];
+            while tap.read(&mut byte).unwrap() == 1 {
+                read.push(byte[0]);
+            }
+
+            assert_eq!(read, encoded);
+            assert_eq!(kept.take(), Some(iccp_chunk));
+        }
+    }
+
+    mod open_png {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        #[test]
+        fn success_when_png() {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("test_target/png")
+                .join("surface0000.png");
+
+            let mut file = open_png(&path).unwrap();
+
+            let mut signature = [0; 8];
+            file.read_exact(&mut signature).unwrap();
+            assert_eq!(signature, PNG_SIGNATURE);
+        }
+
+        #[test]
+        fn failed_when_not_png() {
+            let path = std::env::temp_dir().join("merge-pna_not_a_png.jpg");
+            std::fs::write(
+                &path,
+                [0xff, 0xd8, 0xff, 0xe0, 0, 0x10, b'J', b'F', b'I', b'F'],
+            )
+            .unwrap();
+
+            let result = open_png(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(matches!(result, Err(MergeError::NotAPng { path: p }) if p == path));
+        }
+
+        #[test]
+        fn failed_when_shorter_than_signature() {
+            let path = std::env::temp_dir().join("merge-pna_short.png");
+            std::fs::write(&path, [0x89, b'P', b'N']).unwrap();
+
+            let result = open_png(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(matches!(result, Err(MergeError::NotAPng { .. })));
+        }
+    }
+
+    mod create_output_file {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        #[test]
+        fn failed_when_output_dir_is_not_exist() {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("test_target/not_exist_dir/surface0000_new.png");
+
+            assert!(matches!(create_output_file(&path), Err(MergeError::Io(_))));
         }
     }
 }
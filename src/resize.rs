@@ -0,0 +1,193 @@
+use clap::ValueEnum;
+
+/// Cells of a grid cut short by the right or bottom edge of the image.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum PartialCells {
+    /// Pad them to the full cell size with transparent pixels.
+    #[default]
+    Pad,
+    /// Leave them out.
+    Skip,
+}
+
+/// Downscales rgba buffer by averaging each box of source pixels.
+/// Colors are weighted by alpha so transparent pixels don't bleed into edges.
+pub(crate) fn downscale_box(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    new_width: u32,
+    new_height: u32,
+) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let (new_width, new_height) = (new_width as usize, new_height as usize);
+    let mut result = Vec::with_capacity(new_width * new_height * 4);
+
+    if width == 0 || height == 0 {
+        result.resize(new_width * new_height * 4, 0);
+        return result;
+    }
+
+    for oy in 0..new_height {
+        let (y0, y1) = box_range(oy, height, new_height);
+        for ox in 0..new_width {
+            let (x0, x1) = box_range(ox, width, new_width);
+
+            let mut sum = [0u64; 4];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let p = &rgba[(y * width + x) * 4..(y * width + x) * 4 + 4];
+                    let a = p[3] as u64;
+                    sum[0] += p[0] as u64 * a;
+                    sum[1] += p[1] as u64 * a;
+                    sum[2] += p[2] as u64 * a;
+                    sum[3] += a;
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as u64;
+            let color = |v: u64| (v + sum[3] / 2).checked_div(sum[3]).unwrap_or(0) as u8;
+
+            result.push(color(sum[0]));
+            result.push(color(sum[1]));
+            result.push(color(sum[2]));
+            result.push(((sum[3] + count / 2) / count) as u8);
+        }
+    }
+
+    result
+}
+
+/// Cuts the image into cells of `cell_width` x `cell_height` and returns them with their
+/// index, counted in row-major order. `pixel_size` is the number of bytes per pixel.
+pub(crate) fn slice_cells(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    pixel_size: usize,
+    cell_width: u32,
+    cell_height: u32,
+    partial: PartialCells,
+) -> Vec<(usize, Vec<u8>)> {
+    let (width, height) = (width as usize, height as usize);
+    let (cell_width, cell_height) = (cell_width as usize, cell_height as usize);
+    let cols = width.div_ceil(cell_width);
+    let rows = height.div_ceil(cell_height);
+    let mut cells = Vec::with_capacity(cols * rows);
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let (x0, y0) = (col * cell_width, row * cell_height);
+            let copy_width = cell_width.min(width - x0);
+            let copy_height = cell_height.min(height - y0);
+            if partial == PartialCells::Skip
+                && (copy_width < cell_width || copy_height < cell_height)
+            {
+                continue;
+            }
+
+            let mut cell = vec![0; cell_width * cell_height * pixel_size];
+            for y in 0..copy_height {
+                let src = ((y0 + y) * width + x0) * pixel_size;
+                let dst = y * cell_width * pixel_size;
+                cell[dst..dst + copy_width * pixel_size]
+                    .copy_from_slice(&buf[src..src + copy_width * pixel_size]);
+            }
+            cells.push((row * cols + col, cell));
+        }
+    }
+
+    cells
+}
+
+fn box_range(index: usize, size: usize, new_size: usize) -> (usize, usize) {
+    let start = (index * size / new_size).min(size - 1);
+    let end = ((index + 1) * size / new_size).clamp(start + 1, size);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod slice_cells {
+        use super::*;
+
+        #[test]
+        fn success_when_cells_divide_image() {
+            let buf = [
+                1, 2, 3, 4, //
+                5, 6, 7, 8,
+            ];
+
+            let result = slice_cells(&buf, 4, 2, 1, 2, 1, PartialCells::Pad);
+
+            assert_eq!(
+                result,
+                vec![
+                    (0, vec![1, 2]),
+                    (1, vec![3, 4]),
+                    (2, vec![5, 6]),
+                    (3, vec![7, 8])
+                ]
+            );
+        }
+
+        #[test]
+        fn success_when_partial_cells_are_padded() {
+            let buf = [
+                1, 2, 3, //
+                4, 5, 6,
+            ];
+
+            let result = slice_cells(&buf, 3, 2, 1, 2, 2, PartialCells::Pad);
+
+            assert_eq!(result, vec![(0, vec![1, 2, 4, 5]), (1, vec![3, 0, 6, 0])]);
+        }
+
+        #[test]
+        fn success_when_partial_cells_are_skipped() {
+            let buf = [
+                1, 1, 2, 2, 3, 3, //
+                4, 4, 5, 5, 6, 6,
+            ];
+
+            let result = slice_cells(&buf, 3, 2, 2, 2, 1, PartialCells::Skip);
+
+            assert_eq!(result, vec![(0, vec![1, 1, 2, 2]), (2, vec![4, 4, 5, 5])]);
+        }
+    }
+
+    mod downscale_box {
+        use super::*;
+
+        #[test]
+        fn success_when_halving() {
+            let rgba = [
+                255, 0, 0, 255, 0, 0, 255, 255, //
+                255, 0, 0, 255, 0, 0, 255, 255,
+            ];
+
+            let result = downscale_box(&rgba, 2, 2, 1, 1);
+
+            assert_eq!(result, vec![128, 0, 128, 255]);
+        }
+
+        #[test]
+        fn success_when_transparent_pixel_is_ignored_for_color() {
+            let rgba = [255, 255, 255, 255, 0, 0, 0, 0];
+
+            let result = downscale_box(&rgba, 2, 1, 1, 1);
+
+            assert_eq!(result, vec![255, 255, 255, 128]);
+        }
+
+        #[test]
+        fn success_when_upscaling() {
+            let rgba = [10, 20, 30, 40];
+
+            let result = downscale_box(&rgba, 1, 1, 2, 1);
+
+            assert_eq!(result, vec![10, 20, 30, 40, 10, 20, 30, 40]);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::error::MergeError;
+
+/// Doublings of the backoff stop here, so a long run of failures waits at most
+/// `base * 2^16` between attempts.
+const MAX_BACKOFF_SHIFT: u32 = 16;
+
+/// Runs `f` again after an error that may go away, up to `retries` more times.
+/// With `backoff`, the n-th retry waits `backoff_delay(backoff, n, _)`. Permanent
+/// errors are returned at once.
+pub(crate) fn retry<T>(
+    retries: u32,
+    backoff: Option<Duration>,
+    mut f: impl FnMut() -> Result<T, MergeError>,
+) -> Result<T, MergeError> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries && e.is_transient() => {
+                if let Some(base) = backoff {
+                    std::thread::sleep(backoff_delay(base, attempt, jitter()));
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Exponential backoff with jitter: `base * 2^attempt`, of which the upper half is
+/// scaled by `jitter` (0.0-1.0) so that retries of parallel runs spread out.
+pub(crate) fn backoff_delay(base: Duration, attempt: u32, jitter: f64) -> Duration {
+    let delay = base.saturating_mul(1 << attempt.min(MAX_BACKOFF_SHIFT));
+    delay / 2 + (delay / 2).mul_f64(jitter.clamp(0.0, 1.0))
+}
+
+/// A number in 0.0-1.0 that differs for each call, from the random keys of `RandomState`.
+fn jitter() -> f64 {
+    let v = RandomState::new().build_hasher().finish();
+    (v >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Error, ErrorKind};
+
+    mod retry {
+        use super::*;
+
+        #[test]
+        fn success_when_transient_error_goes_away() {
+            let mut calls = 0;
+
+            let result = retry(3, None, || {
+                calls += 1;
+                match calls {
+                    1 | 2 => Err(MergeError::Io(Error::from(ErrorKind::TimedOut))),
+                    _ => Ok(calls),
+                }
+            });
+
+            assert_eq!(result.unwrap(), 3);
+        }
+
+        #[test]
+        fn failed_when_retries_run_out() {
+            let mut calls = 0;
+
+            let result: Result<(), _> = retry(2, Some(Duration::from_millis(1)), || {
+                calls += 1;
+                Err(MergeError::Io(Error::from(ErrorKind::Interrupted)))
+            });
+
+            assert!(matches!(result, Err(MergeError::Io(_))));
+            assert_eq!(calls, 3);
+        }
+
+        #[test]
+        fn failed_when_permanent_error() {
+            let mut calls = 0;
+
+            let result: Result<(), _> = retry(5, None, || {
+                calls += 1;
+                Err(MergeError::Io(Error::from(ErrorKind::NotFound)))
+            });
+
+            assert!(result.is_err());
+            assert_eq!(calls, 1);
+        }
+    }
+
+    mod backoff_delay {
+        use super::*;
+
+        #[test]
+        fn success_when_attempts_double() {
+            let base = Duration::from_millis(100);
+
+            assert_eq!(backoff_delay(base, 0, 0.0), Duration::from_millis(50));
+            assert_eq!(backoff_delay(base, 0, 1.0), Duration::from_millis(100));
+            assert_eq!(backoff_delay(base, 3, 0.0), Duration::from_millis(400));
+            assert_eq!(backoff_delay(base, 3, 0.5), Duration::from_millis(600));
+            assert_eq!(backoff_delay(base, 3, 1.0), Duration::from_millis(800));
+        }
+
+        #[test]
+        fn success_when_many_attempts() {
+            let base = Duration::from_millis(1);
+
+            assert_eq!(backoff_delay(base, 100, 1.0), backoff_delay(base, 16, 1.0));
+        }
+    }
+
+    mod jitter {
+        use super::*;
+
+        #[test]
+        fn success_when_in_range() {
+            for _ in 0..100 {
+                assert!((0.0..=1.0).contains(&jitter()));
+            }
+        }
+    }
+}
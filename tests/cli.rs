@@ -0,0 +1,153 @@
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+mod no_clobber {
+    use super::*;
+
+    #[test]
+    fn success_when_skipped_output_is_logged_under_verbose() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+        let output_path = std::env::temp_dir().join("merge-pna_cli_no_clobber.png");
+        std::fs::write(&output_path, b"old").unwrap();
+        let run = |verbose: bool| {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_merge-pna"));
+            command
+                .arg("-i")
+                .arg(dir.join("surface0000.png"))
+                .arg("-o")
+                .arg(&output_path)
+                .arg("--no-clobber");
+            if verbose {
+                command.arg("--verbose");
+            }
+            command.output().unwrap()
+        };
+
+        let quiet = run(false);
+        let verbose = run(true);
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(quiet.status.code(), Some(2));
+        assert!(quiet.stderr.is_empty());
+        assert_eq!(verbose.status.code(), Some(2));
+        assert_eq!(
+            String::from_utf8(verbose.stderr).unwrap(),
+            format!("Skipped {}: output exists\n", output_path.display())
+        );
+    }
+}
+
+mod confirm_overwriting {
+    use super::*;
+
+    #[test]
+    fn success_when_declined_in_batch() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+        let work_dir = std::env::temp_dir().join("merge-pna_cli_declined_in_batch");
+        let _ = std::fs::remove_dir_all(&work_dir);
+        std::fs::create_dir_all(&work_dir).unwrap();
+        for name in ["a", "b"] {
+            std::fs::copy(
+                dir.join("surface0000.png"),
+                work_dir.join(format!("{}.png", name)),
+            )
+            .unwrap();
+            std::fs::copy(
+                dir.join("surface0000.pna"),
+                work_dir.join(format!("{}.pna", name)),
+            )
+            .unwrap();
+        }
+        std::fs::write(work_dir.join("a_new.png"), b"old").unwrap();
+
+        // Stdin is empty, so the prompt for a_new.png is declined.
+        let output = Command::new(env!("CARGO_BIN_EXE_merge-pna"))
+            .arg("-i")
+            .arg(&work_dir)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(std::fs::read(work_dir.join("a_new.png")).unwrap(), b"old");
+        assert!(work_dir.join("b_new.png").exists());
+
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn success_when_declined_for_single_file() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+        let output_path = std::env::temp_dir().join("merge-pna_cli_declined.png");
+        std::fs::write(&output_path, b"old").unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_merge-pna"))
+            .arg("-i")
+            .arg(dir.join("surface0000.png"))
+            .arg("-o")
+            .arg(&output_path)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let written = std::fs::read(&output_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(written, b"old");
+        assert!(String::from_utf8(output.stdout)
+            .unwrap()
+            .ends_with("Closing this program...\n"));
+    }
+}
+
+#[cfg(feature = "archive")]
+mod archive {
+    use super::*;
+
+    fn tar_entry(tar: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        tar.extend_from_slice(&header);
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    #[test]
+    fn success_when_declined_entry_is_skipped() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_target/png");
+        let png = std::fs::read(dir.join("surface0000.png")).unwrap();
+        let pna = std::fs::read(dir.join("surface0000.pna")).unwrap();
+        let work_dir = std::env::temp_dir().join("merge-pna_cli_archive_declined");
+        let _ = std::fs::remove_dir_all(&work_dir);
+        std::fs::create_dir_all(&work_dir).unwrap();
+        let mut tar = Vec::new();
+        for name in ["a", "b"] {
+            tar_entry(&mut tar, &format!("{}.png", name), &png);
+            tar_entry(&mut tar, &format!("{}.pna", name), &pna);
+        }
+        tar.extend_from_slice(&[0; 1024]);
+        let archive_path = work_dir.join("pairs.tar");
+        std::fs::write(&archive_path, tar).unwrap();
+        std::fs::write(work_dir.join("a_new.png"), b"old").unwrap();
+
+        // Stdin is empty, so the prompt for a_new.png is declined.
+        let output = Command::new(env!("CARGO_BIN_EXE_merge-pna"))
+            .arg("-i")
+            .arg(&archive_path)
+            .arg("--archive")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(std::fs::read(work_dir.join("a_new.png")).unwrap(), b"old");
+        assert!(work_dir.join("b_new.png").exists());
+
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+}